@@ -1,19 +1,38 @@
 use std::sync::Mutex;
 
 use actix_cors::Cors;
-use actix_web::{web::Data, App, HttpServer};
+use actix_web::{web::{self, Data}, App, HttpServer};
+use tracing::info;
 
 use crate::data::DataService;
+use crate::file::ProvideFile;
+use crate::ws::WsServerHandle;
 
 pub mod api;
+pub mod audit;
+pub mod health;
+pub mod ignore_rules;
+pub mod lockouts;
+pub mod metrics;
+pub mod sessions;
+pub mod throttle;
 pub mod views;
 
 use views as index;
 
-pub async fn start_api_server(webserver_port: String,
-                              data_ins: Data<Mutex<DataService>>,
+pub async fn start_api_server<T: ProvideFile + Sync + Send + 'static>(
+    bind: String,
+    port: u16,
+    data_ins: Data<Mutex<DataService>>,
+    file_ins: Data<Mutex<T>>,
+    ws_server: &WsServerHandle,
+    shutdown_timeout: u64,
 ) -> std::io::Result<()> {
-    println!("WebServer running in port: {}", webserver_port);
+    let sessions_ins = Data::clone(&ws_server.sessions);
+    let ws_state_ins = Data::clone(&ws_server.state);
+    let throttle_ins = Data::clone(&ws_server.throttle);
+    let auth_guard_ins = Data::clone(&ws_server.auth_guard);
+    info!(%bind, port, "WebServer running");
     return HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
@@ -21,6 +40,11 @@ pub async fn start_api_server(webserver_port: String,
             .allow_any_header();
         App::new()
             .app_data(Data::clone(&data_ins))
+            .app_data(Data::clone(&file_ins))
+            .app_data(Data::clone(&sessions_ins))
+            .app_data(Data::clone(&ws_state_ins))
+            .app_data(Data::clone(&throttle_ins))
+            .app_data(Data::clone(&auth_guard_ins))
             .wrap(cors)
             .service(api::client_api_endpoint)
             .service(api::get_client_endpoint)
@@ -28,9 +52,29 @@ pub async fn start_api_server(webserver_port: String,
             .service(api::update_client_endpoint)
             .service(api::delete_client_endpoint)
             .service(api::create_client_endpoint)
+            .service(api::client_keys_endpoint)
+            .service(api::create_client_key_endpoint)
+            .service(api::revoke_client_key_endpoint)
+            .service(audit::audit_api_endpoint)
+            .service(audit::audit_csv_endpoint)
+            .service(sessions::sessions_api_endpoint)
+            .service(sessions::close_session_endpoint)
+            .service(lockouts::lockouts_api_endpoint)
+            .service(lockouts::lift_lockout_endpoint)
+            .service(throttle::throttle_api_endpoint)
+            .service(throttle::set_global_throttle_endpoint)
+            .service(throttle::set_client_throttle_endpoint)
+            .service(throttle::remove_client_throttle_endpoint)
+            .service(ignore_rules::client_ignore_endpoint)
+            .service(ignore_rules::set_client_ignore_endpoint)
+            .service(ignore_rules::remove_client_ignore_endpoint)
+            .service(metrics::metrics_endpoint)
+            .service(health::healthz_endpoint)
+            .service(web::resource("/readyz").route(web::get().to(health::readyz_endpoint::<T>)))
             .service(index::views)
     })
-        .bind(("0.0.0.0", webserver_port.parse::<u16>().unwrap()))?
+        .shutdown_timeout(shutdown_timeout)
+        .bind((bind.as_str(), port))?
         .run()
         .await;
 }
@@ -8,13 +8,41 @@ use actix_web::{
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
 
+use crate::data::keys::{KeySummary, NewClientKey};
 use crate::data::DataService;
 
+/** What to do with a new connection when the client already uses all of its connections */
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitPolicy {
+    Reject,
+    EvictOldest,
+}
+
+impl LimitPolicy {
+    pub fn as_str(self: &LimitPolicy) -> &'static str {
+        match self {
+            LimitPolicy::Reject => "reject",
+            LimitPolicy::EvictOldest => "evict_oldest",
+        }
+    }
+
+    pub fn parse(value: &str) -> LimitPolicy {
+        match value {
+            "evict_oldest" => LimitPolicy::EvictOldest,
+            _ => LimitPolicy::Reject,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Client {
     pub id: Option<i64>,
     pub name: Option<String>,
     pub key: Option<String>,
+    /// maximum number of simultaneous connections, unlimited when missing or 0
+    pub max_connections: Option<i64>,
+    pub limit_policy: Option<LimitPolicy>,
 }
 
 #[get("/api/clients")]
@@ -76,3 +104,48 @@ pub async fn delete_client_endpoint(
     let removed = data_service.remove_client(id.into_inner().0);
     HttpResponse::Ok().body(serde_json::to_string(&removed).unwrap())
 }
+
+#[get("/api/clients/{id}/keys")]
+pub async fn client_keys_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    let id = id.into_inner().0;
+    let data_service = data_service_ins.lock().unwrap();
+    if !data_service.client_exists(id) {
+        return HttpResponse::NotFound().finish();
+    }
+    let keys: Vec<KeySummary> = data_service.get_client_keys(id).iter().map(KeySummary::from).collect();
+    HttpResponse::Ok().body(to_string(&keys).unwrap())
+}
+
+/** The answer is the only time the key is shown */
+#[post("/api/clients/{id}/keys")]
+pub async fn create_client_key_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    new_key: web::Form<NewClientKey>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    let id = id.into_inner().0;
+    let data_service = data_service_ins.lock().unwrap();
+    if !data_service.client_exists(id) {
+        return HttpResponse::NotFound().finish();
+    }
+    match data_service.add_client_key(id, new_key.into_inner()) {
+        Ok(key) => HttpResponse::Ok().body(to_string(&key).unwrap()),
+        Err(err) => HttpResponse::BadRequest().body(err),
+    }
+}
+
+#[delete("/api/clients/{id}/keys/{key_id}")]
+pub async fn revoke_client_key_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    path: web::Path<(i64, i64)>,
+) -> impl Responder {
+    let (id, key_id) = path.into_inner();
+    let data_service = data_service_ins.lock().unwrap();
+    match data_service.revoke_client_key(id, key_id) {
+        Some(key) => HttpResponse::Ok().body(to_string(&KeySummary::from(&key)).unwrap()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
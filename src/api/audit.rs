@@ -0,0 +1,34 @@
+use std::sync::Mutex;
+
+use actix_web::{
+    get,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use serde_json::to_string;
+
+use crate::data::audit::{audit_to_csv, AuditFilter};
+use crate::data::DataService;
+
+#[get("/api/audit")]
+pub async fn audit_api_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    filter: web::Query<AuditFilter>,
+) -> impl Responder {
+    let data_service = data_service_ins.lock().unwrap();
+    let entries = data_service.get_audit_page(&filter.into_inner());
+    HttpResponse::Ok().body(to_string(&entries).unwrap())
+}
+
+#[get("/api/audit/csv")]
+pub async fn audit_csv_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    filter: web::Query<AuditFilter>,
+) -> impl Responder {
+    let data_service = data_service_ins.lock().unwrap();
+    let entries = data_service.get_audit_entries(&filter.into_inner());
+    HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header(("Content-Disposition", "attachment; filename=\"audit.csv\""))
+        .body(audit_to_csv(&entries))
+}
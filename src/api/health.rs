@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, TryLockError};
+
+use actix_web::{get, web::Data, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use serde_json::to_string;
+use tracing::warn;
+
+use crate::data::DataService;
+use crate::file::ProvideFile;
+use crate::ws::WsServerState;
+
+pub const STATUS_OK: &str = "ok";
+pub const STATUS_FAIL: &str = "fail";
+/// the check couldn't run right now, the component is working
+pub const STATUS_BUSY: &str = "busy";
+
+/** Result of a single readiness check */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CheckResult {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/** Body of the health and readiness endpoints */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HealthReport {
+    pub status: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+fn check_result(result: Result<(), String>) -> CheckResult {
+    match result {
+        Ok(()) => CheckResult { status: STATUS_OK.to_string(), error: None },
+        Err(error) => CheckResult { status: STATUS_FAIL.to_string(), error: Some(error) },
+    }
+}
+
+/** Liveness probe, answers as long as the process is serving requests */
+#[get("/healthz")]
+pub async fn healthz_endpoint() -> impl Responder {
+    let report = HealthReport {
+        status: STATUS_OK.to_string(),
+        checks: BTreeMap::new(),
+    };
+    HttpResponse::Ok().content_type("application/json").body(to_string(&report).unwrap())
+}
+
+/** Readiness probe, checks the database, the data root and the WebSocket listener. A scan
+holds the file provider for its whole run, the data root is busy rather than waited for */
+pub async fn readyz_endpoint<T: ProvideFile + 'static>(
+    data_ins: Data<Mutex<DataService>>,
+    file_ins: Data<Mutex<T>>,
+    ws_state_ins: Data<WsServerState>,
+) -> impl Responder {
+    let mut checks = BTreeMap::new();
+    checks.insert("database".to_string(), check_result(data_ins.lock().unwrap().check_writable()));
+    let data_root = match file_ins.try_lock() {
+        Ok(file_service) => check_result(file_service.check_ready()),
+        Err(TryLockError::WouldBlock) => CheckResult { status: STATUS_BUSY.to_string(), error: None },
+        Err(TryLockError::Poisoned(_)) => check_result(Err("The file provider panicked".to_string())),
+    };
+    checks.insert("data_root".to_string(), data_root);
+    let accepting = if ws_state_ins.accepting.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        Err("The WebSocket listener is not accepting connections".to_string())
+    };
+    checks.insert("websocket".to_string(), check_result(accepting));
+
+    let ready = checks.values().all(|check| check.status != STATUS_FAIL);
+    let report = HealthReport {
+        status: (if ready { STATUS_OK } else { STATUS_FAIL }).to_string(),
+        checks,
+    };
+    if ready {
+        HttpResponse::Ok().content_type("application/json").body(to_string(&report).unwrap())
+    } else {
+        warn!(report = ?report, "Readiness check failed");
+        HttpResponse::ServiceUnavailable().content_type("application/json").body(to_string(&report).unwrap())
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::Mutex;
+
+use actix_web::{
+    delete, get, put,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use serde_json::to_string;
+use tracing::info;
+
+use crate::data::DataService;
+
+#[get("/api/clients/{id}/ignore")]
+pub async fn client_ignore_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    match data_service_ins.lock().unwrap().get_client_ignore(id.into_inner().0) {
+        Ok(Some(patterns)) => HttpResponse::Ok().body(to_string(&patterns).unwrap()),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(err) => HttpResponse::InternalServerError().body(err),
+    }
+}
+
+#[put("/api/clients/{id}/ignore")]
+pub async fn set_client_ignore_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    patterns: web::Json<Vec<String>>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    let id = id.into_inner().0;
+    let data_service = data_service_ins.lock().unwrap();
+    // unreadable patterns are replaced
+    if let Ok(None) = data_service.get_client_ignore(id) {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Err(err) = data_service.set_client_ignore(id, Some(&patterns)) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    // the connections read the patterns again for every request
+    info!(client_id = id, patterns = ?patterns.0, "Client ignore patterns changed");
+    HttpResponse::Ok().body(to_string(&patterns.0).unwrap())
+}
+
+#[delete("/api/clients/{id}/ignore")]
+pub async fn remove_client_ignore_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    let id = id.into_inner().0;
+    if data_service_ins.lock().unwrap().set_client_ignore(id, None).is_err() {
+        return HttpResponse::NotFound().finish();
+    }
+    info!(client_id = id, "Client ignore patterns removed");
+    HttpResponse::Ok().body("[]")
+}
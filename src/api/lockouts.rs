@@ -0,0 +1,47 @@
+use std::sync::Mutex;
+
+use actix_web::{
+    delete, get,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use serde::Deserialize;
+use serde_json::to_string;
+use tracing::info;
+
+use crate::data::DataService;
+use crate::ws::auth_guard::{AuthGuard, LOCKOUT_CLIENT, LOCKOUT_PEER};
+
+#[derive(Deserialize)]
+pub struct LockoutQuery {
+    /// only the lockouts still in force
+    pub active: Option<bool>,
+}
+
+#[get("/api/lockouts")]
+pub async fn lockouts_api_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    query: web::Query<LockoutQuery>,
+) -> impl Responder {
+    let lockouts = data_service_ins.lock().unwrap().get_lockouts(query.active.unwrap_or(false));
+    HttpResponse::Ok().body(to_string(&lockouts).unwrap())
+}
+
+#[delete("/api/lockouts/{id}")]
+pub async fn lift_lockout_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    auth_guard_ins: Data<Mutex<AuthGuard>>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    let lifted = data_service_ins.lock().unwrap().lift_lockout(id.into_inner().0);
+    match lifted {
+        Some(lockout) => {
+            // the next attempt doesn't have to back off either
+            let kind = if lockout.kind == LOCKOUT_PEER { LOCKOUT_PEER } else { LOCKOUT_CLIENT };
+            auth_guard_ins.lock().unwrap().reset(&[(kind, lockout.subject.clone())]);
+            info!(kind = lockout.kind, subject = lockout.subject, "Lockout lifted");
+            HttpResponse::Ok().body(to_string(&lockout).unwrap())
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
@@ -0,0 +1,10 @@
+use actix_web::{get, HttpResponse, Responder};
+
+use crate::metrics::render;
+
+#[get("/metrics")]
+pub async fn metrics_endpoint() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render())
+}
@@ -0,0 +1,31 @@
+use std::sync::Mutex;
+
+use actix_web::{
+    delete, get,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use serde_json::to_string;
+
+use crate::ws::session::SessionRegistry;
+
+#[get("/api/sessions")]
+pub async fn sessions_api_endpoint(sessions_ins: Data<Mutex<SessionRegistry>>) -> impl Responder {
+    let sessions = sessions_ins.lock().unwrap().list();
+    HttpResponse::Ok().body(to_string(&sessions).unwrap())
+}
+
+#[delete("/api/sessions/{id}")]
+pub async fn close_session_endpoint(
+    sessions_ins: Data<Mutex<SessionRegistry>>,
+    id: web::Path<(u64,)>,
+) -> impl Responder {
+    let closed = sessions_ins
+        .lock()
+        .unwrap()
+        .request_close(id.into_inner().0, "Session closed by the administrator".to_string());
+    match closed {
+        Some(session) => HttpResponse::Ok().body(to_string(&session).unwrap()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
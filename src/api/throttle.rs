@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+
+use actix_web::{
+    delete, get, put,
+    web::{self, Data},
+    HttpResponse, Responder,
+};
+use serde_json::to_string;
+use tracing::info;
+
+use crate::data::DataService;
+use crate::throttle::{RateSchedule, Throttle};
+
+/** Load the limits saved in the database into the running throttle */
+fn reload(data_service: &DataService, throttle_ins: &Data<Mutex<Throttle>>) -> HttpResponse {
+    match data_service.get_throttle_settings() {
+        Ok(settings) => {
+            throttle_ins.lock().unwrap().set_settings(settings.clone());
+            HttpResponse::Ok().body(to_string(&settings).unwrap())
+        }
+        Err(err) => HttpResponse::InternalServerError().body(err),
+    }
+}
+
+#[get("/api/throttle")]
+pub async fn throttle_api_endpoint(throttle_ins: Data<Mutex<Throttle>>) -> impl Responder {
+    let settings = throttle_ins.lock().unwrap().settings();
+    HttpResponse::Ok().body(to_string(&settings).unwrap())
+}
+
+#[put("/api/throttle")]
+pub async fn set_global_throttle_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    throttle_ins: Data<Mutex<Throttle>>,
+    schedule: web::Json<RateSchedule>,
+) -> impl Responder {
+    let data_service = data_service_ins.lock().unwrap();
+    if let Err(err) = data_service.set_global_rate_limit(&schedule) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    info!(schedule = ?schedule.0, "Global rate limit changed");
+    reload(&data_service, &throttle_ins)
+}
+
+#[put("/api/clients/{id}/throttle")]
+pub async fn set_client_throttle_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    throttle_ins: Data<Mutex<Throttle>>,
+    schedule: web::Json<RateSchedule>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    let id = id.into_inner().0;
+    let data_service = data_service_ins.lock().unwrap();
+    if !data_service.get_clients().iter().any(|client| client.id == Some(id)) {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Err(err) = data_service.set_client_rate_limit(id, Some(&schedule)) {
+        return HttpResponse::BadRequest().body(err);
+    }
+    info!(client_id = id, schedule = ?schedule.0, "Client rate limit changed");
+    reload(&data_service, &throttle_ins)
+}
+
+#[delete("/api/clients/{id}/throttle")]
+pub async fn remove_client_throttle_endpoint(
+    data_service_ins: Data<Mutex<DataService>>,
+    throttle_ins: Data<Mutex<Throttle>>,
+    id: web::Path<(i64,)>,
+) -> impl Responder {
+    let id = id.into_inner().0;
+    let data_service = data_service_ins.lock().unwrap();
+    if data_service.set_client_rate_limit(id, None).is_err() {
+        return HttpResponse::NotFound().finish();
+    }
+    info!(client_id = id, "Client rate limit removed");
+    reload(&data_service, &throttle_ins)
+}
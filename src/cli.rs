@@ -0,0 +1,256 @@
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::to_string_pretty;
+
+use crate::api::api::{Client, LimitPolicy};
+use crate::config::{Config, ConfigLayer};
+use crate::data::migrations::{current_version, latest_version};
+use crate::data::shares::Share;
+use crate::data::DataService;
+use crate::file::hash::HashAlgorithm;
+use crate::file::FileService;
+
+/** Command line of the `copy_service` binary, runs the server when no command is given */
+#[derive(Parser, Debug)]
+#[command(version, about = "Serves a directory to the copy clients over WebSocket")]
+pub struct Cli {
+    /// TOML config file [default: copy_service.toml in the config path, if it exists]
+    #[arg(short, long, env = "CONFIG_FILE", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Print the output of the commands as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub overrides: ConfigLayer,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/** Administration commands, they work on the database directly so the server
+doesn't need to be running */
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the clients allowed to connect
+    #[command(subcommand)]
+    Client(ClientCommand),
+    /// Manage the directories served next to the data path
+    #[command(subcommand)]
+    Share(ShareCommand),
+    /// Database maintenance
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// File hash index maintenance
+    #[command(subcommand)]
+    Index(IndexCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ClientCommand {
+    /// Create a client and print its key
+    Add {
+        name: String,
+        /// Maximum number of simultaneous connections, unlimited when missing
+        #[arg(long)]
+        max_connections: Option<i64>,
+        /// What to do when the connection limit is reached
+        #[arg(long, value_parser = ["reject", "evict_oldest"])]
+        limit_policy: Option<String>,
+    },
+    /// List the clients with their active key
+    List,
+    /// Remove a client and its keys
+    Remove { name: String },
+    /// Generate a new key, the previous keys stay valid during the rotation grace period
+    RotateKey { name: String },
+    /// Replace the gitignore style patterns of the entries the client doesn't get, none to remove them
+    Ignore { name: String, patterns: Vec<String> },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ShareCommand {
+    /// Serve a directory as a top level folder with the given name, from the next start of the server
+    Add { name: String, path: String },
+    /// List the shares
+    List,
+    /// Replace the gitignore style patterns of the entries left out of the share, none to remove them.
+    /// They apply from the next start of the server
+    Ignore { name: String, patterns: Vec<String> },
+    /// Set the hash algorithm of the share, sha256 or blake3, none to use the one of the server.
+    /// It applies from the next start of the server
+    Hash { name: String, algorithm: Option<String> },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Bring the database schema up to date
+    Migrate,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IndexCommand {
+    /// Hash all the served files again and persist the result
+    Rebuild,
+}
+
+/** Output of a command, as text or as JSON */
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print<T: Serialize>(self: &Output, value: &T, text: String) -> String {
+        if self.json {
+            return to_string_pretty(value).unwrap();
+        }
+        return text;
+    }
+}
+
+fn client_line(client: &Client) -> String {
+    format!(
+        "{}\t{}\t{}\tmax_connections={}\tlimit_policy={}",
+        client.id.unwrap_or_default(),
+        client.name.clone().unwrap_or_default(),
+        client.key.clone().unwrap_or("-".to_string()),
+        client.max_connections.map(|max| max.to_string()).unwrap_or("unlimited".to_string()),
+        client.limit_policy.unwrap_or(LimitPolicy::Reject).as_str(),
+    )
+}
+
+fn share_line(share: &Share) -> String {
+    let mut line = format!("{}\t{}", share.name, share.path);
+    if let Some(algorithm) = share.hash_algorithm {
+        line = format!("{}\thash={}", line, algorithm.as_str());
+    }
+    if !share.ignore.is_empty() {
+        line = format!("{}\tignore={}", line, share.ignore.join(","));
+    }
+    return line;
+}
+
+/** Create the directory when it is missing */
+fn ensure_dir(name: &str, path: &str) -> Result<(), String> {
+    create_dir_all(path).map_err(|err| format!("Problems creating the {} {}: {}", name, path, err))
+}
+
+fn open_data_service(config: &Config) -> Result<DataService, String> {
+    ensure_dir("config path", &config.config_path)?;
+    DataService::open(config.db_path(), config.key_rotation_grace)
+}
+
+fn find_client(data_service: &DataService, name: &str) -> Result<Client, String> {
+    data_service
+        .get_client_by_name(name.to_string())
+        .ok_or(format!("There is no client named '{}'", name))
+}
+
+/** Run an administration command, returns what to print */
+pub fn run_command(command: Command, config: &Config, json: bool) -> Result<String, String> {
+    let output = Output { json };
+    match command {
+        Command::Client(ClientCommand::Add { name, max_connections, limit_policy }) => {
+            let data_service = open_data_service(config)?;
+            if data_service.get_client_by_name(name.clone()).is_some() {
+                return Err(format!("A client named '{}' already exists", name));
+            }
+            let client = data_service.new_client(Client {
+                id: None,
+                key: None,
+                name: Some(name),
+                max_connections: None,
+                limit_policy: None,
+            });
+            let client = data_service.update_client(Client {
+                max_connections,
+                limit_policy: limit_policy.map(|policy| LimitPolicy::parse(&policy)),
+                ..client
+            }, false);
+            Ok(output.print(&client, client_line(&client)))
+        }
+        Command::Client(ClientCommand::List) => {
+            let clients = open_data_service(config)?.get_clients();
+            let text = clients.iter().map(client_line).collect::<Vec<String>>().join("\n");
+            Ok(output.print(&clients, text))
+        }
+        Command::Client(ClientCommand::Remove { name }) => {
+            let data_service = open_data_service(config)?;
+            let client = find_client(&data_service, &name)?;
+            let removed = data_service.remove_client(client.id.unwrap());
+            Ok(output.print(&removed, format!("Removed client '{}'", name)))
+        }
+        Command::Client(ClientCommand::RotateKey { name }) => {
+            let data_service = open_data_service(config)?;
+            let client = find_client(&data_service, &name)?;
+            let client = data_service.gen_key(client.id.unwrap());
+            Ok(output.print(&client, client_line(&client)))
+        }
+        Command::Client(ClientCommand::Ignore { name, patterns }) => {
+            let data_service = open_data_service(config)?;
+            let client = find_client(&data_service, &name)?;
+            let patterns = if patterns.is_empty() { None } else { Some(patterns.as_slice()) };
+            data_service.set_client_ignore(client.id.unwrap(), patterns)?;
+            let patterns = data_service.get_client_ignore(client.id.unwrap())?.unwrap_or_default();
+            let text = format!("{}\tignore={}", name, patterns.join(","));
+            Ok(output.print(&patterns, text))
+        }
+        Command::Share(ShareCommand::Add { name, path }) => {
+            let share = open_data_service(config)?.add_share(name, path)?;
+            // the running server reads the shares when it starts
+            let text = format!("{}\nRestart the server to serve the share", share_line(&share));
+            Ok(output.print(&share, text))
+        }
+        Command::Share(ShareCommand::List) => {
+            let shares = open_data_service(config)?.get_shares();
+            let text = shares.iter().map(share_line).collect::<Vec<String>>().join("\n");
+            Ok(output.print(&shares, text))
+        }
+        Command::Share(ShareCommand::Ignore { name, patterns }) => {
+            let share = open_data_service(config)?.set_share_ignore(&name, &patterns)?;
+            Ok(output.print(&share, share_line(&share)))
+        }
+        Command::Share(ShareCommand::Hash { name, algorithm }) => {
+            let algorithm = algorithm.as_deref().map(HashAlgorithm::parse).transpose()?;
+            let share = open_data_service(config)?.set_share_hash_algorithm(&name, algorithm)?;
+            Ok(output.print(&share, share_line(&share)))
+        }
+        Command::Db(DbCommand::Migrate) => {
+            ensure_dir("config path", &config.config_path)?;
+            let db_connection = Connection::open(config.db_path())
+                .map_err(|err| format!("Problems opening the database {}: {}", config.db_path(), err))?;
+            let from = current_version(&db_connection)?;
+            let to = DataService::initialize_db(&db_connection)?;
+            let text = if from == to {
+                format!("The database is up to date (schema version {})", to)
+            } else {
+                format!("Migrated the database from schema version {} to {}", from, to)
+            };
+            Ok(output.print(&serde_json::json!({"from": from, "to": to, "latest": latest_version()}), text))
+        }
+        Command::Index(IndexCommand::Rebuild) => {
+            let data_service = open_data_service(config)?;
+            ensure_dir("data path", &config.data_path)?;
+            let mut file_service = FileService::with_cache_timeout(
+                config.data_path.clone(),
+                Duration::from_secs(config.hash_cache_timeout),
+            );
+            file_service.set_shares(data_service.get_shares());
+            file_service.set_ignore(&config.ignore)?;
+            file_service.set_settle_time(Duration::from_secs(config.settle_time));
+            file_service.set_symlink_policy(config.symlinks);
+            file_service.set_hash_algorithm(config.hash_algorithm);
+            file_service.set_hash_workers(config.hash_workers);
+            let entries = file_service.rebuild_index()?;
+            data_service.save_file_index(&entries)?;
+            let text = format!("Indexed {} files", entries.len());
+            Ok(output.print(&serde_json::json!({"files": entries.len()}), text))
+        }
+    }
+}
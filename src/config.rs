@@ -0,0 +1,450 @@
+use std::env::current_dir;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, Parser};
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::data::DEFAULT_KEY_ROTATION_GRACE;
+use crate::file::hash::HashAlgorithm;
+use crate::file::ignore::IgnoreRules;
+use crate::file::scan::default_hash_workers;
+use crate::file::versions::Retention;
+use crate::file::walk::SymlinkPolicy;
+use crate::file::DEFAULT_HASH_CACHE_TIMEOUT;
+use crate::logging::LogFormat;
+use crate::throttle::parse_utc_offset;
+use crate::ws::auth_guard::AuthPolicy;
+use crate::ws::WsOptions;
+
+/// name of the config file looked up in the config path when none is given
+pub const DEFAULT_CONFIG_FILE_NAME: &str = "copy_service.toml";
+/// bigger chunks are refused, the data is base64 encoded in a single message
+pub const MAX_CHUNK_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+/** Settings of the service, resolved from the command line, the environment,
+the config file and the defaults (in that order of precedence) */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub web_bind: String,
+    pub web_port: u16,
+    pub ws_bind: String,
+    pub ws_port: u16,
+    /// directory of the database file and the config file
+    pub config_path: String,
+    pub db_file_name: String,
+    /// directory with the files served to the clients
+    pub data_path: String,
+    /// biggest chunk of a file a client can request in one message, in bytes
+    pub max_chunk_size: u64,
+    /// seconds a file hash stays cached after the last tree request
+    pub hash_cache_timeout: u64,
+    /// send the owner user and group ids of the entries of the tree
+    pub tree_owner: bool,
+    /// gitignore style patterns left out of the tree, the shares included under their name
+    pub ignore: Vec<String>,
+    /// seconds since the last modification before a file is served
+    pub settle_time: u64,
+    /// what the tree does with the symbolic links
+    pub symlinks: SymlinkPolicy,
+    /// algorithm of the file hashes of the data path and of the shares without one
+    pub hash_algorithm: HashAlgorithm,
+    /// threads hashing the files during a scan
+    pub hash_workers: usize,
+    /// directory of the copies of the files being transferred, without it the files are read in place
+    pub snapshot_dir: Option<String>,
+    /// hash the file again before sending its last chunk, to catch changes that keep the size and mtime
+    pub verify_hash: bool,
+    /// directory keeping the previous contents of the files, no versions without it
+    pub versions_dir: Option<String>,
+    /// versions kept per file, the current one included, 0 for no limit
+    pub versions_keep: usize,
+    /// seconds a version is kept after a newer one replaced it, 0 for no limit
+    pub versions_max_age: u64,
+    /// directory of the chunk store, the files are served from their chunks when set
+    pub chunk_dir: Option<String>,
+    /// seconds to wait for a client to acknowledge a close frame
+    pub close_timeout: u64,
+    /// seconds the transfers in flight can go on after a shutdown is requested
+    pub shutdown_timeout: u64,
+    /// seconds without hearing from a client before pinging it, it has as long to answer
+    pub ping_interval: u64,
+    /// seconds a connection without requests nor transfers stays open
+    pub idle_timeout: u64,
+    /// seconds a connection has to authenticate
+    pub auth_timeout: u64,
+    /// seconds the previous keys stay valid after rotating the key of a client
+    pub key_rotation_grace: i64,
+    /// failed authentications in a row that lock out the peer or the client
+    pub auth_max_failures: u32,
+    /// seconds to wait after a failed authentication, doubled after each following one
+    pub auth_backoff: u64,
+    /// seconds a lockout lasts
+    pub auth_lockout: u64,
+    /// offset from UTC in minutes of the clock of the rate limit windows
+    pub throttle_utc_offset: i32,
+    pub log_format: LogFormat,
+    /// `tracing` filter, like `info` or `cs::ws=debug`
+    pub log_level: String,
+}
+
+/** One source of settings, every field is optional so the sources can be layered.
+The same fields are read from the config file and from the command line flags,
+where each flag can also be given as an environment variable */
+#[derive(Args, Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    /// Address the admin web server binds to [default: 0.0.0.0]
+    #[arg(long, env = "WEB_BIND", global = true)]
+    pub web_bind: Option<String>,
+    /// Port of the admin web server [default: 4000]
+    #[arg(long, env = "WEB_PORT", global = true)]
+    pub web_port: Option<u16>,
+    /// Address the WebSocket server binds to [default: 0.0.0.0]
+    #[arg(long, env = "WS_BIND", global = true)]
+    pub ws_bind: Option<String>,
+    /// Port of the WebSocket server [default: 4001]
+    #[arg(long, env = "WS_PORT", global = true)]
+    pub ws_port: Option<u16>,
+    /// Directory of the database and the config file [default: current directory]
+    #[arg(long, env = "CONFIG_PATH", global = true)]
+    pub config_path: Option<String>,
+    /// Name of the database file inside the config path [default: data.db]
+    #[arg(long, env = "DB_FILE_NAME", global = true)]
+    pub db_file_name: Option<String>,
+    /// Directory with the files served to the clients [default: ./data]
+    #[arg(long, env = "DATA_PATH", global = true)]
+    pub data_path: Option<String>,
+    /// Biggest chunk a client can request in one message, in bytes [default: 4194304]
+    #[arg(long, env = "MAX_CHUNK_SIZE", global = true)]
+    pub max_chunk_size: Option<u64>,
+    /// Seconds a file hash stays cached after the last tree request [default: 30000]
+    #[arg(long, env = "HASH_CACHE_TIMEOUT", global = true)]
+    pub hash_cache_timeout: Option<u64>,
+    /// Send the owner user and group ids of the entries of the tree [default: false]
+    #[arg(long, env = "TREE_OWNER", global = true)]
+    pub tree_owner: Option<bool>,
+    /// Gitignore style pattern left out of the tree, can be repeated or comma separated [default: none]
+    #[arg(long, env = "IGNORE", value_delimiter = ',', global = true)]
+    pub ignore: Option<Vec<String>>,
+    /// Seconds since the last modification before a file is served [default: 0]
+    #[arg(long, env = "SETTLE_TIME", global = true)]
+    pub settle_time: Option<u64>,
+    /// Symbolic links of the tree: report, follow (inside the served directories) or skip [default: report]
+    #[arg(long, env = "SYMLINKS", global = true)]
+    pub symlinks: Option<String>,
+    /// Algorithm of the file hashes, sha256 or blake3, the shares can have their own [default: sha256]
+    #[arg(long, env = "HASH_ALGORITHM", global = true)]
+    pub hash_algorithm: Option<String>,
+    /// Threads hashing the files during a scan, fewer suit spinning disks [default: one per CPU, up to 8]
+    #[arg(long, env = "HASH_WORKERS", global = true)]
+    pub hash_workers: Option<usize>,
+    /// Directory of the copies of the files being transferred, on the filesystem of the data for reflinks [default: none]
+    #[arg(long, env = "SNAPSHOT_DIR", global = true)]
+    pub snapshot_dir: Option<String>,
+    /// Hash the file again before sending its last chunk [default: false]
+    #[arg(long, env = "VERIFY_HASH", global = true)]
+    pub verify_hash: Option<bool>,
+    /// Directory keeping the previous contents of the files, the clients can still copy them [default: none]
+    #[arg(long, env = "VERSIONS_DIR", global = true)]
+    pub versions_dir: Option<String>,
+    /// Versions kept per file, the current one included, 0 for no limit [default: 0]
+    #[arg(long, env = "VERSIONS_KEEP", global = true)]
+    pub versions_keep: Option<usize>,
+    /// Seconds a version is kept after a newer one replaced it, 0 for no limit [default: 0]
+    #[arg(long, env = "VERSIONS_MAX_AGE", global = true)]
+    pub versions_max_age: Option<u64>,
+    /// Directory of the chunk store, the files are then stored and served in deduplicated chunks [default: none]
+    #[arg(long, env = "CHUNK_DIR", global = true)]
+    pub chunk_dir: Option<String>,
+    /// Seconds to wait for a client to acknowledge a close frame [default: 5]
+    #[arg(long, env = "CLOSE_TIMEOUT", global = true)]
+    pub close_timeout: Option<u64>,
+    /// Seconds the transfers in flight can go on after a shutdown is requested [default: 30]
+    #[arg(long, env = "SHUTDOWN_TIMEOUT", global = true)]
+    pub shutdown_timeout: Option<u64>,
+    /// Seconds without hearing from a client before pinging it, it has as long to answer [default: 30]
+    #[arg(long, env = "PING_INTERVAL", global = true)]
+    pub ping_interval: Option<u64>,
+    /// Seconds a connection without requests nor transfers stays open [default: 600]
+    #[arg(long, env = "IDLE_TIMEOUT", global = true)]
+    pub idle_timeout: Option<u64>,
+    /// Seconds a connection has to authenticate [default: 10]
+    #[arg(long, env = "AUTH_TIMEOUT", global = true)]
+    pub auth_timeout: Option<u64>,
+    /// Seconds the previous keys stay valid after rotating a client key [default: 86400]
+    #[arg(long, env = "KEY_ROTATION_GRACE", global = true)]
+    pub key_rotation_grace: Option<i64>,
+    /// Failed authentications in a row that lock out the peer or the client [default: 5]
+    #[arg(long, env = "AUTH_MAX_FAILURES", global = true)]
+    pub auth_max_failures: Option<u32>,
+    /// Seconds to wait after a failed authentication, doubled after each following one [default: 1]
+    #[arg(long, env = "AUTH_BACKOFF", global = true)]
+    pub auth_backoff: Option<u64>,
+    /// Seconds a lockout lasts [default: 900]
+    #[arg(long, env = "AUTH_LOCKOUT", global = true)]
+    pub auth_lockout: Option<u64>,
+    /// Offset from UTC of the time windows of the rate limits, like +02:00, fixed without daylight saving [default: +00:00]
+    #[arg(long, env = "THROTTLE_UTC_OFFSET", global = true)]
+    pub throttle_utc_offset: Option<String>,
+    /// Log output format, text or json [default: text]
+    #[arg(long, env = "LOG_FORMAT", global = true)]
+    pub log_format: Option<String>,
+    /// Log filter, like info or cs::ws=debug [default: info]
+    #[arg(long, env = "LOG_LEVEL", global = true)]
+    pub log_level: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let ws_options = WsOptions::default();
+        let auth_policy = ws_options.auth_policy;
+        Config {
+            web_bind: "0.0.0.0".to_string(),
+            web_port: 4000,
+            ws_bind: "0.0.0.0".to_string(),
+            ws_port: 4001,
+            config_path: current_dir()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or(".".to_string()),
+            db_file_name: "data.db".to_string(),
+            data_path: "./data".to_string(),
+            max_chunk_size: 4 * 1024 * 1024,
+            hash_cache_timeout: DEFAULT_HASH_CACHE_TIMEOUT.as_secs(),
+            tree_owner: false,
+            ignore: Vec::new(),
+            settle_time: 0,
+            symlinks: SymlinkPolicy::default(),
+            hash_algorithm: HashAlgorithm::default(),
+            hash_workers: default_hash_workers(),
+            snapshot_dir: None,
+            verify_hash: false,
+            versions_dir: None,
+            versions_keep: 0,
+            versions_max_age: 0,
+            chunk_dir: None,
+            close_timeout: 5,
+            shutdown_timeout: 30,
+            ping_interval: ws_options.ping_interval.as_secs(),
+            idle_timeout: ws_options.idle_timeout.as_secs(),
+            auth_timeout: ws_options.auth_timeout.as_secs(),
+            key_rotation_grace: DEFAULT_KEY_ROTATION_GRACE,
+            auth_max_failures: auth_policy.max_failures,
+            auth_backoff: auth_policy.backoff.as_secs(),
+            auth_lockout: auth_policy.lockout.as_secs(),
+            throttle_utc_offset: ws_options.throttle_utc_offset,
+            log_format: LogFormat::Text,
+            log_level: "info".to_string(),
+        }
+    }
+}
+
+impl ConfigLayer {
+    /** Read a layer from a TOML file */
+    pub fn from_file(path: &Path) -> Result<ConfigLayer, String> {
+        let content = read_to_string(path)
+            .map_err(|err| format!("Problems reading the config file {}: {}", path.display(), err))?;
+        toml::from_str(&content)
+            .map_err(|err| format!("Invalid config file {}: {}", path.display(), err))
+    }
+}
+
+/** The settings given in the environment alone, as if the binary was run without flags */
+#[derive(Parser)]
+struct EnvLayer {
+    #[arg(long, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    overrides: ConfigLayer,
+}
+
+impl Config {
+    /** Resolve the settings from the environment and the config file, without any
+    command line flag. Like the commands, the paths don't have to exist yet */
+    pub fn from_env() -> Result<Config, String> {
+        let env = EnvLayer::try_parse_from(["copy_service"])
+            .map_err(|err| format!("Invalid environment variable: {}", err))?;
+        return Config::load_for_command(env.config.as_deref(), env.overrides);
+    }
+
+    /** Resolve the settings from the command line/environment layer and the config
+    file. Without an explicit file, `copy_service.toml` in the config path is used
+    when it exists */
+    pub fn load(config_file: Option<&Path>, overrides: ConfigLayer) -> Result<Config, String> {
+        let config = Config::resolve(config_file, overrides)?;
+        config.validate()?;
+        return Ok(config);
+    }
+
+    /** Like `load`, for the administration commands. The config path and the data path
+    don't have to exist yet, the commands create them when they use them */
+    pub fn load_for_command(config_file: Option<&Path>, overrides: ConfigLayer) -> Result<Config, String> {
+        let config = Config::resolve(config_file, overrides)?;
+        config.check(false)?;
+        return Ok(config);
+    }
+
+    fn resolve(config_file: Option<&Path>, overrides: ConfigLayer) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        let default_file = Path::new(overrides.config_path.as_deref().unwrap_or(&config.config_path))
+            .join(DEFAULT_CONFIG_FILE_NAME);
+        let file_layer = match config_file {
+            Some(path) => Some(ConfigLayer::from_file(path)?),
+            None if default_file.is_file() => Some(ConfigLayer::from_file(&default_file)?),
+            None => None,
+        };
+
+        if let Some(layer) = file_layer {
+            config.apply(layer)?;
+        }
+        config.apply(overrides)?;
+        return Ok(config);
+    }
+
+    /** Override the settings present in the layer */
+    pub fn apply(self: &mut Config, layer: ConfigLayer) -> Result<(), String> {
+        if let Some(value) = layer.web_bind { self.web_bind = value; }
+        if let Some(value) = layer.web_port { self.web_port = value; }
+        if let Some(value) = layer.ws_bind { self.ws_bind = value; }
+        if let Some(value) = layer.ws_port { self.ws_port = value; }
+        if let Some(value) = layer.config_path { self.config_path = value; }
+        if let Some(value) = layer.db_file_name { self.db_file_name = value; }
+        if let Some(value) = layer.data_path { self.data_path = value; }
+        if let Some(value) = layer.max_chunk_size { self.max_chunk_size = value; }
+        if let Some(value) = layer.hash_cache_timeout { self.hash_cache_timeout = value; }
+        if let Some(value) = layer.tree_owner { self.tree_owner = value; }
+        if let Some(value) = layer.ignore { self.ignore = value; }
+        if let Some(value) = layer.settle_time { self.settle_time = value; }
+        if let Some(value) = layer.symlinks { self.symlinks = SymlinkPolicy::parse(&value)?; }
+        if let Some(value) = layer.hash_algorithm { self.hash_algorithm = HashAlgorithm::parse(&value)?; }
+        if let Some(value) = layer.hash_workers { self.hash_workers = value; }
+        if let Some(value) = layer.snapshot_dir { self.snapshot_dir = Some(value); }
+        if let Some(value) = layer.verify_hash { self.verify_hash = value; }
+        if let Some(value) = layer.versions_dir { self.versions_dir = Some(value); }
+        if let Some(value) = layer.versions_keep { self.versions_keep = value; }
+        if let Some(value) = layer.versions_max_age { self.versions_max_age = value; }
+        if let Some(value) = layer.chunk_dir { self.chunk_dir = Some(value); }
+        if let Some(value) = layer.close_timeout { self.close_timeout = value; }
+        if let Some(value) = layer.shutdown_timeout { self.shutdown_timeout = value; }
+        if let Some(value) = layer.ping_interval { self.ping_interval = value; }
+        if let Some(value) = layer.idle_timeout { self.idle_timeout = value; }
+        if let Some(value) = layer.auth_timeout { self.auth_timeout = value; }
+        if let Some(value) = layer.key_rotation_grace { self.key_rotation_grace = value; }
+        if let Some(value) = layer.auth_max_failures { self.auth_max_failures = value; }
+        if let Some(value) = layer.auth_backoff { self.auth_backoff = value; }
+        if let Some(value) = layer.auth_lockout { self.auth_lockout = value; }
+        if let Some(value) = layer.throttle_utc_offset { self.throttle_utc_offset = parse_utc_offset(&value)?; }
+        if let Some(value) = layer.log_format { self.log_format = LogFormat::parse(&value)?; }
+        if let Some(value) = layer.log_level { self.log_level = value; }
+        Ok(())
+    }
+
+    /** Check every setting, all the problems found are reported together */
+    pub fn validate(self: &Config) -> Result<(), String> {
+        return self.check(true);
+    }
+
+    fn check(self: &Config, served_dirs_exist: bool) -> Result<(), String> {
+        let mut errors: Vec<String> = Vec::new();
+
+        for (name, bind) in [("web_bind", &self.web_bind), ("ws_bind", &self.ws_bind)] {
+            if bind.parse::<IpAddr>().is_err() {
+                errors.push(format!("{}: '{}' is not an IP address", name, bind));
+            }
+        }
+        for (name, port) in [("web_port", self.web_port), ("ws_port", self.ws_port)] {
+            if port == 0 {
+                errors.push(format!("{}: the port must be between 1 and 65535", name));
+            }
+        }
+        if self.web_port == self.ws_port {
+            errors.push(format!("ws_port: the port {} is already used by the web server", self.ws_port));
+        }
+        for (name, path) in [("config_path", &self.config_path), ("data_path", &self.data_path)] {
+            if served_dirs_exist && !Path::new(path).is_dir() {
+                errors.push(format!("{}: '{}' is not an existing directory", name, path));
+            }
+        }
+        if let Some(snapshot_dir) = &self.snapshot_dir {
+            if !Path::new(snapshot_dir).is_dir() {
+                errors.push(format!("snapshot_dir: '{}' is not an existing directory", snapshot_dir));
+            }
+        }
+        if self.db_file_name.is_empty() || self.db_file_name.contains('/') {
+            errors.push(format!("db_file_name: '{}' is not a file name", self.db_file_name));
+        }
+        if self.max_chunk_size == 0 || self.max_chunk_size > MAX_CHUNK_SIZE_LIMIT {
+            errors.push(format!(
+                "max_chunk_size: {} must be between 1 and {} bytes", self.max_chunk_size, MAX_CHUNK_SIZE_LIMIT
+            ));
+        }
+        if let Err(err) = IgnoreRules::new(&self.ignore) {
+            errors.push(format!("ignore: {}", err));
+        }
+        if self.hash_workers == 0 {
+            errors.push("hash_workers: at least 1 thread is needed to hash the files".to_string());
+        }
+        if self.auth_max_failures == 0 {
+            errors.push("auth_max_failures: at least 1 failure is needed for a lockout".to_string());
+        }
+        let timeouts = [
+            ("hash_cache_timeout", self.hash_cache_timeout),
+            ("close_timeout", self.close_timeout),
+            ("auth_lockout", self.auth_lockout),
+            ("ping_interval", self.ping_interval),
+            ("idle_timeout", self.idle_timeout),
+            ("auth_timeout", self.auth_timeout),
+        ];
+        for (name, timeout) in timeouts {
+            if timeout == 0 {
+                errors.push(format!("{}: the timeout must be at least 1 second", name));
+            }
+        }
+        if self.key_rotation_grace < 0 {
+            errors.push(format!("key_rotation_grace: {} can not be negative", self.key_rotation_grace));
+        }
+        if let Err(err) = EnvFilter::try_new(&self.log_level) {
+            errors.push(format!("log_level: '{}' is not a valid filter: {}", self.log_level, err));
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        return Err(format!("Invalid configuration:\n  {}", errors.join("\n  ")));
+    }
+
+    pub fn db_path(self: &Config) -> String {
+        format!("{}/{}", self.config_path, self.db_file_name)
+    }
+
+    /** How long the previous versions of the files are kept */
+    pub fn retention(self: &Config) -> Retention {
+        Retention {
+            max_versions: Some(self.versions_keep).filter(|keep| *keep > 0),
+            max_age: Some(self.versions_max_age).filter(|age| *age > 0).map(Duration::from_secs),
+        }
+    }
+
+    pub fn ws_options(self: &Config) -> WsOptions {
+        WsOptions {
+            bind: self.ws_bind.clone(),
+            port: self.ws_port,
+            max_chunk_size: self.max_chunk_size,
+            close_timeout: Duration::from_secs(self.close_timeout),
+            auth_policy: AuthPolicy {
+                max_failures: self.auth_max_failures,
+                backoff: Duration::from_secs(self.auth_backoff),
+                lockout: Duration::from_secs(self.auth_lockout),
+            },
+            ping_interval: Duration::from_secs(self.ping_interval),
+            idle_timeout: Duration::from_secs(self.idle_timeout),
+            auth_timeout: Duration::from_secs(self.auth_timeout),
+            throttle_utc_offset: self.throttle_utc_offset,
+            ..WsOptions::default()
+        }
+    }
+}
@@ -1,17 +1,44 @@
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::env::{self, current_dir};
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 use actix_web::web::Data;
-use rusqlite::{Connection, params, Statement, ToSql};
-
-use crate::api::api::Client;
+use rusqlite::{Connection, OptionalExtension, params, Statement, ToSql};
+use tracing::info;
+
+use crate::api::api::{Client, LimitPolicy};
+use crate::config::Config;
+use keys::NewClientKey;
+
+pub mod audit;
+pub mod ignore_rules;
+pub mod keys;
+pub mod lockouts;
+pub mod migrations;
+pub mod rate_limits;
+pub mod shares;
+
+/// Seconds the previous keys of a client stay valid after a key rotation.
+pub const DEFAULT_KEY_ROTATION_GRACE: i64 = 24 * 60 * 60;
+
+/// Client columns, the key is the newest key of the client that is still active.
+const CLIENT_SELECT: &str = "SELECT c.id, \
+    (SELECT k.key FROM client_key AS k WHERE k.client_id = c.id AND k.revoked_at IS NULL \
+     AND (k.expires_at IS NULL OR k.expires_at > CAST(strftime('%s', 'now') AS INTEGER)) \
+     ORDER BY k.created_at DESC, k.id DESC LIMIT 1), \
+    c.name, c.max_connections, c.limit_policy FROM client AS c";
+
+/** Current unix time in seconds */
+pub fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Configuration {
     version: String,
+    /// version of the database schema, after the migrations
+    pub schema_version: i64,
 }
 
 pub struct ConfigurationOption {
@@ -20,48 +47,50 @@ pub struct ConfigurationOption {
 }
 
 pub struct DataService {
-    pub connection_status: HashSet<String>,
     pub db_connection: Data<Mutex<Connection>>,
+    /// seconds the previous keys stay valid after rotating the key of a client
+    pub key_rotation_grace: i64,
+}
+
+impl Default for DataService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl DataService {
+    /** Service over the database of the settings in the environment, `CONFIG_PATH`
+    and `DB_FILE_NAME` among them, and in the config file */
     pub fn new() -> DataService {
-        let config_path: String = env::var("CONFIG_PATH")
-            .unwrap_or(current_dir().unwrap().display().to_string());
-        let db_file_name: String = env::var("DB_FILE_NAME")
-            .unwrap_or("data.db".to_string());
-        println!("{}", db_file_name);
-        let config_file = String::from(format!("{}/{}", config_path, db_file_name));
-        println!("{}", config_file);
-
-        let db_connection = Connection::open(config_file).unwrap();
-        DataService::initialize_db(&db_connection);
-
-        DataService {
-            connection_status: HashSet::new(),
-            db_connection: Data::new(Mutex::new(db_connection)),
-        }
+        let config = Config::from_env().unwrap_or_else(|err| panic!("{}", err));
+        DataService::open(config.db_path(), config.key_rotation_grace)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 
-    pub fn initialize_db(db_connection: &Connection) {
-        // create initial configuration table
-        db_connection.execute(
-            "CREATE TABLE IF NOT EXISTS configuration ( key TEXT PRIMARY KEY, value TEXT )",
-            [],
-        ).unwrap();
+    /** Open the sqlite database in the given path and bring its schema up to date */
+    pub fn open(db_path: String, key_rotation_grace: i64) -> Result<DataService, String> {
+        info!(db_path, "Opening database");
 
-        // create initial client table
-        db_connection.execute(
-            "CREATE TABLE IF NOT EXISTS client ( id INTEGER PRIMARY KEY, key TEXT NOT NULL, name TEXT )",
-            [],
-        ).unwrap();
+        let db_connection = Connection::open(db_path.clone())
+            .map_err(|err| format!("Problems opening the database {}: {}", db_path, err))?;
+        DataService::initialize_db(&db_connection)?;
+
+        Ok(DataService {
+            db_connection: Data::new(Mutex::new(db_connection)),
+            key_rotation_grace,
+        })
+    }
+
+    /** Run the pending schema migrations, returns the resulting schema version */
+    pub fn initialize_db(db_connection: &Connection) -> Result<i64, String> {
+        migrations::migrate(db_connection)
     }
 
-    /** Migrate to sqlite db at some point*/
     pub fn read_configuration(self: &DataService) -> Configuration {
         let db_connection = self.db_connection.lock().unwrap();
         let mut config = Configuration {
             version: "1.0.0".to_string(),
+            schema_version: 0,
         };
 
         let mut stmt = db_connection.prepare(
@@ -78,37 +107,64 @@ impl DataService {
             let it = conf.unwrap();
             if it.key == "version" {
                 config.version = it.value;
+            } else if it.key == migrations::SCHEMA_VERSION_KEY {
+                config.schema_version = it.value.parse().unwrap_or(0);
             }
         }
 
         return config;
     }
 
+    /** Check the database accepts writes, used by the readiness probe */
+    pub fn check_writable(self: &DataService) -> Result<(), String> {
+        let db_connection = self.db_connection.lock().unwrap();
+        db_connection.execute(
+            "INSERT INTO configuration (key, value) VALUES ('last_readiness_check', ?1) \
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value;",
+            [now_secs().to_string()],
+        ).map(|_| ()).map_err(|err| format!("Problems writing to the database: {}", err))
+    }
+
     pub fn get_clients(self: &DataService) -> Vec<Client> {
         let db_connection = self.db_connection.lock().unwrap();
         let mut stmt = db_connection.prepare(
-            "SELECT * FROM client;",
+            &format!("{};", CLIENT_SELECT),
         ).unwrap();
 
         return self.get_client_from_query(&mut stmt, params![]);
     }
 
+    /** Whether the key is an active key of the client, marking the key as used when it is */
     pub fn validate_user_auth(self: &DataService, name: String, key: String) -> bool {
         let db_connection = self.db_connection.lock().unwrap();
-        let mut stmt = db_connection.prepare(
-            "SELECT * FROM client AS c WHERE c.name = ?1 AND c.key = ?2;",
-        ).unwrap();
-
-        let v = self.get_client_from_query(&mut stmt, params![name, key]);
-        return !v.is_empty();
+        let now = now_secs();
+        let key_id: Option<i64> = db_connection.query_row(
+            "SELECT k.id FROM client_key AS k JOIN client AS c ON c.id = k.client_id \
+             WHERE c.name = ?1 AND k.key = ?2 AND k.revoked_at IS NULL \
+             AND (k.expires_at IS NULL OR k.expires_at > ?3);",
+            params![name, key, now],
+            |row| row.get(0),
+        ).optional().unwrap();
+
+        if let Some(key_id) = key_id {
+            db_connection.execute(
+                "UPDATE client_key SET last_used_at = ?1 WHERE id = ?2;",
+                params![now, key_id],
+            ).unwrap();
+        }
+        return key_id.is_some();
     }
 
     pub fn remove_client(self: &DataService, id: i64) -> Client {
         let client_removed = self.get_client(id);
 
-        let db_connection = self.db_connection.lock();
+        let db_connection = self.db_connection.lock().unwrap();
 
-        db_connection.unwrap().execute(
+        db_connection.execute(
+            "DELETE FROM client_key AS k WHERE k.client_id = ?1;",
+            [id],
+        ).unwrap();
+        db_connection.execute(
             "DELETE FROM client AS c WHERE c.id = ?1;",
             [id],
         ).unwrap();
@@ -117,20 +173,20 @@ impl DataService {
     }
 
     pub fn new_client(self: &DataService, client: Client) -> Client {
-        let new_client_id: i64;
-
         let existing = self.get_client_by_name(client.name.clone().unwrap());
-        if existing.is_none() {
+        if let Some(existing) = existing {
+            return existing;
+        }
+
+        let new_client_id: i64 = {
             let db_connection = self.db_connection.lock().unwrap();
 
             db_connection.execute(
-                "INSERT INTO client (name, key) VALUES (?1, ?2);",
-                [client.name.unwrap(), "".to_string()],
+                "INSERT INTO client (name) VALUES (?1);",
+                [client.name.unwrap()],
             ).unwrap();
-            new_client_id = db_connection.last_insert_rowid();
-        } else {
-            return existing.unwrap();
-        }
+            db_connection.last_insert_rowid()
+        };
 
         return self.gen_key(new_client_id);
     }
@@ -138,31 +194,23 @@ impl DataService {
     pub fn get_client_by_name(self: &DataService, client_name: String) -> Option<Client> {
         let db_connection = self.db_connection.lock().unwrap();
         let mut stmt = db_connection.prepare(
-            "SELECT * FROM client AS c WHERE c.name = ?1;",
+            &format!("{} WHERE c.name = ?1;", CLIENT_SELECT),
         ).unwrap();
 
         let v: Vec<Client> = self.get_client_from_query(&mut stmt, params![client_name]);
         return if v.is_empty() { None } else { Some(v[0].clone()) };
     }
 
+    /** Newest active key of the client */
     pub fn get_client_key(self: &DataService, client_name: String) -> Option<String> {
-        let db_connection = self.db_connection.lock().unwrap();
-        let mut stmt = db_connection.prepare(
-            "SELECT * FROM client AS c WHERE c.name = ?1;",
-        ).unwrap();
-
-        let v: Vec<Client> = self.get_client_from_query(&mut stmt, params![client_name]);
-        if v.is_empty() {
-            return None;
-        } else {
-            return Some(v[0].key.clone().unwrap());
-        }
+        return self.get_client_by_name(client_name).and_then(|client| client.key);
     }
 
+    /** Rotate the client key, the previous keys stay valid during the rotation grace period */
     pub fn gen_key(self: &DataService, id: i64) -> Client {
         let mut client = self.get_client(id);
 
-        client.key = Some(format!("{}-{}", self.gen_str(10), self.gen_str(5)));
+        client.key = Some(self.gen_key_value());
 
         return self.update_client(client, true);
     }
@@ -173,20 +221,39 @@ impl DataService {
         {
             let db_connection = self.db_connection.lock().unwrap();
 
-            let key = if update_key { client.key.unwrap() } else { current_client.key.unwrap() };
+            let max_connections = client.max_connections.or(current_client.max_connections);
+            let limit_policy = client.limit_policy.or(current_client.limit_policy).unwrap();
             db_connection.execute(
-                "UPDATE client SET name=?1, key=?2 WHERE id=?3;",
-                [client.name.unwrap(), key, format!("{}", client.id.unwrap())],
+                "UPDATE client SET name=?1, max_connections=?2, limit_policy=?3 WHERE id=?4;",
+                (client.name.unwrap(), max_connections, limit_policy.as_str(), client.id.unwrap()),
             ).unwrap();
         }
 
+        if update_key {
+            self.add_client_key(client.id.unwrap(), NewClientKey {
+                key: client.key,
+                label: None,
+                expires_in: None,
+                rotate: Some(true),
+            }).unwrap();
+        }
+
         return self.get_client(client.id.unwrap());
     }
 
+    pub fn client_exists(self: &DataService, id: i64) -> bool {
+        let db_connection = self.db_connection.lock().unwrap();
+        return db_connection
+            .query_row("SELECT 1 FROM client WHERE id = ?1;", [id], |_| Ok(()))
+            .optional()
+            .unwrap()
+            .is_some();
+    }
+
     pub fn get_client(self: &DataService, id: i64) -> Client {
         let db_connection = self.db_connection.lock().unwrap();
         let mut stmt = db_connection.prepare(
-            "SELECT * FROM client AS c WHERE c.id=?1;",
+            &format!("{} WHERE c.id = ?1;", CLIENT_SELECT),
         ).unwrap();
 
         let clients = self.get_client_from_query(&mut stmt, params![id]);
@@ -195,10 +262,13 @@ impl DataService {
 
     pub fn get_client_from_query(self: &DataService, stmt: &mut Statement<'_>, params: &[&dyn ToSql]) -> Vec<Client> {
         let clients_mapped = stmt.query_map(params.as_ref(), |row| {
+            let limit_policy: String = row.get(4)?;
             Ok(Client {
                 id: row.get(0)?,
                 key: row.get(1)?,
                 name: row.get(2)?,
+                max_connections: row.get(3)?,
+                limit_policy: Some(LimitPolicy::parse(&limit_policy)),
             })
         }).unwrap();
 
@@ -210,6 +280,10 @@ impl DataService {
         return clients;
     }
 
+    fn gen_key_value(self: &DataService) -> String {
+        return format!("{}-{}", self.gen_str(10), self.gen_str(5));
+    }
+
     fn gen_str(self: &DataService, size: usize) -> String {
         return thread_rng()
             .sample_iter(&Alphanumeric)
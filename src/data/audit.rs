@@ -0,0 +1,171 @@
+use rusqlite::ToSql;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{now_secs, DataService};
+
+pub const AUTH_SUCCESS: &str = "auth_success";
+pub const AUTH_FAILURE: &str = "auth_failure";
+pub const AUTH_LOCKOUT: &str = "auth_lockout";
+pub const TREE_REQUEST: &str = "tree";
+pub const FILE_TRANSFER: &str = "copy";
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+
+/** Row of the append-only audit log */
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AuditEntry {
+    pub id: Option<i64>,
+    /// unix time in seconds
+    pub time: i64,
+    pub event: String,
+    pub client: Option<String>,
+    pub peer: Option<String>,
+    pub file_hash: Option<String>,
+    pub bytes: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(event: &str, client: Option<String>, peer: Option<String>) -> AuditEntry {
+        AuditEntry {
+            time: now_secs(),
+            event: event.to_string(),
+            client,
+            peer,
+            ..Default::default()
+        }
+    }
+}
+
+/** Filters and paging accepted when querying the audit log */
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub event: Option<String>,
+    pub client: Option<String>,
+    pub peer: Option<String>,
+    pub file_hash: Option<String>,
+    /// inclusive lower bound, unix time in seconds
+    pub since: Option<i64>,
+    /// exclusive upper bound, unix time in seconds
+    pub until: Option<i64>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl DataService {
+    pub fn add_audit_entry(self: &DataService, entry: AuditEntry) {
+        let db_connection = self.db_connection.lock().unwrap();
+        db_connection.execute(
+            "INSERT INTO audit (time, event, client, peer, file_hash, bytes, duration_ms, detail) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+            (
+                entry.time,
+                entry.event,
+                entry.client,
+                entry.peer,
+                entry.file_hash,
+                entry.bytes,
+                entry.duration_ms,
+                entry.detail,
+            ),
+        ).unwrap();
+    }
+
+    /** Audit entries matching the filter, newest first. Without a limit every match is returned */
+    pub fn get_audit_entries(self: &DataService, filter: &AuditFilter) -> Vec<AuditEntry> {
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+
+        let text_filters = [
+            ("event = ?", &filter.event),
+            ("client = ?", &filter.client),
+            ("peer = ?", &filter.peer),
+            ("file_hash = ?", &filter.file_hash),
+        ];
+        for (condition, value) in text_filters {
+            if let Some(value) = value {
+                conditions.push(condition);
+                params.push(Box::new(value.clone()));
+            }
+        }
+        if let Some(since) = filter.since {
+            conditions.push("time >= ?");
+            params.push(Box::new(since));
+        }
+        if let Some(until) = filter.until {
+            conditions.push("time < ?");
+            params.push(Box::new(until));
+        }
+
+        let mut query = "SELECT id, time, event, client, peer, file_hash, bytes, duration_ms, detail \
+                         FROM audit".to_string();
+        if !conditions.is_empty() {
+            query = format!("{} WHERE {}", query, conditions.join(" AND "));
+        }
+        query = format!("{} ORDER BY time DESC, id DESC LIMIT ? OFFSET ?;", query);
+        params.push(Box::new(filter.limit.unwrap_or(-1)));
+        params.push(Box::new(filter.offset.unwrap_or(0)));
+
+        let db_connection = self.db_connection.lock().unwrap();
+        let mut stmt = db_connection.prepare(&query).unwrap();
+        let params_ref: Vec<&dyn ToSql> = params.iter().map(|param| param.as_ref()).collect();
+        let entries_mapped = stmt.query_map(params_ref.as_slice(), |row| {
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                time: row.get(1)?,
+                event: row.get(2)?,
+                client: row.get(3)?,
+                peer: row.get(4)?,
+                file_hash: row.get(5)?,
+                bytes: row.get(6)?,
+                duration_ms: row.get(7)?,
+                detail: row.get(8)?,
+            })
+        }).unwrap();
+
+        let mut entries = Vec::new();
+        for entry in entries_mapped {
+            entries.push(entry.unwrap());
+        }
+
+        return entries;
+    }
+
+    /** Same as `get_audit_entries` but applying the default page size when no limit is given */
+    pub fn get_audit_page(self: &DataService, filter: &AuditFilter) -> Vec<AuditEntry> {
+        let mut filter = filter.clone();
+        filter.limit = Some(filter.limit.unwrap_or(DEFAULT_PAGE_SIZE));
+        return self.get_audit_entries(&filter);
+    }
+}
+
+fn csv_field(value: Option<String>) -> String {
+    let value = value.unwrap_or_default();
+    if value.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value;
+}
+
+/** Render the audit entries as CSV, including a header row */
+pub fn audit_to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("id,time,event,client,peer,file_hash,bytes,duration_ms,detail\n");
+    for entry in entries {
+        let fields = [
+            entry.id.map(|v| v.to_string()),
+            Some(entry.time.to_string()),
+            Some(entry.event.clone()),
+            entry.client.clone(),
+            entry.peer.clone(),
+            entry.file_hash.clone(),
+            entry.bytes.map(|v| v.to_string()),
+            entry.duration_ms.map(|v| v.to_string()),
+            entry.detail.clone(),
+        ];
+        let line: Vec<String> = fields.into_iter().map(csv_field).collect();
+        csv.push_str(&line.join(","));
+        csv.push('\n');
+    }
+    return csv;
+}
@@ -0,0 +1,92 @@
+use rusqlite::{params, OptionalExtension};
+
+use crate::data::shares::Share;
+use crate::data::DataService;
+use crate::file::ignore::{ClientScope, IgnoreRules};
+
+/** Patterns stored as a JSON array, missing for no patterns. A value that can't be read is
+an error, serving what the patterns should exclude is worse than not serving it */
+pub fn parse_patterns(value: Option<String>) -> Result<Vec<String>, String> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    return serde_json::from_str(&value)
+        .map_err(|err| format!("Invalid ignore patterns stored in the database: {}", err));
+}
+
+/** Check the patterns and turn them into the stored value */
+fn store_patterns(patterns: &[String]) -> Result<Option<String>, String> {
+    IgnoreRules::new(patterns)?;
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    return Ok(Some(serde_json::to_string(patterns).unwrap()));
+}
+
+impl DataService {
+    /** Replace the ignore patterns of a share, applied the next time the server starts */
+    pub fn set_share_ignore(self: &DataService, name: &str, patterns: &[String]) -> Result<Share, String> {
+        let value = store_patterns(patterns)?;
+        let updated = {
+            let db_connection = self.db_connection.lock().unwrap();
+            db_connection.execute(
+                "UPDATE share SET ignore = ?1 WHERE name = ?2;",
+                params![value, name],
+            ).map_err(|err| format!("Problems saving the ignore patterns: {}", err))?
+        };
+        if updated == 0 {
+            return Err(format!("There is no share named '{}'", name));
+        }
+        return Ok(self.get_shares().into_iter().find(|share| share.name == name).unwrap());
+    }
+
+    /** Ignore patterns of a client, `None` when there is no such client */
+    pub fn get_client_ignore(self: &DataService, client_id: i64) -> Result<Option<Vec<String>>, String> {
+        let db_connection = self.db_connection.lock().unwrap();
+        let value: Option<Option<String>> = db_connection.query_row(
+            "SELECT ignore FROM client WHERE id = ?1;",
+            [client_id],
+            |row| row.get(0),
+        ).optional().unwrap();
+        return value.map(parse_patterns).transpose();
+    }
+
+    /** Set or remove (with `None`) the ignore patterns of a client */
+    pub fn set_client_ignore(
+        self: &DataService,
+        client_id: i64,
+        patterns: Option<&[String]>,
+    ) -> Result<(), String> {
+        let value = match patterns {
+            Some(patterns) => store_patterns(patterns)?,
+            None => None,
+        };
+        let db_connection = self.db_connection.lock().unwrap();
+        let updated = db_connection.execute(
+            "UPDATE client SET ignore = ?1 WHERE id = ?2;",
+            params![value, client_id],
+        ).map_err(|err| format!("Problems saving the ignore patterns: {}", err))?;
+        if updated == 0 {
+            return Err(format!("There is no client with id {}", client_id));
+        }
+        Ok(())
+    }
+
+    /** What the client with the given name can see of the tree, an error when its patterns
+    can't be read */
+    pub fn get_client_scope(self: &DataService, client_name: &str) -> Result<ClientScope, String> {
+        let value: Option<String> = {
+            let db_connection = self.db_connection.lock().unwrap();
+            db_connection.query_row(
+                "SELECT ignore FROM client WHERE name = ?1;",
+                [client_name],
+                |row| row.get(0),
+            ).optional()
+                .map_err(|err| format!("Problems reading the ignore patterns: {}", err))?
+                .flatten()
+        };
+        let patterns = parse_patterns(value).map_err(|err| format!("{} for the client {}", err, client_name))?;
+        return ClientScope::new(Some(client_name.to_string()), &patterns);
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt;
+
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::data::{now_secs, DataService};
+
+const KEY_COLUMNS: &str =
+    "id, client_id, key, label, created_at, expires_at, last_used_at, revoked_at";
+/// characters of a key shown in the lists, enough to tell the keys of a client apart
+const KEY_PREFIX_LEN: usize = 4;
+
+/** Key a client can authenticate with */
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ClientKey {
+    pub id: i64,
+    pub client_id: i64,
+    pub key: String,
+    pub label: Option<String>,
+    /// unix times in seconds
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+/** Key as listed, without the key material. The key itself is only shown when it is created */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct KeySummary {
+    pub id: i64,
+    pub client_id: i64,
+    /// first characters of the key
+    pub prefix: String,
+    pub label: Option<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+impl From<&ClientKey> for KeySummary {
+    fn from(key: &ClientKey) -> KeySummary {
+        KeySummary {
+            id: key.id,
+            client_id: key.client_id,
+            prefix: key.key.chars().take(KEY_PREFIX_LEN).collect(),
+            label: key.label.clone(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            last_used_at: key.last_used_at,
+            revoked_at: key.revoked_at,
+        }
+    }
+}
+
+/** Request to add a key to a client */
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct NewClientKey {
+    /// generated when missing
+    pub key: Option<String>,
+    pub label: Option<String>,
+    /// seconds until the key expires, never when missing, more than 0
+    pub expires_in: Option<i64>,
+    /// start the rotation grace period of the other keys of the client
+    pub rotate: Option<bool>,
+}
+
+impl fmt::Debug for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientKey")
+            .field("id", &self.id)
+            .field("client_id", &self.client_id)
+            .field("key", &format_args!("<redacted>"))
+            .field("label", &self.label)
+            .field("created_at", &self.created_at)
+            .field("expires_at", &self.expires_at)
+            .field("last_used_at", &self.last_used_at)
+            .field("revoked_at", &self.revoked_at)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NewClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewClientKey")
+            .field("key", &self.key.as_ref().map(|_| format_args!("<redacted>")))
+            .field("label", &self.label)
+            .field("expires_in", &self.expires_in)
+            .field("rotate", &self.rotate)
+            .finish()
+    }
+}
+
+fn key_from_row(row: &Row<'_>) -> rusqlite::Result<ClientKey> {
+    Ok(ClientKey {
+        id: row.get(0)?,
+        client_id: row.get(1)?,
+        key: row.get(2)?,
+        label: row.get(3)?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        last_used_at: row.get(6)?,
+        revoked_at: row.get(7)?,
+    })
+}
+
+impl DataService {
+    pub fn get_client_keys(self: &DataService, client_id: i64) -> Vec<ClientKey> {
+        let db_connection = self.db_connection.lock().unwrap();
+        let mut stmt = db_connection.prepare(
+            &format!("SELECT {} FROM client_key WHERE client_id = ?1 ORDER BY created_at, id;", KEY_COLUMNS),
+        ).unwrap();
+        let keys_mapped = stmt.query_map([client_id], key_from_row).unwrap();
+
+        let mut keys = Vec::new();
+        for key in keys_mapped {
+            keys.push(key.unwrap());
+        }
+        return keys;
+    }
+
+    pub fn get_client_key_by_id(self: &DataService, client_id: i64, key_id: i64) -> Option<ClientKey> {
+        let db_connection = self.db_connection.lock().unwrap();
+        return db_connection.query_row(
+            &format!("SELECT {} FROM client_key WHERE client_id = ?1 AND id = ?2;", KEY_COLUMNS),
+            [client_id, key_id],
+            key_from_row,
+        ).optional().unwrap();
+    }
+
+    /** Add a key to the client. When rotating, the other active keys of the client
+    expire once the rotation grace period is over */
+    pub fn add_client_key(self: &DataService, client_id: i64, new_key: NewClientKey) -> Result<ClientKey, String> {
+        if let Some(expires_in) = new_key.expires_in.filter(|expires_in| *expires_in <= 0) {
+            return Err(format!("The key would already be expired, expires_in is {} seconds", expires_in));
+        }
+        if !self.client_exists(client_id) {
+            return Err(format!("There is no client with id {}", client_id));
+        }
+        let now = now_secs();
+        let key = new_key.key.unwrap_or_else(|| self.gen_key_value());
+        let expires_at = new_key.expires_in.map(|expires_in| now + expires_in);
+
+        let key_id = {
+            let db_connection = self.db_connection.lock().unwrap();
+            if new_key.rotate.unwrap_or(false) {
+                let grace_end = now + self.key_rotation_grace;
+                db_connection.execute(
+                    "UPDATE client_key SET expires_at = ?1 WHERE client_id = ?2 AND revoked_at IS NULL \
+                     AND (expires_at IS NULL OR expires_at > ?1);",
+                    params![grace_end, client_id],
+                ).unwrap();
+            }
+            db_connection.execute(
+                "INSERT INTO client_key (client_id, key, label, created_at, expires_at) \
+                 VALUES (?1, ?2, ?3, ?4, ?5);",
+                params![client_id, key, new_key.label, now, expires_at],
+            ).unwrap();
+            db_connection.last_insert_rowid()
+        };
+
+        return Ok(self.get_client_key_by_id(client_id, key_id).unwrap());
+    }
+
+    /** Revoke the key right away, returns the revoked key if it belongs to the client */
+    pub fn revoke_client_key(self: &DataService, client_id: i64, key_id: i64) -> Option<ClientKey> {
+        {
+            let db_connection = self.db_connection.lock().unwrap();
+            db_connection.execute(
+                "UPDATE client_key SET revoked_at = ?1 \
+                 WHERE client_id = ?2 AND id = ?3 AND revoked_at IS NULL;",
+                params![now_secs(), client_id, key_id],
+            ).unwrap();
+        }
+        return self.get_client_key_by_id(client_id, key_id);
+    }
+}
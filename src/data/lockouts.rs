@@ -0,0 +1,121 @@
+use rusqlite::{params, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::data::{now_secs, DataService};
+
+const LOCKOUT_SELECT: &str = "SELECT id, kind, subject, failures, created_at, locked_until, lifted_at FROM auth_lockout";
+
+/** Peer or client that can't authenticate for a while after too many failed attempts */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Lockout {
+    pub id: i64,
+    /// `peer` or `client`
+    pub kind: String,
+    /// address of the peer, or id of the client (`unknown` for the names that are not a client)
+    pub subject: String,
+    pub failures: i64,
+    /// unix time in seconds
+    pub created_at: i64,
+    /// unix time in seconds
+    pub locked_until: i64,
+    /// unix time in seconds, when an administrator lifted the lockout
+    pub lifted_at: Option<i64>,
+}
+
+impl Lockout {
+    pub fn is_active(self: &Lockout, now: i64) -> bool {
+        self.lifted_at.is_none() && self.locked_until > now
+    }
+}
+
+fn lockout_from_row(row: &Row<'_>) -> rusqlite::Result<Lockout> {
+    Ok(Lockout {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        subject: row.get(2)?,
+        failures: row.get(3)?,
+        created_at: row.get(4)?,
+        locked_until: row.get(5)?,
+        lifted_at: row.get(6)?,
+    })
+}
+
+impl DataService {
+    pub fn add_lockout(
+        self: &DataService,
+        kind: &str,
+        subject: &str,
+        failures: u32,
+        duration_secs: i64,
+    ) -> Lockout {
+        let now = now_secs();
+        let id = {
+            let db_connection = self.db_connection.lock().unwrap();
+            db_connection.execute(
+                "INSERT INTO auth_lockout (kind, subject, failures, created_at, locked_until) \
+                 VALUES (?1, ?2, ?3, ?4, ?5);",
+                params![kind, subject, failures, now, now + duration_secs],
+            ).unwrap();
+            db_connection.last_insert_rowid()
+        };
+        return self.get_lockout(id).unwrap();
+    }
+
+    pub fn get_lockout(self: &DataService, id: i64) -> Option<Lockout> {
+        let db_connection = self.db_connection.lock().unwrap();
+        return db_connection.query_row(
+            &format!("{} WHERE id = ?1;", LOCKOUT_SELECT),
+            [id],
+            lockout_from_row,
+        ).optional().unwrap();
+    }
+
+    /** Lockouts newest first, only the ones still in force when `active_only` */
+    pub fn get_lockouts(self: &DataService, active_only: bool) -> Vec<Lockout> {
+        let db_connection = self.db_connection.lock().unwrap();
+        let mut stmt = db_connection.prepare(&format!(
+            "{} WHERE ?1 = 0 OR (lifted_at IS NULL AND locked_until > ?2) ORDER BY created_at DESC, id DESC;",
+            LOCKOUT_SELECT
+        )).unwrap();
+        let lockouts_mapped = stmt.query_map(params![active_only, now_secs()], lockout_from_row).unwrap();
+
+        let mut lockouts = Vec::new();
+        for lockout in lockouts_mapped {
+            lockouts.push(lockout.unwrap());
+        }
+        return lockouts;
+    }
+
+    /** Lockout in force for the peer or client, the one lasting longer when there are several */
+    pub fn active_lockout(self: &DataService, kind: &str, subject: &str) -> Option<Lockout> {
+        let db_connection = self.db_connection.lock().unwrap();
+        return db_connection.query_row(
+            &format!(
+                "{} WHERE kind = ?1 AND subject = ?2 AND lifted_at IS NULL AND locked_until > ?3 \
+                 ORDER BY locked_until DESC LIMIT 1;",
+                LOCKOUT_SELECT
+            ),
+            params![kind, subject, now_secs()],
+            lockout_from_row,
+        ).optional().unwrap();
+    }
+
+    /** Lift a lockout still in force with the other ones of its peer or client, returns it
+    or `None` when there is no such lockout */
+    pub fn lift_lockout(self: &DataService, id: i64) -> Option<Lockout> {
+        let lockout = self.get_lockout(id)?;
+        let now = now_secs();
+        if !lockout.is_active(now) {
+            return None;
+        }
+        {
+            let db_connection = self.db_connection.lock().unwrap();
+            db_connection.execute(
+                "UPDATE auth_lockout SET lifted_at = ?1 \
+                 WHERE kind = ?2 AND subject = ?3 AND lifted_at IS NULL AND locked_until > ?1;",
+                params![now, lockout.kind, lockout.subject],
+            ).unwrap();
+        }
+        return self.get_lockout(id);
+    }
+}
@@ -0,0 +1,141 @@
+use rusqlite::{Connection, OptionalExtension};
+
+/// Key of the `configuration` row that stores the applied schema version.
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Ordered schema migrations, the migration at index `i` upgrades the
+/// database to schema version `i + 1`. Never edit an already released
+/// migration, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial client table
+    "CREATE TABLE IF NOT EXISTS client ( id INTEGER PRIMARY KEY, key TEXT NOT NULL, name TEXT );",
+    // 2: append-only audit log of connections and transfers
+    "CREATE TABLE audit (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        client TEXT,
+        peer TEXT,
+        file_hash TEXT,
+        bytes INTEGER,
+        duration_ms INTEGER,
+        detail TEXT
+    );
+    CREATE INDEX audit_time ON audit ( time );
+    CREATE INDEX audit_client ON audit ( client, time );",
+    // 3: per client concurrency limit
+    "ALTER TABLE client ADD COLUMN max_connections INTEGER;
+    ALTER TABLE client ADD COLUMN limit_policy TEXT NOT NULL DEFAULT 'reject';",
+    // 4: several keys per client, moving the existing keys out of the client table
+    "CREATE TABLE client_key (
+        id INTEGER PRIMARY KEY,
+        client_id INTEGER NOT NULL REFERENCES client ( id ),
+        key TEXT NOT NULL,
+        label TEXT,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER,
+        last_used_at INTEGER,
+        revoked_at INTEGER
+    );
+    CREATE INDEX client_key_client ON client_key ( client_id );
+    INSERT INTO client_key (client_id, key, label, created_at)
+        SELECT id, key, 'default', CAST(strftime('%s', 'now') AS INTEGER) FROM client WHERE key <> '';
+    ALTER TABLE client DROP COLUMN key;",
+    // 5: extra directories served next to the data path, and the persisted file hashes of each algorithm
+    "CREATE TABLE share (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        path TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE file_index (
+        path TEXT NOT NULL,
+        algorithm TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime_ns INTEGER NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY ( path, algorithm )
+    );",
+    // 6: per client bandwidth limit, a JSON rate schedule
+    "ALTER TABLE client ADD COLUMN rate_limit TEXT;",
+    // 7: peers and clients locked out after too many failed authentications
+    "CREATE TABLE auth_lockout (
+        id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        failures INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        locked_until INTEGER NOT NULL,
+        lifted_at INTEGER
+    );
+    CREATE INDEX auth_lockout_subject ON auth_lockout ( kind, subject, locked_until );",
+    // 8: gitignore style patterns of the shares and the clients, JSON arrays
+    "ALTER TABLE share ADD COLUMN ignore TEXT;
+    ALTER TABLE client ADD COLUMN ignore TEXT;",
+    // 9: hash algorithm of the shares
+    "ALTER TABLE share ADD COLUMN hash_algorithm TEXT;",
+];
+
+/// Schema version this build of the service knows how to work with.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.len() as i64
+}
+
+fn create_configuration_table(db_connection: &Connection) -> Result<(), String> {
+    db_connection.execute(
+        "CREATE TABLE IF NOT EXISTS configuration ( key TEXT PRIMARY KEY, value TEXT )",
+        [],
+    ).map_err(|err| format!("Problems creating the configuration table: {}", err))?;
+    Ok(())
+}
+
+/// Schema version recorded in the database, databases created before the
+/// migrations were introduced have no version and are reported as `0`.
+pub fn current_version(db_connection: &Connection) -> Result<i64, String> {
+    create_configuration_table(db_connection)?;
+    let value: Option<String> = db_connection.query_row(
+        "SELECT value FROM configuration WHERE key = ?1;",
+        [SCHEMA_VERSION_KEY],
+        |row| row.get(0),
+    ).optional().map_err(|err| format!("Problems reading the schema version: {}", err))?;
+
+    match value {
+        None => Ok(0),
+        Some(version) => version
+            .parse::<i64>()
+            .map_err(|_| format!("Invalid schema version stored in the database: {}", version)),
+    }
+}
+
+/// Apply every pending migration, each one inside its own transaction
+/// together with the update of the recorded schema version. Returns the
+/// schema version of the database after the migration.
+pub fn migrate(db_connection: &Connection) -> Result<i64, String> {
+    create_configuration_table(db_connection)?;
+
+    let current = current_version(db_connection)?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(format!(
+            "The database schema version ({}) is newer than the one supported by this service ({})",
+            current, latest
+        ));
+    }
+
+    for version in (current + 1)..=latest {
+        let sql = MIGRATIONS[(version - 1) as usize];
+        let tx = db_connection.unchecked_transaction()
+            .map_err(|err| format!("Problems starting migration {}: {}", version, err))?;
+        tx.execute_batch(sql)
+            .map_err(|err| format!("Problems applying migration {}: {}", version, err))?;
+        tx.execute(
+            "INSERT INTO configuration (key, value) VALUES (?1, ?2) \
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value;",
+            [SCHEMA_VERSION_KEY.to_string(), version.to_string()],
+        ).map_err(|err| format!("Problems recording migration {}: {}", version, err))?;
+        tx.commit()
+            .map_err(|err| format!("Problems committing migration {}: {}", version, err))?;
+    }
+
+    Ok(latest)
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use rusqlite::{params, OptionalExtension};
+
+use crate::data::DataService;
+use crate::throttle::{RateSchedule, ThrottleSettings};
+
+/// Key of the `configuration` row that stores the global rate schedule.
+pub const GLOBAL_RATE_LIMIT_KEY: &str = "rate_limit";
+
+fn parse_schedule(value: &str) -> Result<RateSchedule, String> {
+    serde_json::from_str(value).map_err(|err| format!("Invalid rate limit stored in the database: {}", err))
+}
+
+impl DataService {
+    /** Global and per client rate limits stored in the database */
+    pub fn get_throttle_settings(self: &DataService) -> Result<ThrottleSettings, String> {
+        let db_connection = self.db_connection.lock().unwrap();
+        let global: Option<String> = db_connection.query_row(
+            "SELECT value FROM configuration WHERE key = ?1;",
+            [GLOBAL_RATE_LIMIT_KEY],
+            |row| row.get(0),
+        ).optional().map_err(|err| format!("Problems reading the rate limits: {}", err))?;
+
+        let mut stmt = db_connection.prepare(
+            "SELECT id, rate_limit FROM client WHERE rate_limit IS NOT NULL;",
+        ).unwrap();
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        }).map_err(|err| format!("Problems reading the rate limits: {}", err))?;
+
+        let mut clients = BTreeMap::new();
+        for row in rows {
+            let (id, value) = row.map_err(|err| format!("Problems reading the rate limits: {}", err))?;
+            clients.insert(id, parse_schedule(&value)?);
+        }
+
+        Ok(ThrottleSettings {
+            global: match global {
+                Some(value) => parse_schedule(&value)?,
+                None => RateSchedule::unlimited(),
+            },
+            clients,
+        })
+    }
+
+    pub fn set_global_rate_limit(self: &DataService, schedule: &RateSchedule) -> Result<(), String> {
+        schedule.validate()?;
+        let db_connection = self.db_connection.lock().unwrap();
+        db_connection.execute(
+            "INSERT INTO configuration (key, value) VALUES (?1, ?2) \
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value;",
+            [GLOBAL_RATE_LIMIT_KEY.to_string(), serde_json::to_string(schedule).unwrap()],
+        ).map_err(|err| format!("Problems saving the rate limit: {}", err))?;
+        Ok(())
+    }
+
+    /** Set or remove (with `None`) the rate limit of a client */
+    pub fn set_client_rate_limit(
+        self: &DataService,
+        client_id: i64,
+        schedule: Option<&RateSchedule>,
+    ) -> Result<(), String> {
+        if let Some(schedule) = schedule {
+            schedule.validate()?;
+        }
+        let value = schedule.map(|schedule| serde_json::to_string(schedule).unwrap());
+        let db_connection = self.db_connection.lock().unwrap();
+        let updated = db_connection.execute(
+            "UPDATE client SET rate_limit = ?1 WHERE id = ?2;",
+            params![value, client_id],
+        ).map_err(|err| format!("Problems saving the rate limit: {}", err))?;
+        if updated == 0 {
+            return Err(format!("There is no client with id {}", client_id));
+        }
+        Ok(())
+    }
+}
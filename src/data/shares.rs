@@ -0,0 +1,149 @@
+use std::path::Path;
+
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::data::ignore_rules::parse_patterns;
+use crate::data::{now_secs, DataService};
+use crate::file::hash::HashAlgorithm;
+use crate::file::index::IndexEntry;
+
+/** Directory served to the clients as a top level folder of the tree */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Share {
+    pub id: i64,
+    pub name: String,
+    /// absolute path of the directory
+    pub path: String,
+    /// unix time in seconds
+    pub created_at: i64,
+    /// gitignore style patterns, relative to the path of the share
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// why the stored patterns can't be read, the share is not served then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_error: Option<String>,
+    /// algorithm of the file hashes, the one of the server when missing
+    #[serde(default)]
+    pub hash_algorithm: Option<HashAlgorithm>,
+}
+
+impl DataService {
+    pub fn get_shares(self: &DataService) -> Vec<Share> {
+        let db_connection = self.db_connection.lock().unwrap();
+        let mut stmt = db_connection.prepare(
+            "SELECT id, name, path, created_at, ignore, hash_algorithm FROM share ORDER BY name;",
+        ).unwrap();
+        let shares_mapped = stmt.query_map([], |row| {
+            let (ignore, ignore_error) = match parse_patterns(row.get(4)?) {
+                Ok(patterns) => (patterns, None),
+                Err(err) => (Vec::new(), Some(err)),
+            };
+            Ok(Share {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                path: row.get(2)?,
+                created_at: row.get(3)?,
+                ignore,
+                ignore_error,
+                hash_algorithm: row.get::<_, Option<String>>(5)?
+                    .and_then(|value| HashAlgorithm::parse(&value).ok()),
+            })
+        }).unwrap();
+
+        let mut shares = Vec::new();
+        for share in shares_mapped {
+            shares.push(share.unwrap());
+        }
+        return shares;
+    }
+
+    /** Add a share, the path must be an existing directory */
+    pub fn add_share(self: &DataService, name: String, path: String) -> Result<Share, String> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(format!("Invalid share name '{}'", name));
+        }
+        let path = Path::new(&path)
+            .canonicalize()
+            .map_err(|err| format!("Invalid share path {}: {}", path, err))?;
+        if !path.is_dir() {
+            return Err(format!("The share path {} is not a directory", path.display()));
+        }
+        if self.get_shares().iter().any(|share| share.name == name) {
+            return Err(format!("A share named '{}' already exists", name));
+        }
+
+        {
+            let db_connection = self.db_connection.lock().unwrap();
+            db_connection.execute(
+                "INSERT INTO share (name, path, created_at) VALUES (?1, ?2, ?3);",
+                params![name, path.display().to_string(), now_secs()],
+            ).map_err(|err| format!("Problems adding the share: {}", err))?;
+        }
+
+        return Ok(self.get_shares().into_iter().find(|share| share.name == name).unwrap());
+    }
+
+    pub fn get_file_index(self: &DataService) -> Vec<IndexEntry> {
+        let db_connection = self.db_connection.lock().unwrap();
+        let mut stmt = db_connection.prepare(
+            "SELECT path, size, mtime_ns, hash FROM file_index ORDER BY path, hash;",
+        ).unwrap();
+        let entries_mapped = stmt.query_map([], |row| {
+            Ok(IndexEntry {
+                path: row.get(0)?,
+                size: row.get::<_, i64>(1)? as u64,
+                mtime_ns: row.get(2)?,
+                hash: row.get(3)?,
+            })
+        }).unwrap();
+
+        let mut entries = Vec::new();
+        for entry in entries_mapped {
+            entries.push(entry.unwrap());
+        }
+        return entries;
+    }
+
+    /** Set or remove (with `None`) the hash algorithm of a share, applied the next time the server starts */
+    pub fn set_share_hash_algorithm(
+        self: &DataService,
+        name: &str,
+        algorithm: Option<HashAlgorithm>,
+    ) -> Result<Share, String> {
+        let updated = {
+            let db_connection = self.db_connection.lock().unwrap();
+            db_connection.execute(
+                "UPDATE share SET hash_algorithm = ?1 WHERE name = ?2;",
+                params![algorithm.map(|algorithm| algorithm.as_str()), name],
+            ).map_err(|err| format!("Problems saving the hash algorithm: {}", err))?
+        };
+        if updated == 0 {
+            return Err(format!("There is no share named '{}'", name));
+        }
+        return Ok(self.get_shares().into_iter().find(|share| share.name == name).unwrap());
+    }
+
+    /** Replace the persisted file index with the given entries */
+    pub fn save_file_index(self: &DataService, entries: &[IndexEntry]) -> Result<(), String> {
+        let db_connection = self.db_connection.lock().unwrap();
+        let tx = db_connection.unchecked_transaction()
+            .map_err(|err| format!("Problems saving the file index: {}", err))?;
+        tx.execute("DELETE FROM file_index;", [])
+            .map_err(|err| format!("Problems saving the file index: {}", err))?;
+        for entry in entries {
+            tx.execute(
+                "INSERT INTO file_index (path, algorithm, size, mtime_ns, hash) VALUES (?1, ?2, ?3, ?4, ?5);",
+                params![
+                    entry.path,
+                    HashAlgorithm::of_hash(&entry.hash).as_str(),
+                    entry.size as i64,
+                    entry.mtime_ns,
+                    entry.hash,
+                ],
+            ).map_err(|err| format!("Problems saving the file index: {}", err))?;
+        }
+        tx.commit()
+            .map_err(|err| format!("Problems saving the file index: {}", err))
+    }
+}
@@ -1,25 +1,98 @@
 use std::cmp::min;
-use std::fs::File as Fl;
-use std::path::Path;
+use std::fmt;
+use std::fs::{File as Fl, Metadata};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread::{sleep, spawn};
-use std::time::{Duration, Instant};
-use std::{collections::HashMap, fs::read_dir};
+use std::time::{Duration, Instant, SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, read_link};
 
 #[cfg(target_family = "unix")]
 use std::os::unix::prelude::FileExt;
 #[cfg(target_family = "windows")]
 use std::os::windows::prelude::FileExt;
 
-use crate::ws::ws_message::{Directory, File};
+use crate::data::now_secs;
+use crate::data::shares::Share;
+use crate::metrics::{FILE_DATA_SECONDS, HASH_CACHE_LOOKUPS, TREE_SCAN_SECONDS};
+use crate::ws::ws_message::{Chunk, Directory, EntryError, File, FileVersion, Symlink};
 use base64::{engine::general_purpose, Engine as _};
+use attributes::attributes;
+use hash::HashAlgorithm;
+use ignore::{ClientScope, IgnoreRules};
+use index::{FileIndex, IndexEntry};
+use scan::{default_hash_workers, hash_files, HashJob, ScanProgress};
+use snapshot::{clear_snapshots, file_changed, remove_snapshot, take_snapshot, SNAPSHOT_TIMEOUT};
+use versions::{Retention, VersionStore};
+use walk::{
+    dir_id, DirId, SymlinkPolicy, ENTRY_INVALID_NAME, ENTRY_IO_ERROR, ENTRY_OUTSIDE_ROOT, ENTRY_SYMLINK_LOOP,
+};
 use serde::{Deserialize, Serialize};
-use sha256::try_digest;
+use tracing::{debug, warn};
+
+pub mod attributes;
+pub mod chunks;
+pub mod hash;
+pub mod ignore;
+pub mod index;
+pub mod memory;
+pub mod scan;
+pub mod snapshot;
+pub mod versions;
+pub mod walk;
+
+/// time a file hash stays cached after the last tree request
+pub const DEFAULT_HASH_CACHE_TIMEOUT: Duration = Duration::from_secs(30000);
 
 struct PathCash {
     time: Instant,
     path: String,
     size: u64,
+    /// modification time of the file when it was hashed, the transfers fail once it changes
+    mtime_ns: i64,
+    /// copy of the file served instead of it, when the snapshots are enabled
+    snapshot: Option<Snapshot>,
+    /// the file was hashed again for the next read of its last chunk
+    verified: bool,
+}
+
+struct Snapshot {
+    path: PathBuf,
+    used: Instant,
+}
+
+/** Whether the file has the hash, always when the hashes are not verified */
+fn has_hash(verify_hash: bool, hash: &str, path: &Path) -> Result<bool, FileError> {
+    if !verify_hash {
+        return Ok(true);
+    }
+    let current = HashAlgorithm::of_hash(hash).digest(path).map_err(FileError::Io)?;
+    return Ok(current == hash);
+}
+
+/** Remember where the file with the hash is. The snapshot of the same content is kept */
+fn remember_file(hash_map: &mut HashMap<String, PathCash>, file: &File, now: Instant) {
+    let path = file.path.clone().unwrap_or_default();
+    match hash_map.get_mut(&file.hash) {
+        Some(path_cash) => {
+            path_cash.time = now;
+            path_cash.path = path;
+            path_cash.size = file.size;
+            path_cash.mtime_ns = file.attributes.mtime_ns;
+            path_cash.verified = false;
+        }
+        None => {
+            hash_map.insert(file.hash.clone(), PathCash {
+                time: now,
+                path,
+                size: file.size,
+                mtime_ns: file.attributes.mtime_ns,
+                snapshot: None,
+                verified: false,
+            });
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -29,23 +102,273 @@ pub struct ReadedData {
     pub last_data: bool,
 }
 
+/** Reasons a chunk of a file can't be served */
+#[derive(Clone, Debug, PartialEq)]
+pub enum FileError {
+    /// no file has the requested hash
+    NotFound(String),
+    /// the file with the hash changed since the tree was sent
+    Changed(String),
+    /// the end of the range is before its start
+    InvalidRange { start: u64, end: u64 },
+    /// the range starts after the end of the file
+    RangeNotSatisfiable { start: u64, size: u64 },
+    Io(String),
+}
+
+impl FileError {
+    /** Stable identifier of the error sent to the clients */
+    pub fn code(self: &FileError) -> &'static str {
+        match self {
+            FileError::NotFound(_) => "file_not_found",
+            FileError::Changed(_) => "file_changed",
+            FileError::InvalidRange { .. } => "invalid_range",
+            FileError::RangeNotSatisfiable { .. } => "range_not_satisfiable",
+            FileError::Io(_) => "io_error",
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::NotFound(hash) => write!(f, "Problems to find the file with hash: {}", hash),
+            FileError::Changed(hash) => write!(f, "The file with hash {} changed during the transfer", hash),
+            FileError::InvalidRange { start, end } => {
+                write!(f, "Invalid range, the end {} is before the start {}", end, start)
+            }
+            FileError::RangeNotSatisfiable { start, size } => {
+                write!(f, "The range starts at {} but the file has {} bytes", start, size)
+            }
+            FileError::Io(err) => write!(f, "Problems reading the file: {}", err),
+        }
+    }
+}
+
+/** Range to read for a request, limited to `max_chunk_size` bytes. Without an end
+the range goes to the end of the file (or the maximum chunk size) */
+pub fn requested_range(start: u64, end: Option<u64>, max_chunk_size: u64) -> Result<(u64, u64), FileError> {
+    let limit = start.saturating_add(max_chunk_size);
+    match end {
+        Some(end) if end < start => Err(FileError::InvalidRange { start, end }),
+        Some(end) => Ok((start, min(end, limit))),
+        None => Ok((start, limit)),
+    }
+}
+
+/** Interval [start, end) of content of the given size, the end capped to the size.
+A read returning less data, like a file cut meanwhile, ends the interval earlier */
+fn read_range(
+    size: u64,
+    start: u64,
+    end: u64,
+    read: impl FnOnce(u64, u64) -> Result<Vec<u8>, FileError>,
+) -> Result<ReadedData, FileError> {
+    if start > size {
+        return Err(FileError::RangeNotSatisfiable { start, size });
+    }
+    if end < start {
+        return Err(FileError::InvalidRange { start, end });
+    }
+    let data = read(start, min(size, end))?;
+    let end = start + data.len() as u64;
+    return Ok(ReadedData {
+        data: general_purpose::STANDARD.encode(data),
+        end,
+        last_data: end >= size,
+    });
+}
+
+/** Slow work a read has to wait for, run without holding the provider */
+pub type Preparation = Box<dyn FnOnce() -> Result<(), FileError> + Send>;
+
 pub trait ProvideFile {
-    fn get_tree(&self) -> Result<Directory, String>;
-    fn get_file_data(&self, start: u64, end: u64, file_key: String) -> Result<ReadedData, String>;
+    /** Tree of the entries the client of the scope can see */
+    fn get_tree(&self, scope: &ClientScope) -> Result<Directory, String>;
+    /** Read the interval [start, end) of the file, the end is capped to the file size.
+    Files the client of the scope can't see in the tree are not found */
+    fn get_file_data(
+        &self,
+        start: u64,
+        end: u64,
+        file_key: String,
+        scope: &ClientScope,
+    ) -> Result<ReadedData, FileError>;
+
+    /** Work to do before `get_file_data` can serve the range, like taking the snapshot of
+    the file or hashing it again. The caller runs it without holding the provider, so the
+    other clients are served meanwhile, then reads. An error is the answer to the read */
+    fn prepare_file_data(
+        &self,
+        _start: u64,
+        _end: u64,
+        _file_key: &str,
+        _scope: &ClientScope,
+    ) -> Option<Preparation> {
+        None
+    }
+
+    /** Versions kept of the file with the path of the tree, the oldest first */
+    fn get_versions(&self, _path: &str, _scope: &ClientScope) -> Result<Vec<FileVersion>, FileError> {
+        Ok(Vec::new())
+    }
+
+    /** Chunks of the file with the hash, in the order of the file. Empty when the
+    provider doesn't store the files in chunks */
+    fn get_chunks(&self, _file_hash: &str, _scope: &ClientScope) -> Result<Vec<Chunk>, FileError> {
+        Ok(Vec::new())
+    }
+
+    /** Directories served in the trees, recorded in the audit log */
+    fn served_paths(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /** Progress of the scans, readable while a scan holds the provider */
+    fn scan_progress(&self) -> Option<Arc<ScanProgress>> {
+        None
+    }
+
+    /// Whether the files can be served, used by the readiness probe.
+    fn check_ready(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/** Read the range of the file, doing first the preparation of the provider without holding it */
+pub fn read_file_data<T: ProvideFile>(
+    file_service: &Mutex<T>,
+    start: u64,
+    end: u64,
+    file_key: String,
+    scope: &ClientScope,
+) -> Result<ReadedData, FileError> {
+    let _timer = FILE_DATA_SECONDS.start_timer();
+    let preparation = file_service.lock().unwrap().prepare_file_data(start, end, &file_key, scope);
+    if let Some(preparation) = preparation {
+        preparation()?;
+    }
+    return file_service.lock().unwrap().get_file_data(start, end, file_key, scope);
 }
 
 pub struct FileService {
     root_path: String,
     files_hash: Arc<Mutex<HashMap<String, PathCash>>>,
+    cache_timeout: Duration,
+    /// shares with their compiled ignore rules
+    shares: Vec<(Share, IgnoreRules)>,
+    index: Mutex<FileIndex>,
+    /// send the owner user and group of the entries in the tree
+    include_owner: bool,
+    /// rules of the whole tree, the shares included under their name
+    ignore: IgnoreRules,
+    /// files modified more recently are left out until they are no longer written
+    settle_time: Duration,
+    symlink_policy: SymlinkPolicy,
+    /// algorithm of the data path and of the shares without one
+    hash_algorithm: HashAlgorithm,
+    /// threads hashing the files that are not in the index
+    hash_workers: usize,
+    progress: Arc<ScanProgress>,
+    /// directory of the copies of the files being transferred, no copies without it
+    snapshot_dir: Option<PathBuf>,
+    /// hash again the file before sending its last chunk
+    verify_hash: bool,
+    /// previous contents of the files, they can still be copied
+    versions: Option<Mutex<VersionStore>>,
+}
+
+/** State of the walk through a scan root */
+struct Walk<'a> {
+    root: &'a ScanRoot<'a>,
+    scope: &'a ClientScope,
+    now: SystemTime,
+    /// canonical paths of the scan roots, the links can't lead out of them
+    served: &'a [PathBuf],
+    /// directories from the scan root to the one being walked, to detect loops
+    ancestors: Vec<DirId>,
+    /// files to hash once every root is walked, their hash is empty meanwhile
+    jobs: &'a mut HashSet<HashJob>,
+}
+
+fn entry_error(dir: &mut Directory, name: String, code: &str, err: String) {
+    warn!(dir = ?dir.path, name, code, error = %err, "Entry left out of the tree");
+    dir.errors.get_or_insert_with(Vec::new).push(EntryError {
+        name,
+        err,
+        code: code.to_string(),
+    });
+}
+
+/** Set the hashes of the files left pending by the walk, the files that couldn't
+be hashed are reported in the errors of their directory */
+fn fill_hashes(dir: &mut Directory, algorithm: HashAlgorithm, hashes: &HashMap<HashJob, Result<String, String>>) {
+    let mut failed = Vec::new();
+    if let Some(files) = dir.files.as_mut() {
+        files.retain_mut(|file| {
+            if !file.hash.is_empty() {
+                return true;
+            }
+            let job = HashJob {
+                path: PathBuf::from(file.path.clone().unwrap_or_default()),
+                algorithm,
+            };
+            match hashes.get(&job) {
+                Some(Ok(hash)) => {
+                    file.hash = hash.clone();
+                    true
+                }
+                Some(Err(err)) => {
+                    failed.push((file.name.clone(), err.clone()));
+                    false
+                }
+                None => false,
+            }
+        });
+    }
+    for (name, err) in failed {
+        entry_error(dir, name, ENTRY_IO_ERROR, err);
+    }
+    for sub_dir in dir.dirs.iter_mut().flatten() {
+        fill_hashes(sub_dir, algorithm, hashes);
+    }
+}
+
+/** Directory scanned for files, with where it is in the tree */
+struct ScanRoot<'a> {
+    path: &'a Path,
+    /// empty for the data path, the name of the share for the shares
+    tree_path: PathBuf,
+    /// rules of the share, relative to its directory
+    ignore: Option<&'a IgnoreRules>,
+    /// preferred algorithm for the files of the directory
+    hash_algorithm: HashAlgorithm,
 }
 
 impl FileService {
     pub fn new(root_path: String) -> FileService {
+        FileService::with_cache_timeout(root_path, DEFAULT_HASH_CACHE_TIMEOUT)
+    }
+
+    pub fn with_cache_timeout(root_path: String, cache_timeout: Duration) -> FileService {
         let files_hash = Arc::new(Mutex::new(HashMap::new()));
 
         let service = FileService {
             root_path,
             files_hash,
+            cache_timeout,
+            shares: Vec::new(),
+            index: Mutex::new(FileIndex::new()),
+            include_owner: false,
+            ignore: IgnoreRules::default(),
+            settle_time: Duration::ZERO,
+            symlink_policy: SymlinkPolicy::default(),
+            hash_algorithm: HashAlgorithm::default(),
+            hash_workers: default_hash_workers(),
+            progress: Arc::new(ScanProgress::new()),
+            snapshot_dir: None,
+            verify_hash: false,
+            versions: None,
         };
 
         service.start_cash_timeout_checker();
@@ -55,146 +378,666 @@ impl FileService {
 
     fn start_cash_timeout_checker(self: &FileService) {
         let map = self.files_hash.clone();
+        let cache_timeout = self.cache_timeout;
         spawn(move || loop {
-            let mut to_remove: Vec<String> = Vec::new();
-            for (hash, path_cash) in map.lock().unwrap().iter() {
-                if path_cash.time.elapsed() > Duration::from_secs(30000) {
-                    to_remove.push(hash.clone());
+            map.lock().unwrap().retain(|_, path_cash| {
+                let expired = path_cash.time.elapsed() > cache_timeout;
+                let stale = path_cash
+                    .snapshot
+                    .as_ref()
+                    .map(|snapshot| expired || snapshot.used.elapsed() > SNAPSHOT_TIMEOUT)
+                    .unwrap_or(false);
+                if stale {
+                    remove_snapshot(&path_cash.snapshot.take().unwrap().path);
                 }
-            }
-            for r in to_remove {
-                map.lock().unwrap().remove(&r);
-            }
+                !expired
+            });
             sleep(Duration::from_secs(5));
         });
     }
 
-    fn get_tree_rec(self: &FileService, path: &Path, dir: &mut Directory) {
-        for entry in read_dir(path).unwrap() {
-            let new_path = entry.unwrap().path();
-            if new_path.is_dir() {
-                let mut new_dir = Directory {
-                    name: new_path.file_name().unwrap().to_str().unwrap().to_string(),
-                    path: Some(new_path.to_str().unwrap().to_string()),
-                    files: Some(Vec::new()),
-                    dirs: Some(Vec::new()),
-                };
-                self.get_tree_rec(&new_path, &mut new_dir);
+    /** Serve the shares as top level folders next to the content of the root path */
+    pub fn set_shares(self: &mut FileService, shares: Vec<Share>) {
+        self.shares = Vec::new();
+        for share in shares {
+            if let Some(err) = &share.ignore_error {
+                warn!(share = %share.name, error = %err, "The share is not served");
+                continue;
+            }
+            match IgnoreRules::new(&share.ignore) {
+                Ok(rules) => self.shares.push((share, rules)),
+                // serving what should be excluded is worse than not serving the share
+                Err(err) => warn!(share = %share.name, error = %err, "The share is not served"),
+            }
+        }
+    }
+
+    pub fn set_include_owner(self: &mut FileService, include_owner: bool) {
+        self.include_owner = include_owner;
+    }
+
+    /** Gitignore style patterns applied to the whole tree */
+    pub fn set_ignore(self: &mut FileService, patterns: &[String]) -> Result<(), String> {
+        self.ignore = IgnoreRules::new(patterns)?;
+        Ok(())
+    }
+
+    pub fn set_settle_time(self: &mut FileService, settle_time: Duration) {
+        self.settle_time = settle_time;
+    }
+
+    pub fn set_symlink_policy(self: &mut FileService, symlink_policy: SymlinkPolicy) {
+        self.symlink_policy = symlink_policy;
+    }
+
+    pub fn set_hash_algorithm(self: &mut FileService, hash_algorithm: HashAlgorithm) {
+        self.hash_algorithm = hash_algorithm;
+    }
+
+    pub fn set_hash_workers(self: &mut FileService, hash_workers: usize) {
+        self.hash_workers = hash_workers.max(1);
+    }
+
+    /** Serve the transfers from copies of the files in the directory, the snapshots
+    left there by a previous run are removed */
+    pub fn set_snapshot_dir(self: &mut FileService, snapshot_dir: Option<String>) -> Result<(), String> {
+        let snapshot_dir = snapshot_dir.map(PathBuf::from);
+        if let Some(dir) = &snapshot_dir {
+            clear_snapshots(dir)?;
+        }
+        self.snapshot_dir = snapshot_dir;
+        Ok(())
+    }
+
+    pub fn set_verify_hash(self: &mut FileService, verify_hash: bool) {
+        self.verify_hash = verify_hash;
+    }
+
+    /** Keep the contents of the files in the directory, the previous versions are
+    kept as long as the retention says */
+    pub fn set_versions(self: &mut FileService, dir: Option<String>, retention: Retention) -> Result<(), String> {
+        self.versions = match dir {
+            Some(dir) => Some(Mutex::new(VersionStore::open(Path::new(&dir), retention)?)),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /** Seed the file hashes, usually with the index persisted in the database */
+    pub fn load_index(self: &FileService, entries: Vec<IndexEntry>) {
+        self.index.lock().unwrap().load(entries);
+    }
+
+    pub fn index_entries(self: &FileService) -> Vec<IndexEntry> {
+        self.index.lock().unwrap().entries()
+    }
+
+    /** Hash all the files again, returns the resulting index */
+    pub fn rebuild_index(self: &FileService) -> Result<Vec<IndexEntry>, String> {
+        self.index.lock().unwrap().clear();
+        self.get_tree(&ClientScope::default())?;
+        Ok(self.index_entries())
+    }
+
+    /** Directories scanned for files, the root path and the paths of the shares */
+    fn scan_roots(self: &FileService) -> Vec<ScanRoot<'_>> {
+        let mut roots = vec![ScanRoot {
+            path: Path::new(&self.root_path),
+            tree_path: PathBuf::new(),
+            ignore: None,
+            hash_algorithm: self.hash_algorithm,
+        }];
+        roots.extend(self.shares.iter().map(|(share, rules)| ScanRoot {
+            path: Path::new(&share.path),
+            tree_path: PathBuf::from(&share.name),
+            ignore: Some(rules),
+            hash_algorithm: share.hash_algorithm.unwrap_or(self.hash_algorithm),
+        }));
+        return roots;
+    }
+
+    /** Whether the path is out of the root or excluded by the ignore rules */
+    fn is_excluded(self: &FileService, root: &ScanRoot, scope: &ClientScope, path: &Path, is_dir: bool) -> bool {
+        let relative = match path.strip_prefix(root.path) {
+            Ok(relative) => relative,
+            Err(_) => return true,
+        };
+        if root.ignore.map(|rules| rules.is_ignored(relative, is_dir)).unwrap_or(false) {
+            return true;
+        }
+        let tree_path = root.tree_path.join(relative);
+        return self.ignore.is_ignored(&tree_path, is_dir) || scope.ignore.is_ignored(&tree_path, is_dir);
+    }
+
+    /** Whether the entry is excluded by the ignore rules or still being written */
+    fn is_hidden(
+        self: &FileService,
+        root: &ScanRoot,
+        scope: &ClientScope,
+        path: &Path,
+        metadata: &Metadata,
+        now: SystemTime,
+    ) -> bool {
+        if self.is_excluded(root, scope, path, metadata.is_dir()) {
+            return true;
+        }
+        if !metadata.is_file() || self.settle_time.is_zero() {
+            return false;
+        }
+        // a modification time in the future is not settled either
+        return match metadata.modified() {
+            Ok(modified) => now.duration_since(modified).map(|age| age < self.settle_time).unwrap_or(true),
+            Err(_) => false,
+        };
+    }
+
+    /** Whether a file found earlier can still be served to the client */
+    fn is_visible(self: &FileService, path: &Path, scope: &ClientScope) -> bool {
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return false,
+        };
+        let now = SystemTime::now();
+        return self
+            .scan_roots()
+            .iter()
+            .filter(|root| path.starts_with(root.path))
+            .any(|root| !self.is_hidden(root, scope, path, &metadata, now));
+    }
+
+    /** Where the file with the path of the tree is, the shares are under their name
+    unless a folder of the data path has the same name */
+    fn server_path(self: &FileService, tree_path: &str) -> Option<PathBuf> {
+        let tree_path = Path::new(tree_path);
+        if !tree_path.components().all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+        let first = tree_path.components().next()?;
+        let in_data_path = Path::new(&self.root_path).join(first).is_dir();
+        let share = self.shares.iter().find(|(share, _)| Path::new(&share.name) == Path::new(&first));
+        return match share {
+            Some((share, _)) if !in_data_path => {
+                Some(Path::new(&share.path).join(tree_path.strip_prefix(&share.name).ok()?))
+            }
+            _ => Some(Path::new(&self.root_path).join(tree_path)),
+        };
+    }
+
+    /** Whether the rules let the client see the file, even when it no longer exists */
+    fn is_allowed(self: &FileService, path: &Path, scope: &ClientScope) -> bool {
+        if path.symlink_metadata().is_ok() {
+            return self.is_visible(path, scope);
+        }
+        return self
+            .scan_roots()
+            .iter()
+            .filter(|root| path.starts_with(root.path))
+            .any(|root| !self.is_excluded(root, scope, path, false));
+    }
+
+    fn new_dir(self: &FileService, name: String, path: &Path) -> Directory {
+        let attributes = path
+            .metadata()
+            .map(|metadata| attributes(&metadata, self.include_owner))
+            .unwrap_or_default();
+        Directory {
+            name,
+            path: Some(path.to_string_lossy().to_string()),
+            files: Some(Vec::new()),
+            dirs: Some(Vec::new()),
+            links: Some(Vec::new()),
+            errors: Some(Vec::new()),
+            attributes,
+        }
+    }
+
+    /** Metadata of the target of a link, when the policy lets the walk follow it */
+    fn follow_link(self: &FileService, walk: &Walk, path: &Path) -> Result<Metadata, (&'static str, String)> {
+        let target = path
+            .canonicalize()
+            .map_err(|err| (ENTRY_IO_ERROR, format!("Problems following the link: {}", err)))?;
+        if !walk.served.iter().any(|served| target.starts_with(served)) {
+            return Err((ENTRY_OUTSIDE_ROOT, "The link leads out of the served directories".to_string()));
+        }
+        return target
+            .metadata()
+            .map_err(|err| (ENTRY_IO_ERROR, format!("Problems following the link: {}", err)));
+    }
+
+    /** Add the entries of the directory to the tree, the entries that can't be
+    served are reported in the errors of the directory */
+    fn get_tree_rec(self: &FileService, walk: &mut Walk, path: &Path, dir: &mut Directory) -> Result<(), String> {
+        let entries = read_dir(path).map_err(|err| format!("Problems reading the directory: {}", err))?;
+        for entry in entries {
+            let new_path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    entry_error(dir, String::new(), ENTRY_IO_ERROR, format!("Problems reading the directory: {}", err));
+                    continue;
+                }
+            };
+            let name = match new_path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => {
+                    let name = new_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                    entry_error(dir, name, ENTRY_INVALID_NAME, "The name is not valid UTF-8".to_string());
+                    continue;
+                }
+            };
+            let link_metadata = match new_path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    entry_error(dir, name, ENTRY_IO_ERROR, format!("Problems reading the entry: {}", err));
+                    continue;
+                }
+            };
+            if self.is_hidden(walk.root, walk.scope, &new_path, &link_metadata, walk.now) {
+                continue;
+            }
 
-                let mut dirs = dir.dirs.clone().unwrap();
-                dirs.push(new_dir);
-                dir.dirs = Some(dirs);
+            let metadata = if !link_metadata.is_symlink() {
+                link_metadata
             } else {
-                let new_file = File {
-                    name: new_path.file_name().unwrap().to_str().unwrap().to_string(),
-                    path: Some(new_path.to_str().unwrap().to_string()),
-                    hash: try_digest(new_path.clone()).unwrap(),
-                    size: new_path.metadata().unwrap().len(),
-                };
+                match self.symlink_policy {
+                    SymlinkPolicy::Skip => continue,
+                    SymlinkPolicy::Report => {
+                        match read_link(&new_path) {
+                            Ok(target) => dir.links.get_or_insert_with(Vec::new).push(Symlink {
+                                name,
+                                path: Some(new_path.to_string_lossy().to_string()),
+                                target: target.to_string_lossy().to_string(),
+                                attributes: attributes(&link_metadata, self.include_owner),
+                            }),
+                            Err(err) => {
+                                entry_error(dir, name, ENTRY_IO_ERROR, format!("Problems reading the link: {}", err))
+                            }
+                        }
+                        continue;
+                    }
+                    SymlinkPolicy::Follow => match self.follow_link(walk, &new_path) {
+                        Ok(metadata) if self.is_hidden(walk.root, walk.scope, &new_path, &metadata, walk.now) => {
+                            continue;
+                        }
+                        Ok(metadata) => metadata,
+                        Err((code, err)) => {
+                            entry_error(dir, name, code, err);
+                            continue;
+                        }
+                    },
+                }
+            };
+
+            if metadata.is_dir() {
+                let id = dir_id(&new_path, &metadata);
+                if walk.ancestors.contains(&id) {
+                    entry_error(dir, name, ENTRY_SYMLINK_LOOP, "The directory contains itself".to_string());
+                    continue;
+                }
+                walk.ancestors.push(id);
+                let mut new_dir = self.new_dir(name.clone(), &new_path);
+                let walked = self.get_tree_rec(walk, &new_path, &mut new_dir);
+                walk.ancestors.pop();
+                match walked {
+                    Ok(()) => dir.dirs.get_or_insert_with(Vec::new).push(new_dir),
+                    Err(err) => entry_error(dir, name, ENTRY_IO_ERROR, err),
+                }
+            } else if metadata.is_file() {
+                let algorithm = walk.scope.hash_algorithm(walk.root.hash_algorithm);
+                let hash = self.index.lock().unwrap().cached(&new_path, &metadata, algorithm);
+                // a file reached twice through the links is hashed once
+                let pending = hash.is_none() && walk.jobs.insert(HashJob {
+                    path: new_path.clone(),
+                    algorithm,
+                });
+                self.progress.found(!pending);
+                dir.files.get_or_insert_with(Vec::new).push(File {
+                    name,
+                    path: Some(new_path.to_string_lossy().to_string()),
+                    hash: hash.unwrap_or_default(),
+                    size: metadata.len(),
+                    attributes: attributes(&metadata, self.include_owner),
+                });
+            } else {
+                // fifos, sockets and devices can block the reads forever
+                debug!(path = %new_path.display(), "Special file left out of the tree");
+            }
+        }
+        Ok(())
+    }
+
+    /** Walk the data path and the shares, then hash the files missing from the index */
+    fn scan_tree(self: &FileService, scope: &ClientScope) -> Result<Directory, String> {
+        let _timer = TREE_SCAN_SECONDS.start_timer();
+        let _scan = self.progress.start();
+        let roots = self.scan_roots();
+        let served: Vec<PathBuf> = roots.iter().filter_map(|root| root.path.canonicalize().ok()).collect();
+        let mut jobs = HashSet::new();
+        let mut walk_root = |root| -> Result<Directory, String> {
+            let mut walk = Walk {
+                root,
+                scope,
+                now: SystemTime::now(),
+                served: &served,
+                ancestors: Vec::new(),
+                jobs: &mut jobs,
+            };
+            let name = root.tree_path.to_string_lossy().to_string();
+            let mut dir = self.new_dir(if name.is_empty() { "root".to_string() } else { name }, root.path);
+            if let Ok(metadata) = root.path.metadata() {
+                walk.ancestors.push(dir_id(root.path, &metadata));
+            }
+            self.get_tree_rec(&mut walk, root.path, &mut dir)?;
+            Ok(dir)
+        };
+        // without the data path there is no tree, a share that can't be read is reported in it
+        let mut root_dir = walk_root(&roots[0])?;
+
+        let mut share_dirs: Vec<(&ScanRoot, Directory)> = Vec::new();
+        for root in &roots[1..] {
+            let name = root.tree_path.to_string_lossy().to_string();
+            let taken = root_dir.dirs.as_ref().unwrap().iter().any(|dir| dir.name == name)
+                || share_dirs.iter().any(|(_, dir)| dir.name == name);
+            if taken {
+                warn!(share = %name, "The share is hidden by a folder with the same name");
+                continue;
+            }
+            if self.ignore.is_ignored(&root.tree_path, true) || scope.ignore.is_ignored(&root.tree_path, true) {
+                continue;
+            }
+            match walk_root(root) {
+                Ok(share_dir) => share_dirs.push((root, share_dir)),
+                Err(err) => entry_error(&mut root_dir, name, ENTRY_IO_ERROR, err),
+            }
+        }
 
-                let mut files = dir.files.clone().unwrap();
-                files.push(new_file);
-                dir.files = Some(files);
+        // the files missing from the index are hashed together, in parallel
+        let jobs: Vec<HashJob> = jobs.into_iter().collect();
+        let hashes = hash_files(&jobs, self.hash_workers, &self.index, &self.progress);
+        fill_hashes(&mut root_dir, scope.hash_algorithm(roots[0].hash_algorithm), &hashes);
+        for (root, mut share_dir) in share_dirs {
+            fill_hashes(&mut share_dir, scope.hash_algorithm(root.hash_algorithm), &hashes);
+            root_dir.dirs.get_or_insert_with(Vec::new).push(share_dir);
+        }
+
+        Ok(root_dir)
+    }
+
+    /** Remember where the files of the tree are, the copies find them without a scan */
+    fn remember_files(self: &FileService, tree: &Directory) {
+        let now = Instant::now();
+        let mut hash_map = self.files_hash.lock().unwrap();
+        let mut dirs = vec![tree];
+        while let Some(dir) = dirs.pop() {
+            for file in dir.files.iter().flatten() {
+                remember_file(&mut hash_map, file, now);
             }
+            dirs.extend(dir.dirs.iter().flatten());
         }
     }
 
-    fn get_file_list(self: &FileService, path: &Path) -> Vec<File> {
-        let mut res = Vec::new();
-        let mut q = Vec::new();
-        q.push(path.to_str().unwrap().to_string());
-        while !q.is_empty() {
-            let dir_path: String = q.pop().unwrap();
-            for entry in read_dir(dir_path).unwrap() {
-                let new_path = entry.unwrap().path();
-                if new_path.is_dir() {
-                    let path_str = new_path.to_str().unwrap().to_string();
-                    q.push(path_str);
-                } else {
-                    let new_file = File {
-                        name: new_path.file_name().unwrap().to_str().unwrap().to_string(),
-                        path: Some(new_path.to_str().unwrap().to_string()),
-                        hash: try_digest(new_path.clone()).unwrap(),
-                        size: new_path.metadata().unwrap().len(),
-                    };
-                    res.push(new_file);
+    /** Keep the content of the files of the tree whose version is not stored yet */
+    fn record_versions(self: &FileService, tree: &Directory) {
+        let mut store = match &self.versions {
+            Some(store) => store.lock().unwrap(),
+            None => return,
+        };
+        let now = now_secs();
+        let mut changed = false;
+        let mut dirs = vec![tree];
+        while let Some(dir) = dirs.pop() {
+            for file in dir.files.iter().flatten() {
+                let version = FileVersion {
+                    hash: file.hash.clone(),
+                    size: file.size,
+                    mtime_ns: file.attributes.mtime_ns,
+                    stored_at: now,
+                };
+                match store.record(Path::new(file.path.as_deref().unwrap_or_default()), version) {
+                    Ok(recorded) => changed |= recorded,
+                    Err(err) => warn!(error = %err, "Problems keeping a version of the file"),
                 }
             }
+            dirs.extend(dir.dirs.iter().flatten());
+        }
+        changed |= store.prune(now);
+        if changed {
+            if let Err(err) = store.save() {
+                warn!(error = %err, "Problems saving the versions");
+            }
+        }
+    }
+
+    /** Whether the client can see a file with the hash in the trees sent so far, or a
+    stored version of it. Nothing is scanned nor read, a file that changed since counts */
+    pub fn in_tree(self: &FileService, hash: &str, scope: &ClientScope) -> bool {
+        let path = self.files_hash.lock().unwrap().get(hash).map(|path_cash| path_cash.path.clone());
+        if path.map(|path| self.is_allowed(Path::new(&path), scope)).unwrap_or(false) {
+            return true;
         }
-        return res;
+        let paths = match &self.versions {
+            Some(store) => store.lock().unwrap().find(hash).map(|(_, _, paths)| paths).unwrap_or_default(),
+            None => return false,
+        };
+        return paths.iter().any(|path| self.is_allowed(Path::new(path), scope));
+    }
+
+    /** Read a stored version of a file, `None` when the client can't see any file
+    that had it */
+    fn read_version(
+        self: &FileService,
+        hash: &str,
+        start: u64,
+        end: u64,
+        scope: &ClientScope,
+    ) -> Option<Result<ReadedData, FileError>> {
+        let (object, size, paths) = self.versions.as_ref()?.lock().unwrap().find(hash)?;
+        if !paths.iter().any(|path| self.is_allowed(Path::new(path), scope)) {
+            return None;
+        }
+        return Some(self.read_data(&object, size, start, end));
+    }
+
+    /** Whether the file still has the hash, always when the hashes are not verified */
+    fn verified(self: &FileService, hash: &str, path: &Path) -> Result<bool, FileError> {
+        return has_hash(self.verify_hash, hash, path);
+    }
+
+    /** Every file of the tree the client of the scope can see */
+    fn get_file_list(self: &FileService, scope: &ClientScope) -> Result<Vec<File>, FileError> {
+        let mut res = Vec::new();
+        let mut q = vec![self.scan_tree(scope).map_err(FileError::Io)?];
+        while let Some(dir) = q.pop() {
+            res.extend(dir.files.unwrap_or_default());
+            q.extend(dir.dirs.unwrap_or_default());
+        }
+        return Ok(res);
     }
 
     fn read_data(
         self: &FileService,
-        path: String,
+        path: &Path,
         file_len: u64,
         start: u64,
         end: u64,
-    ) -> ReadedData {
-        let file = Fl::open(path).unwrap();
+    ) -> Result<ReadedData, FileError> {
+        return read_range(file_len, start, end, |start, end| {
+            let file = Fl::open(path).map_err(|err| FileError::Io(err.to_string()))?;
+            let mut vec: Vec<u8> = vec![0; usize::try_from(end - start).unwrap()];
+            let readed = file.read_at(&mut vec, start).map_err(|err| FileError::Io(err.to_string()))?;
+            vec.truncate(readed);
+            Ok(vec)
+        });
+    }
+}
 
-        let end = min(file_len, end);
-        let mut vec: Vec<u8> = vec![0; usize::try_from(end - start).unwrap()];
+impl ProvideFile for FileService {
+    fn get_tree(self: &FileService, scope: &ClientScope) -> Result<Directory, String> {
+        let tree = self.scan_tree(scope)?;
+        self.remember_files(&tree);
+        self.record_versions(&tree);
+        Ok(tree)
+    }
 
-        file.read_at(&mut vec, start).unwrap();
+    /** The data path, then the shares as `name=path` */
+    fn served_paths(&self) -> Vec<String> {
+        let mut paths = vec![self.root_path.clone()];
+        paths.extend(self.shares.iter().map(|(share, _)| format!("{}={}", share.name, share.path)));
+        return paths;
+    }
+
+    fn scan_progress(&self) -> Option<Arc<ScanProgress>> {
+        Some(self.progress.clone())
+    }
 
-        // todo: last_data and len
-        return ReadedData {
-            data: general_purpose::STANDARD.encode(vec),
-            end,
-            last_data: end >= file_len,
+    fn get_versions(&self, path: &str, scope: &ClientScope) -> Result<Vec<FileVersion>, FileError> {
+        let (store, path) = match (&self.versions, self.server_path(path)) {
+            (Some(store), Some(path)) => (store, path),
+            _ => return Ok(Vec::new()),
         };
+        if !self.is_allowed(&path, scope) {
+            return Ok(Vec::new());
+        }
+        return Ok(store.lock().unwrap().versions(&path.to_string_lossy()));
     }
-}
 
-impl ProvideFile for FileService {
-    fn get_tree(self: &FileService) -> Result<Directory, String> {
-        let mut root_dir = Directory {
-            name: "root".to_string(),
-            path: Some(self.root_path.clone()),
-            files: Some(Vec::new()),
-            dirs: Some(Vec::new()),
+    /** The snapshot of the file, or the hash again of a file whose last chunk is read */
+    fn prepare_file_data(
+        &self,
+        _start: u64,
+        end: u64,
+        file_key: &str,
+        scope: &ClientScope,
+    ) -> Option<Preparation> {
+        let (path, size, mtime_ns) = {
+            let hash_map = self.files_hash.lock().unwrap();
+            let path_cash = hash_map.get(file_key)?;
+            if path_cash.snapshot.is_some() || !self.is_visible(Path::new(&path_cash.path), scope) {
+                return None;
+            }
+            (PathBuf::from(&path_cash.path), path_cash.size, path_cash.mtime_ns)
         };
-        self.get_tree_rec(&Path::new(&self.root_path), &mut root_dir);
+        if self.snapshot_dir.is_none() && !(self.verify_hash && end >= size) {
+            return None;
+        }
+        let snapshot_dir = self.snapshot_dir.clone();
+        let verify_hash = self.verify_hash;
+        let files_hash = self.files_hash.clone();
+        let file_key = file_key.to_string();
+        return Some(Box::new(move || {
+            // the read tells what changed
+            if file_changed(&path, size, mtime_ns) {
+                return Ok(());
+            }
+            let snapshot = match &snapshot_dir {
+                Some(dir) => Some(take_snapshot(dir, &file_key, &path).map_err(FileError::Io)?),
+                None => None,
+            };
+            let hashed = snapshot.as_deref().unwrap_or(&path);
+            // the file can change while it is copied
+            if file_changed(&path, size, mtime_ns) || !has_hash(verify_hash, &file_key, hashed)? {
+                if let Some(snapshot) = &snapshot {
+                    remove_snapshot(snapshot);
+                }
+                return Err(FileError::Changed(file_key));
+            }
 
-        Ok(root_dir)
+            let mut hash_map = files_hash.lock().unwrap();
+            let path_cash = hash_map.get_mut(&file_key).filter(|path_cash| {
+                Path::new(&path_cash.path) == path && path_cash.size == size && path_cash.mtime_ns == mtime_ns
+            });
+            match (path_cash, snapshot) {
+                (Some(path_cash), Some(snapshot)) if path_cash.snapshot.is_none() => {
+                    path_cash.snapshot = Some(Snapshot { path: snapshot, used: Instant::now() });
+                }
+                (Some(path_cash), None) => path_cash.verified = verify_hash,
+                // another read took the snapshot first, or the file is no longer the same
+                (_, Some(snapshot)) => remove_snapshot(&snapshot),
+                (None, None) => {}
+            }
+            Ok(())
+        }));
     }
 
-    fn get_file_data(&self, start: u64, end: u64, file_key: String) -> Result<ReadedData, String> {
+    fn check_ready(&self) -> Result<(), String> {
+        read_dir(&self.root_path)
+            .map(|_| ())
+            .map_err(|err| format!("Problems reading the data root {}: {}", self.root_path, err))
+    }
+
+    fn get_file_data(
+        &self,
+        start: u64,
+        end: u64,
+        file_key: String,
+        scope: &ClientScope,
+    ) -> Result<ReadedData, FileError> {
         // search file given the key
-        let mut file: Option<String> = None;
-        let mut file_len: Option<u64> = None;
-        let mut hash_map = self.files_hash.lock().unwrap();
-        if hash_map.contains_key(&file_key) {
-            file = Some(hash_map.get(&file_key).unwrap().path.clone());
-            file_len = Some(hash_map.get(&file_key).unwrap().size);
-            hash_map.remove(&file_key);
+        let now = Instant::now();
+        let cached = match self.files_hash.lock().unwrap().get_mut(&file_key) {
+            Some(path_cash) if self.is_visible(Path::new(&path_cash.path), scope) => {
+                // keep the entry while the file is being copied
+                path_cash.time = now;
+                true
+            }
+            _ => false,
+        };
+        if cached {
+            HASH_CACHE_LOOKUPS.with_label_values(&["hit"]).inc();
+        } else if let Some(data) = self.read_version(&file_key, start, end, scope) {
+            return data;
         } else {
-            let files = self.get_file_list(Path::new(&self.root_path));
-            for f in files {
-                if f.hash == file_key {
-                    file = Some(f.path.clone().unwrap());
-                    file_len = Some(f.size);
-                    hash_map.insert(
-                        f.hash,
-                        PathCash {
-                            time: Instant::now(),
-                            path: f.path.clone().unwrap(),
-                            size: f.size,
-                        },
-                    );
-                }
+            HASH_CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+            // the files can be found by a hash of any algorithm, whatever the tree has.
+            // The other reads go on during the scan
+            let lookup_scope = ClientScope {
+                hash_algorithms: Some(vec![HashAlgorithm::of_hash(&file_key)]),
+                ..scope.clone()
+            };
+            let files = self.get_file_list(&lookup_scope)?;
+            let mut hash_map = self.files_hash.lock().unwrap();
+            for f in files.iter().filter(|f| f.hash == file_key) {
+                remember_file(&mut hash_map, f, now);
             }
         }
-
-        if file.is_none() {
-            return Err("file don't exist".to_string());
-        }
+        let mut hash_map = self.files_hash.lock().unwrap();
+        let path_cash = match hash_map.get_mut(&file_key) {
+            Some(path_cash) if self.is_visible(Path::new(&path_cash.path), scope) => path_cash,
+            _ => return Err(FileError::NotFound(file_key)),
+        };
 
         // read data in, Read the interval [start, end)
-        let data = self.read_data(file.unwrap(), file_len.unwrap(), start, end);
-
-        // return the data
+        if let Some(snapshot) = path_cash.snapshot.as_mut() {
+            snapshot.used = now;
+            return self.read_data(&snapshot.path, path_cash.size, start, end);
+        }
+        let path = PathBuf::from(&path_cash.path);
+        // the content of the tree can still be in the version store
+        let changed = |file_key: String| {
+            self.read_version(&file_key, start, end, scope).unwrap_or(Err(FileError::Changed(file_key)))
+        };
+        if file_changed(&path, path_cash.size, path_cash.mtime_ns) {
+            return changed(file_key);
+        }
+        if let Some(dir) = &self.snapshot_dir {
+            let snapshot = take_snapshot(dir, &file_key, &path).map_err(FileError::Io)?;
+            // the file can change while it is copied
+            if file_changed(&path, path_cash.size, path_cash.mtime_ns) || !self.verified(&file_key, &snapshot)? {
+                remove_snapshot(&snapshot);
+                return Err(FileError::Changed(file_key));
+            }
+            let data = self.read_data(&snapshot, path_cash.size, start, end);
+            path_cash.snapshot = Some(Snapshot { path: snapshot, used: now });
+            return data;
+        }
+        let data = self.read_data(&path, path_cash.size, start, end)?;
+        // the bytes read can belong to a newer version of the file
+        if file_changed(&path, path_cash.size, path_cash.mtime_ns) {
+            return changed(file_key);
+        }
+        // a prepared read already hashed the file again
+        if data.last_data && !std::mem::take(&mut path_cash.verified) && !self.verified(&file_key, &path)? {
+            return Err(FileError::Changed(file_key));
+        }
         Ok(data)
     }
 }
@@ -211,8 +1054,6 @@ mod tests {
 
         let bb = general_purpose::STANDARD.decode(s).unwrap();
 
-        for i in [0..b.len()] {
-            assert_eq!(bb[i.clone()], b[i]);
-        }
+        assert_eq!(bb[0..b.len()], b[0..b.len()]);
     }
 }
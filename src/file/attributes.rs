@@ -0,0 +1,27 @@
+use std::fs::Metadata;
+
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::MetadataExt;
+
+use crate::file::index::mtime_ns;
+use crate::ws::ws_message::Attributes;
+
+/** Attributes of a tree entry from its metadata, without following symlinks
+when the metadata comes from `symlink_metadata` */
+#[cfg(target_family = "unix")]
+pub fn attributes(metadata: &Metadata, include_owner: bool) -> Attributes {
+    Attributes {
+        mtime_ns: mtime_ns(metadata),
+        mode: Some(metadata.mode() & 0o7777),
+        uid: if include_owner { Some(metadata.uid()) } else { None },
+        gid: if include_owner { Some(metadata.gid()) } else { None },
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn attributes(metadata: &Metadata, _include_owner: bool) -> Attributes {
+    Attributes {
+        mtime_ns: mtime_ns(metadata),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,305 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_dir, read_to_string, rename, write, File as Fl};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_family = "unix")]
+use std::os::unix::prelude::FileExt;
+#[cfg(target_family = "windows")]
+use std::os::windows::prelude::FileExt;
+
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::file::ignore::ClientScope;
+use crate::file::scan::ScanProgress;
+use crate::file::snapshot::file_changed;
+use crate::file::{read_range, FileError, FileService, Preparation, ProvideFile, ReadedData};
+use crate::ws::ws_message::{Chunk, Directory, File, FileVersion};
+
+/// tag of the chunk hashes, they can be copied like the files
+pub const CHUNK_PREFIX: &str = "chunk:";
+/// bounds of the content defined chunks, an edit only changes the chunks around it
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+static PARTIALS_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/** File stored as the list of its chunks */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub hash: String,
+    pub size: u64,
+    pub chunks: Vec<Chunk>,
+}
+
+/** Chunks stored once by hash, and the manifests of the files made of them */
+pub struct ChunkStore {
+    dir: PathBuf,
+    manifests: HashMap<String, Manifest>,
+    /// hashes of the files each chunk is part of
+    chunk_files: HashMap<String, Vec<String>>,
+}
+
+/** Whether the key is a chunk hash, `chunk:` and the 64 lowercase hex digits of a BLAKE3 hash.
+The keys come from the clients, anything else must not reach the filesystem */
+pub fn is_chunk_key(key: &str) -> bool {
+    match key.strip_prefix(CHUNK_PREFIX) {
+        Some(hex) => hex.len() == 64 && hex.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f')),
+        None => false,
+    }
+}
+
+/** Path of the chunk in the store directory, `None` when the key is not a chunk hash */
+fn chunk_path(dir: &Path, chunk_hash: &str) -> Option<PathBuf> {
+    if !is_chunk_key(chunk_hash) {
+        return None;
+    }
+    return Some(dir.join("chunks").join(&chunk_hash[CHUNK_PREFIX.len()..]));
+}
+
+/** Write the file through a partial copy of its own, so the readers never see half of it
+and two connections storing the same content at once don't mix their writes */
+fn write_whole(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let number = PARTIALS_WRITTEN.fetch_add(1, Ordering::SeqCst);
+    let partial = path.with_extension(format!("partial-{}", number));
+    write(&partial, content).and_then(|_| rename(&partial, path))
+}
+
+/** Split the file into chunks, write the ones missing from the store directory and the
+manifest of the file. The store itself is left alone, so this runs without holding it.
+`None` when the file doesn't have the size and the modification time of its hash */
+fn split_file(dir: &Path, path: &Path, file: &File) -> Result<Option<Manifest>, String> {
+    let ingest_error = |err: String| format!("Problems storing {} in chunks: {}", path.display(), err);
+    let source = Fl::open(path).map_err(|err| ingest_error(err.to_string()))?;
+    let mut chunks = Vec::new();
+    for chunk in StreamCDC::new(source, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk = chunk.map_err(|err| ingest_error(err.to_string()))?;
+        let hash = format!("{}{}", CHUNK_PREFIX, blake3::hash(&chunk.data).to_hex());
+        let chunk_path = chunk_path(dir, &hash).unwrap();
+        if !chunk_path.exists() {
+            write_whole(&chunk_path, &chunk.data).map_err(|err| ingest_error(err.to_string()))?;
+        }
+        chunks.push(Chunk {
+            hash,
+            offset: chunk.offset,
+            size: chunk.length as u64,
+        });
+    }
+    if file_changed(path, file.size, file.attributes.mtime_ns) {
+        return Ok(None);
+    }
+
+    let manifest = Manifest {
+        hash: file.hash.clone(),
+        size: file.size,
+        chunks,
+    };
+    let manifest_path = dir.join("manifests").join(format!("{}.json", file.hash.replace(':', "-")));
+    write_whole(&manifest_path, serde_json::to_string(&manifest).unwrap().as_bytes())
+        .map_err(|err| ingest_error(err.to_string()))?;
+    return Ok(Some(manifest));
+}
+
+/** Read the interval [start, end) of the chunk */
+fn read_chunk(dir: &Path, chunk_hash: &str, start: u64, end: u64) -> Result<Vec<u8>, FileError> {
+    let path = chunk_path(dir, chunk_hash).ok_or(FileError::NotFound(chunk_hash.to_string()))?;
+    let chunk = Fl::open(path).map_err(|err| FileError::Io(err.to_string()))?;
+    let mut data = vec![0; usize::try_from(end - start).unwrap()];
+    chunk.read_exact_at(&mut data, start).map_err(|err| FileError::Io(err.to_string()))?;
+    return Ok(data);
+}
+
+/** Read the interval [start, end) of a file from the chunks it is made of */
+fn read_file(dir: &Path, manifest: &Manifest, start: u64, end: u64) -> Result<Vec<u8>, FileError> {
+    let mut data = Vec::with_capacity(usize::try_from(end - start).unwrap());
+    for chunk in &manifest.chunks {
+        let chunk_end = chunk.offset + chunk.size;
+        if chunk_end <= start || chunk.offset >= end {
+            continue;
+        }
+        let from = start.max(chunk.offset) - chunk.offset;
+        let to = min(end, chunk_end) - chunk.offset;
+        data.extend(read_chunk(dir, &chunk.hash, from, to)?);
+    }
+    return Ok(data);
+}
+
+impl ChunkStore {
+    pub fn open(dir: &Path) -> Result<ChunkStore, String> {
+        let store_error = |err: std::io::Error| format!("Problems opening the chunk store {}: {}", dir.display(), err);
+        create_dir_all(dir.join("chunks")).map_err(store_error)?;
+        create_dir_all(dir.join("manifests")).map_err(store_error)?;
+        let mut store = ChunkStore {
+            dir: dir.to_path_buf(),
+            manifests: HashMap::new(),
+            chunk_files: HashMap::new(),
+        };
+        for entry in read_dir(dir.join("manifests")).map_err(store_error)?.flatten() {
+            let parsed = read_to_string(entry.path())
+                .map_err(|err| err.to_string())
+                .and_then(|content| serde_json::from_str::<Manifest>(&content).map_err(|err| err.to_string()));
+            match parsed {
+                Ok(manifest) => store.add_manifest(manifest),
+                // an unfinished manifest, the file is split again when it is needed
+                Err(err) => warn!(manifest = %entry.path().display(), error = %err, "Invalid manifest"),
+            }
+        }
+        return Ok(store);
+    }
+
+    fn add_manifest(self: &mut ChunkStore, manifest: Manifest) {
+        for chunk in &manifest.chunks {
+            let files = self.chunk_files.entry(chunk.hash.clone()).or_default();
+            if !files.contains(&manifest.hash) {
+                files.push(manifest.hash.clone());
+            }
+        }
+        self.manifests.insert(manifest.hash.clone(), manifest);
+    }
+
+    pub fn manifest(self: &ChunkStore, file_hash: &str) -> Option<&Manifest> {
+        self.manifests.get(file_hash)
+    }
+
+    /** Hashes of the files the chunk is part of */
+    pub fn chunk_files(self: &ChunkStore, chunk_hash: &str) -> Vec<String> {
+        self.chunk_files.get(chunk_hash).cloned().unwrap_or_default()
+    }
+}
+
+/** Provider serving the files of a `FileService` from a chunk store. The tree and the
+rules are the ones of the file service, the content comes from the chunks, so the same
+content is stored once across the shares and the versions of the files, and the clients
+can copy only the chunks they lack */
+pub struct ChunkFileService {
+    files: FileService,
+    store: Mutex<ChunkStore>,
+}
+
+impl ChunkFileService {
+    pub fn new(files: FileService, dir: &Path) -> Result<ChunkFileService, String> {
+        return Ok(ChunkFileService {
+            files,
+            store: Mutex::new(ChunkStore::open(dir)?),
+        });
+    }
+
+    pub fn files(self: &ChunkFileService) -> &FileService {
+        &self.files
+    }
+
+    /** Store in chunks the files of the tree that are not stored yet. The files are
+    split without holding the store, the other connections keep reading from it */
+    fn ingest_tree(self: &ChunkFileService, tree: &Directory) {
+        let store_dir = self.store.lock().unwrap().dir.clone();
+        let mut dirs = vec![tree];
+        while let Some(dir) = dirs.pop() {
+            for file in dir.files.iter().flatten() {
+                if self.store.lock().unwrap().manifest(&file.hash).is_some() {
+                    continue;
+                }
+                let path = Path::new(file.path.as_deref().unwrap_or_default());
+                match split_file(&store_dir, path, file) {
+                    Ok(Some(manifest)) => self.store.lock().unwrap().add_manifest(manifest),
+                    Ok(None) => {}
+                    // the files that can't be stored are still served by the file service
+                    Err(err) => warn!(error = %err, "Problems storing a file in chunks"),
+                }
+            }
+            dirs.extend(dir.dirs.iter().flatten());
+        }
+    }
+
+    /** Whether the client can see a file with the hash in the trees it was sent. The
+    stored content is served even when the file changed since */
+    fn check_visible(self: &ChunkFileService, file_hash: &str, scope: &ClientScope) -> Result<(), FileError> {
+        if self.files.in_tree(file_hash, scope) {
+            return Ok(());
+        }
+        return Err(FileError::NotFound(file_hash.to_string()));
+    }
+}
+
+impl ProvideFile for ChunkFileService {
+    fn get_tree(&self, scope: &ClientScope) -> Result<Directory, String> {
+        let tree = self.files.get_tree(scope)?;
+        self.ingest_tree(&tree);
+        Ok(tree)
+    }
+
+    /** A chunk is served to the clients that can see one of the files it is part of */
+    fn get_file_data(
+        &self,
+        start: u64,
+        end: u64,
+        file_key: String,
+        scope: &ClientScope,
+    ) -> Result<ReadedData, FileError> {
+        // the stored chunks never change, they are checked and read without holding the store
+        if file_key.starts_with(CHUNK_PREFIX) {
+            let (files, store_dir) = {
+                let store = self.store.lock().unwrap();
+                (store.chunk_files(&file_key), store.dir.clone())
+            };
+            if !files.iter().any(|file_hash| self.check_visible(file_hash, scope).is_ok()) {
+                return Err(FileError::NotFound(file_key));
+            }
+            let size = chunk_path(&store_dir, &file_key)
+                .and_then(|path| path.metadata().ok())
+                .map(|metadata| metadata.len())
+                .ok_or(FileError::NotFound(file_key.clone()))?;
+            return read_range(size, start, end, |start, end| read_chunk(&store_dir, &file_key, start, end));
+        }
+        let stored = {
+            let store = self.store.lock().unwrap();
+            store.manifest(&file_key).map(|manifest| (manifest.clone(), store.dir.clone()))
+        };
+        let (manifest, store_dir) = match stored {
+            Some(stored) => stored,
+            None => return self.files.get_file_data(start, end, file_key, scope),
+        };
+        self.check_visible(&file_key, scope)?;
+        return read_range(manifest.size, start, end, |start, end| read_file(&store_dir, &manifest, start, end));
+    }
+
+    /** The chunks need no preparation, the other files are read from the file service */
+    fn prepare_file_data(
+        &self,
+        start: u64,
+        end: u64,
+        file_key: &str,
+        scope: &ClientScope,
+    ) -> Option<Preparation> {
+        if file_key.starts_with(CHUNK_PREFIX) || self.store.lock().unwrap().manifest(file_key).is_some() {
+            return None;
+        }
+        return self.files.prepare_file_data(start, end, file_key, scope);
+    }
+
+    fn get_chunks(&self, file_hash: &str, scope: &ClientScope) -> Result<Vec<Chunk>, FileError> {
+        self.check_visible(file_hash, scope)?;
+        let store = self.store.lock().unwrap();
+        return Ok(store.manifest(file_hash).map(|manifest| manifest.chunks.clone()).unwrap_or_default());
+    }
+
+    fn get_versions(&self, path: &str, scope: &ClientScope) -> Result<Vec<FileVersion>, FileError> {
+        self.files.get_versions(path, scope)
+    }
+
+    fn served_paths(&self) -> Vec<String> {
+        self.files.served_paths()
+    }
+
+    fn scan_progress(&self) -> Option<Arc<ScanProgress>> {
+        self.files.scan_progress()
+    }
+
+    fn check_ready(&self) -> Result<(), String> {
+        self.files.check_ready()
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io::copy;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha256::{digest, try_digest};
+
+/** Algorithm identifying the files. The sha256 hashes are sent as is for the clients
+that predate the other algorithms, the others are tagged like `blake3:<hex>` */
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn parse(value: &str) -> Result<HashAlgorithm, String> {
+        match value {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("Invalid hash algorithm '{}', expected 'sha256' or 'blake3'", value)),
+        }
+    }
+
+    pub fn as_str(self: &HashAlgorithm) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /** Algorithm of a hash from its tag, the hashes without tag are sha256 */
+    pub fn of_hash(hash: &str) -> HashAlgorithm {
+        match hash.split_once(':') {
+            Some(("blake3", _)) => HashAlgorithm::Blake3,
+            _ => HashAlgorithm::Sha256,
+        }
+    }
+
+    /** Tagged hash of the content of the file */
+    pub fn digest(self: &HashAlgorithm, path: &Path) -> Result<String, String> {
+        let hash_error = |err: std::io::Error| format!("Problems hashing {}: {}", path.display(), err);
+        match self {
+            HashAlgorithm::Sha256 => try_digest(path).map_err(hash_error),
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                copy(&mut File::open(path).map_err(hash_error)?, &mut hasher).map_err(hash_error)?;
+                Ok(format!("blake3:{}", hasher.finalize().to_hex()))
+            }
+        }
+    }
+
+    /** Tagged hash of the content */
+    pub fn digest_data(self: &HashAlgorithm, data: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Sha256 => digest(data),
+            HashAlgorithm::Blake3 => format!("blake3:{}", blake3::hash(data).to_hex()),
+        }
+    }
+}
+
+/** Algorithms used with a client, out of the ones it offers when authenticating and in
+its order of preference. The clients that don't offer any only know sha256 */
+pub fn negotiate(offered: Option<&[String]>) -> Vec<HashAlgorithm> {
+    let mut algorithms: Vec<HashAlgorithm> = Vec::new();
+    for name in offered.unwrap_or_default() {
+        if let Ok(algorithm) = HashAlgorithm::parse(name) {
+            if !algorithms.contains(&algorithm) {
+                algorithms.push(algorithm);
+            }
+        }
+    }
+    if algorithms.is_empty() {
+        algorithms.push(HashAlgorithm::Sha256);
+    }
+    return algorithms;
+}
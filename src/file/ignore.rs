@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::file::hash::HashAlgorithm;
+
+/** Gitignore style patterns, matched against paths relative to where they apply.
+A pattern starting with `!` includes again what an earlier pattern excluded */
+#[derive(Clone, Debug)]
+pub struct IgnoreRules {
+    patterns: Vec<String>,
+    matcher: Gitignore,
+}
+
+impl Default for IgnoreRules {
+    fn default() -> Self {
+        IgnoreRules {
+            patterns: Vec::new(),
+            matcher: Gitignore::empty(),
+        }
+    }
+}
+
+impl IgnoreRules {
+    pub fn new(patterns: &[String]) -> Result<IgnoreRules, String> {
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in patterns {
+            builder
+                .add_line(None, pattern)
+                .map_err(|err| format!("Invalid ignore pattern '{}': {}", pattern, err))?;
+        }
+        let matcher = builder
+            .build()
+            .map_err(|err| format!("Invalid ignore patterns: {}", err))?;
+        return Ok(IgnoreRules {
+            patterns: patterns.to_vec(),
+            matcher,
+        });
+    }
+
+    pub fn patterns(self: &IgnoreRules) -> &[String] {
+        &self.patterns
+    }
+
+    /** Whether the relative path, or one of the directories it is in, is excluded */
+    pub fn is_ignored(self: &IgnoreRules, path: &Path, is_dir: bool) -> bool {
+        self.matcher.matched_path_or_any_parents(path, is_dir).is_ignore()
+    }
+}
+
+/** What a client is allowed to see of the tree, on top of the rules of the server.
+The rules of a client can only exclude more entries, never include again the ones
+the server excludes */
+#[derive(Clone, Debug, Default)]
+pub struct ClientScope {
+    pub client: Option<String>,
+    /// matched against the paths of the tree, shares included under their name
+    pub ignore: IgnoreRules,
+    /// hash algorithms the client knows, in its order of preference, any when missing
+    pub hash_algorithms: Option<Vec<HashAlgorithm>>,
+}
+
+impl ClientScope {
+    pub fn new(client: Option<String>, patterns: &[String]) -> Result<ClientScope, String> {
+        Ok(ClientScope {
+            client,
+            ignore: IgnoreRules::new(patterns)?,
+            hash_algorithms: None,
+        })
+    }
+
+    /** Algorithm to hash the files of a directory that prefers the given one */
+    pub fn hash_algorithm(self: &ClientScope, preferred: HashAlgorithm) -> HashAlgorithm {
+        match &self.hash_algorithms {
+            Some(known) if !known.contains(&preferred) => known.first().copied().unwrap_or_default(),
+            _ => preferred,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::file::hash::HashAlgorithm;
+
+/** Hash of a file, valid while the size and the modification time of the file don't change.
+A file can have a hash for each algorithm */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub path: String,
+    pub size: u64,
+    /// modification time in nanoseconds since the unix epoch
+    pub mtime_ns: i64,
+    pub hash: String,
+}
+
+/** Memo of the file hashes, avoids hashing again the files that didn't change */
+#[derive(Default)]
+pub struct FileIndex {
+    entries: HashMap<(String, HashAlgorithm), IndexEntry>,
+}
+
+pub fn mtime_ns(metadata: &Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+impl FileIndex {
+    pub fn new() -> FileIndex {
+        FileIndex {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn load(self: &mut FileIndex, entries: Vec<IndexEntry>) {
+        for entry in entries {
+            self.entries.insert((entry.path.clone(), HashAlgorithm::of_hash(&entry.hash)), entry);
+        }
+    }
+
+    pub fn clear(self: &mut FileIndex) {
+        self.entries.clear();
+    }
+
+    /** Entries ordered by path */
+    pub fn entries(self: &FileIndex) -> Vec<IndexEntry> {
+        let mut entries: Vec<IndexEntry> = self.entries.values().cloned().collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path).then_with(|| a.hash.cmp(&b.hash)));
+        return entries;
+    }
+
+    /** Hash of the file when it didn't change since it was indexed */
+    pub fn cached(self: &FileIndex, path: &Path, metadata: &Metadata, algorithm: HashAlgorithm) -> Option<String> {
+        let key = (path.to_string_lossy().to_string(), algorithm);
+        return self
+            .entries
+            .get(&key)
+            .filter(|entry| entry.size == metadata.len() && entry.mtime_ns == mtime_ns(metadata))
+            .map(|entry| entry.hash.clone());
+    }
+
+    /** Record the hash of the file as it is described by the metadata */
+    pub fn insert(self: &mut FileIndex, path: &Path, metadata: &Metadata, hash: String) {
+        let path_str = path.to_string_lossy().to_string();
+        let key = (path_str.clone(), HashAlgorithm::of_hash(&hash));
+        self.entries.insert(key, IndexEntry {
+            path: path_str,
+            size: metadata.len(),
+            mtime_ns: mtime_ns(metadata),
+            hash,
+        });
+    }
+}
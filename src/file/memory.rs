@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::file::hash::HashAlgorithm;
+use crate::file::ignore::ClientScope;
+use crate::file::{read_range, FileError, ProvideFile, ReadedData};
+use crate::ws::ws_message::{Attributes, Directory, File};
+
+/** Fault injected in the reads of a file */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// the reads fail with the error
+    Io(String),
+    /// every read takes this long
+    Slow(Duration),
+    /// the content changes after this many reads, the following ones fail with `file_changed`
+    ChangeAfter(usize),
+}
+
+struct MemoryFile {
+    content: Vec<u8>,
+    mtime_ns: i64,
+    faults: Vec<Fault>,
+    reads: usize,
+    /// hashes of the current content
+    hashes: HashMap<HashAlgorithm, String>,
+}
+
+impl MemoryFile {
+    fn new(content: Vec<u8>, mtime_ns: i64) -> MemoryFile {
+        MemoryFile {
+            content,
+            mtime_ns,
+            faults: Vec::new(),
+            reads: 0,
+            hashes: HashMap::new(),
+        }
+    }
+
+    fn hash(self: &mut MemoryFile, algorithm: HashAlgorithm) -> String {
+        let content = &self.content;
+        self.hashes.entry(algorithm).or_insert_with(|| algorithm.digest_data(content)).clone()
+    }
+
+    fn write(self: &mut MemoryFile, content: Vec<u8>) {
+        self.content = content;
+        self.mtime_ns += 1;
+        self.hashes.clear();
+    }
+}
+
+/** Provider serving files kept in memory, for the tests of the clients and of the protocol.
+The files are added with `with_file`, with `/` separated paths of the tree, and the
+faults of their reads with `with_fault`. Like the file service, a file is only found by
+the hashes sent in a tree, and a file whose content changed since is `file_changed` */
+pub struct MemoryFileService {
+    files: Mutex<BTreeMap<String, MemoryFile>>,
+    hash_algorithm: HashAlgorithm,
+    /// path of each hash sent in a tree
+    hashes: Mutex<HashMap<String, String>>,
+}
+
+impl Default for MemoryFileService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFileService {
+    pub fn new() -> MemoryFileService {
+        MemoryFileService {
+            files: Mutex::new(BTreeMap::new()),
+            hash_algorithm: HashAlgorithm::default(),
+            hashes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_file(self: MemoryFileService, path: &str, content: impl Into<Vec<u8>>) -> MemoryFileService {
+        self.files.lock().unwrap().insert(tree_path(path), MemoryFile::new(content.into(), 0));
+        return self;
+    }
+
+    /** Inject a fault in the reads of the file, which must be added first */
+    pub fn with_fault(self: MemoryFileService, path: &str, fault: Fault) -> MemoryFileService {
+        self.files
+            .lock()
+            .unwrap()
+            .get_mut(&tree_path(path))
+            .unwrap_or_else(|| panic!("There is no file {} to inject a fault in", path))
+            .faults
+            .push(fault);
+        return self;
+    }
+
+    /** Algorithm of the tree, when the client knows it */
+    pub fn with_hash_algorithm(self: MemoryFileService, algorithm: HashAlgorithm) -> MemoryFileService {
+        MemoryFileService {
+            hash_algorithm: algorithm,
+            ..self
+        }
+    }
+
+    /** Replace the content of a file, or add it. The transfers of the previous content
+    fail with `file_changed` */
+    pub fn write_file(self: &MemoryFileService, path: &str, content: impl Into<Vec<u8>>) {
+        let mut files = self.files.lock().unwrap();
+        match files.get_mut(&tree_path(path)) {
+            Some(file) => file.write(content.into()),
+            None => {
+                files.insert(tree_path(path), MemoryFile::new(content.into(), 0));
+            }
+        }
+    }
+
+    pub fn remove_file(self: &MemoryFileService, path: &str) {
+        self.files.lock().unwrap().remove(&tree_path(path));
+    }
+
+    /** Whether the rules of the client exclude the file or one of its directories */
+    fn is_hidden(path: &str, scope: &ClientScope) -> bool {
+        let mut dir = String::new();
+        let mut names = path.split('/').peekable();
+        while let Some(name) = names.next() {
+            dir = if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) };
+            if scope.ignore.is_ignored(Path::new(&dir), names.peek().is_some()) {
+                return true;
+            }
+        }
+        return false;
+    }
+}
+
+fn tree_path(path: &str) -> String {
+    path.trim_matches('/').to_string()
+}
+
+fn empty_dir(name: &str, path: &str) -> Directory {
+    Directory {
+        name: name.to_string(),
+        path: Some(path.to_string()),
+        dirs: Some(Vec::new()),
+        files: Some(Vec::new()),
+        links: Some(Vec::new()),
+        errors: Some(Vec::new()),
+        attributes: Attributes::default(),
+    }
+}
+
+/** Directory with the path under the root, created with its parents when missing */
+fn make_dir<'a>(root: &'a mut Directory, path: &str) -> &'a mut Directory {
+    let mut dir = root;
+    let mut dir_path = String::new();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        dir_path = if dir_path.is_empty() { name.to_string() } else { format!("{}/{}", dir_path, name) };
+        let dirs = dir.dirs.get_or_insert_with(Vec::new);
+        let position = match dirs.iter().position(|sub_dir| sub_dir.name == name) {
+            Some(position) => position,
+            None => {
+                dirs.push(empty_dir(name, &dir_path));
+                dirs.len() - 1
+            }
+        };
+        dir = &mut dirs[position];
+    }
+    return dir;
+}
+
+impl ProvideFile for MemoryFileService {
+    fn get_tree(&self, scope: &ClientScope) -> Result<Directory, String> {
+        let algorithm = scope.hash_algorithm(self.hash_algorithm);
+        let mut files = self.files.lock().unwrap();
+        let mut hashes = self.hashes.lock().unwrap();
+        let mut root = empty_dir("root", "");
+        for (path, file) in files.iter_mut() {
+            if MemoryFileService::is_hidden(path, scope) {
+                continue;
+            }
+            let hash = file.hash(algorithm);
+            hashes.insert(hash.clone(), path.clone());
+            let (dir_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+            make_dir(&mut root, dir_path).files.get_or_insert_with(Vec::new).push(File {
+                name: name.to_string(),
+                path: Some(path.clone()),
+                hash,
+                size: file.content.len() as u64,
+                attributes: Attributes {
+                    mtime_ns: file.mtime_ns,
+                    ..Default::default()
+                },
+            });
+        }
+        Ok(root)
+    }
+
+    fn get_file_data(
+        &self,
+        start: u64,
+        end: u64,
+        file_key: String,
+        scope: &ClientScope,
+    ) -> Result<ReadedData, FileError> {
+        let path = self.hashes.lock().unwrap().get(&file_key).cloned();
+        let path = match path {
+            Some(path) if !MemoryFileService::is_hidden(&path, scope) => path,
+            _ => return Err(FileError::NotFound(file_key)),
+        };
+        // the slow reads wait without holding the files, the other reads go on meanwhile
+        let delay: Duration = self.files.lock().unwrap().get(&path).map(|file| {
+            file.faults.iter().filter_map(|fault| match fault {
+                Fault::Slow(delay) => Some(*delay),
+                _ => None,
+            }).sum()
+        }).unwrap_or_default();
+        sleep(delay);
+
+        let mut files = self.files.lock().unwrap();
+        let file = match files.get_mut(&path) {
+            Some(file) => file,
+            None => return Err(FileError::Changed(file_key)),
+        };
+        if file.hash(HashAlgorithm::of_hash(&file_key)) != file_key {
+            return Err(FileError::Changed(file_key));
+        }
+
+        let mut change_after = None;
+        for fault in &file.faults {
+            match fault {
+                Fault::Io(err) => return Err(FileError::Io(err.clone())),
+                Fault::Slow(_) => {}
+                Fault::ChangeAfter(reads) => change_after = Some(*reads),
+            }
+        }
+        let content = &file.content;
+        let readed = read_range(content.len() as u64, start, end, |start, end| {
+            Ok(content[start as usize..end as usize].to_vec())
+        })?;
+        file.reads += 1;
+        if change_after == Some(file.reads) {
+            let mut content = file.content.clone();
+            content.push(b'\n');
+            file.write(content);
+        }
+        return Ok(readed);
+    }
+}
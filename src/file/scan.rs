@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::{available_parallelism, scope};
+
+use crate::file::hash::HashAlgorithm;
+use crate::file::index::FileIndex;
+
+/// more threads rarely help, the disk is the limit before the CPU
+pub const MAX_DEFAULT_HASH_WORKERS: usize = 8;
+
+/** Threads hashing the files of a scan, one per CPU up to `MAX_DEFAULT_HASH_WORKERS` */
+pub fn default_hash_workers() -> usize {
+    available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+        .min(MAX_DEFAULT_HASH_WORKERS)
+}
+
+/** File of the tree that is not in the index, or changed since it was indexed */
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HashJob {
+    pub path: PathBuf,
+    pub algorithm: HashAlgorithm,
+}
+
+/** Files of the scan in flight. It is shared outside the lock of the file service,
+the connections waiting for a tree can tell their clients how far the scan is */
+#[derive(Debug, Default)]
+pub struct ScanProgress {
+    scanning: AtomicBool,
+    /// files with a hash, from the index or computed
+    done: AtomicU64,
+    /// files found so far, it grows while the directories are read
+    total: AtomicU64,
+}
+
+impl ScanProgress {
+    pub fn new() -> ScanProgress {
+        ScanProgress::default()
+    }
+
+    /** Start counting the files of a new scan, until the guard is dropped */
+    pub fn start(self: &ScanProgress) -> ScanGuard<'_> {
+        self.done.store(0, Ordering::SeqCst);
+        self.total.store(0, Ordering::SeqCst);
+        self.scanning.store(true, Ordering::SeqCst);
+        return ScanGuard(self);
+    }
+
+    /** A file was found, `hashed` when its hash was already in the index */
+    pub fn found(self: &ScanProgress, hashed: bool) {
+        self.total.fetch_add(1, Ordering::SeqCst);
+        if hashed {
+            self.done.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    pub fn hashed(self: &ScanProgress) {
+        self.done.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /** Files hashed and files found, `None` when no scan is running */
+    pub fn current(self: &ScanProgress) -> Option<(u64, u64)> {
+        if !self.scanning.load(Ordering::SeqCst) {
+            return None;
+        }
+        return Some((self.done.load(Ordering::SeqCst), self.total.load(Ordering::SeqCst)));
+    }
+}
+
+/** Marks the end of the scan, even when it fails */
+pub struct ScanGuard<'a>(&'a ScanProgress);
+
+impl Drop for ScanGuard<'_> {
+    fn drop(&mut self) {
+        self.0.scanning.store(false, Ordering::SeqCst);
+    }
+}
+
+/** Hash the files on up to `workers` threads. The index is only locked to record
+the hashes, the other scans can use it meanwhile */
+pub fn hash_files(
+    jobs: &[HashJob],
+    workers: usize,
+    index: &Mutex<FileIndex>,
+    progress: &ScanProgress,
+) -> HashMap<HashJob, Result<String, String>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(HashMap::with_capacity(jobs.len()));
+    scope(|threads| {
+        for _ in 0..workers.clamp(1, jobs.len().max(1)) {
+            threads.spawn(|| {
+                while let Some(job) = jobs.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let hash = job
+                        .path
+                        .metadata()
+                        .map_err(|err| format!("Problems reading {}: {}", job.path.display(), err))
+                        .and_then(|metadata| {
+                            let hash = job.algorithm.digest(&job.path)?;
+                            index.lock().unwrap().insert(&job.path, &metadata, hash.clone());
+                            Ok(hash)
+                        });
+                    progress.hashed();
+                    results.lock().unwrap().insert(job.clone(), hash);
+                }
+            });
+        }
+    });
+    return results.into_inner().unwrap();
+}
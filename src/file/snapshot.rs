@@ -0,0 +1,54 @@
+use std::fs::{copy, read_dir, remove_file};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::file::index::mtime_ns;
+
+/// snapshots not read for this time are removed, the transfer is over or abandoned
+pub const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+static SNAPSHOTS_TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/** Whether the file is no longer the one described by the size and the modification
+time it had when it was hashed. A file that can't be read changed too */
+pub fn file_changed(path: &Path, size: u64, mtime: i64) -> bool {
+    match path.metadata() {
+        Ok(metadata) => metadata.len() != size || mtime_ns(&metadata) != mtime,
+        Err(_) => true,
+    }
+}
+
+/** Copy of the file in the snapshot directory, served while the file is transferred.
+On linux the copy uses `copy_file_range`, which shares the data of the file instead
+of copying it on the filesystems with reflinks (btrfs, xfs) */
+pub fn take_snapshot(dir: &Path, hash: &str, path: &Path) -> Result<PathBuf, String> {
+    // the reads taking a snapshot of the same file at once don't share the copy
+    let number = SNAPSHOTS_TAKEN.fetch_add(1, Ordering::SeqCst);
+    let snapshot = dir.join(format!("{}{}-{}", SNAPSHOT_PREFIX, hash.replace(':', "-"), number));
+    copy(path, &snapshot)
+        .map_err(|err| format!("Problems taking a snapshot of {}: {}", path.display(), err))?;
+    return Ok(snapshot);
+}
+
+pub fn remove_snapshot(snapshot: &Path) {
+    if let Err(err) = remove_file(snapshot) {
+        warn!(snapshot = %snapshot.display(), error = %err, "Problems removing a snapshot");
+    }
+}
+
+/** Remove the snapshots left by a previous run, the other files of the directory are kept */
+pub fn clear_snapshots(dir: &Path) -> Result<(), String> {
+    let entries = read_dir(dir)
+        .map_err(|err| format!("Problems reading the snapshot directory {}: {}", dir.display(), err))?;
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with(SNAPSHOT_PREFIX) {
+            remove_snapshot(&entry.path());
+        }
+    }
+    Ok(())
+}
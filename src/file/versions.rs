@@ -0,0 +1,157 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{copy, create_dir_all, read_dir, read_to_string, remove_file, rename, write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::file::snapshot::file_changed;
+use crate::ws::ws_message::FileVersion;
+
+/// versions of every path, next to the directory of the contents
+const MANIFEST: &str = "versions.json";
+const OBJECTS: &str = "objects";
+
+/** How long the previous versions of a file are kept, the newest one always is */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// versions kept per path, the newest one included, no limit when missing
+    pub max_versions: Option<usize>,
+    /// a version is removed this long after a newer one replaced it, no limit when missing
+    pub max_age: Option<Duration>,
+}
+
+/** Contents the files had, stored once per hash. The store owns its directory,
+the manifest is written again every time the versions change */
+pub struct VersionStore {
+    dir: PathBuf,
+    retention: Retention,
+    /// versions of each path, the oldest first
+    versions: HashMap<String, Vec<FileVersion>>,
+}
+
+impl VersionStore {
+    pub fn open(dir: &Path, retention: Retention) -> Result<VersionStore, String> {
+        create_dir_all(dir.join(OBJECTS))
+            .map_err(|err| format!("Problems creating the version store {}: {}", dir.display(), err))?;
+        let manifest = dir.join(MANIFEST);
+        let versions = if manifest.exists() {
+            let content = read_to_string(&manifest)
+                .map_err(|err| format!("Problems reading {}: {}", manifest.display(), err))?;
+            serde_json::from_str(&content).map_err(|err| format!("Invalid manifest {}: {}", manifest.display(), err))?
+        } else {
+            HashMap::new()
+        };
+        return Ok(VersionStore {
+            dir: dir.to_path_buf(),
+            retention,
+            versions,
+        });
+    }
+
+    fn object_path(self: &VersionStore, hash: &str) -> PathBuf {
+        self.dir.join(OBJECTS).join(hash.replace(':', "-"))
+    }
+
+    /** Store the content of the file as its newest version, unless it already is.
+    The copy is dropped when the file changes while it is copied */
+    pub fn record(self: &mut VersionStore, path: &Path, version: FileVersion) -> Result<bool, String> {
+        let key = path.to_string_lossy().to_string();
+        let versions = self.versions.get(&key);
+        if versions.and_then(|versions| versions.last()).map(|last| last.hash == version.hash).unwrap_or(false) {
+            return Ok(false);
+        }
+        let object = self.object_path(&version.hash);
+        if !object.exists() {
+            let partial = object.with_extension("partial");
+            copy(path, &partial).map_err(|err| format!("Problems storing {}: {}", path.display(), err))?;
+            if file_changed(path, version.size, version.mtime_ns) {
+                let _ = remove_file(&partial);
+                return Ok(false);
+            }
+            rename(&partial, &object).map_err(|err| format!("Problems storing {}: {}", path.display(), err))?;
+        }
+        self.versions.entry(key).or_default().push(version);
+        return Ok(true);
+    }
+
+    /** Versions of the path, the oldest first */
+    pub fn versions(self: &VersionStore, path: &str) -> Vec<FileVersion> {
+        self.versions.get(path).cloned().unwrap_or_default()
+    }
+
+    /** Stored content with the hash, with its size and the paths that had it */
+    pub fn find(self: &VersionStore, hash: &str) -> Option<(PathBuf, u64, Vec<String>)> {
+        let mut size = None;
+        let mut paths = Vec::new();
+        for (path, versions) in &self.versions {
+            if let Some(version) = versions.iter().find(|version| version.hash == hash) {
+                size = Some(version.size);
+                paths.push(path.clone());
+            }
+        }
+        return size.map(|size| (self.object_path(hash), size, paths));
+    }
+
+    /** Remove the versions out of the retention, and the contents no version has anymore.
+    Returns whether versions were removed */
+    pub fn prune(self: &mut VersionStore, now: i64) -> bool {
+        let retention = self.retention;
+        let mut removed = false;
+        for versions in self.versions.values_mut() {
+            let count = versions.len();
+            let replaced_at: Vec<i64> = versions.iter().skip(1).map(|version| version.stored_at).collect();
+            let mut index = 0;
+            versions.retain(|_| {
+                let position = index;
+                index += 1;
+                if position + 1 == count {
+                    return true;
+                }
+                let too_many = retention.max_versions.map(|max| count - position > max).unwrap_or(false);
+                let too_old = retention
+                    .max_age
+                    .map(|max_age| now - replaced_at[position] > max_age.as_secs() as i64)
+                    .unwrap_or(false);
+                return !(too_many || too_old);
+            });
+            removed |= versions.len() != count;
+        }
+        if removed {
+            self.remove_unused_objects();
+        }
+        return removed;
+    }
+
+    fn remove_unused_objects(self: &VersionStore) {
+        let used: HashSet<PathBuf> = self
+            .versions
+            .values()
+            .flatten()
+            .map(|version| self.object_path(&version.hash))
+            .collect();
+        let entries = match read_dir(self.dir.join(OBJECTS)) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!(error = %err, "Problems reading the version store");
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            if !used.contains(&entry.path()) {
+                if let Err(err) = remove_file(entry.path()) {
+                    warn!(object = %entry.path().display(), error = %err, "Problems removing a stored version");
+                }
+            }
+        }
+    }
+
+    /** Write the manifest, replacing the previous one at once */
+    pub fn save(self: &VersionStore) -> Result<(), String> {
+        let manifest = self.dir.join(MANIFEST);
+        let partial = manifest.with_extension("partial");
+        write(&partial, serde_json::to_string(&self.versions).unwrap())
+            .and_then(|_| rename(&partial, &manifest))
+            .map_err(|err| format!("Problems saving {}: {}", manifest.display(), err))
+    }
+}
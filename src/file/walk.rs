@@ -0,0 +1,55 @@
+use std::fs::Metadata;
+use std::path::Path;
+#[cfg(not(target_family = "unix"))]
+use std::path::PathBuf;
+
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::MetadataExt;
+
+/// the entry can't be read
+pub const ENTRY_IO_ERROR: &str = "io_error";
+/// the name of the entry is not valid UTF-8
+pub const ENTRY_INVALID_NAME: &str = "invalid_name";
+/// the link leads to one of the directories it is in
+pub const ENTRY_SYMLINK_LOOP: &str = "symlink_loop";
+/// the link leads out of the served directories
+pub const ENTRY_OUTSIDE_ROOT: &str = "outside_root";
+
+/** What the tree does with the symbolic links */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SymlinkPolicy {
+    /// listed in the `links` of their directory, without following them
+    #[default]
+    Report,
+    /// followed when they lead inside the served directories
+    Follow,
+    /// left out of the tree
+    Skip,
+}
+
+impl SymlinkPolicy {
+    pub fn parse(value: &str) -> Result<SymlinkPolicy, String> {
+        match value {
+            "report" => Ok(SymlinkPolicy::Report),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            _ => Err(format!("Invalid symlink policy '{}', expected 'report', 'follow' or 'skip'", value)),
+        }
+    }
+}
+
+/** Identity of a directory, the same one reached through a link has the same id */
+#[cfg(target_family = "unix")]
+pub type DirId = (u64, u64);
+#[cfg(not(target_family = "unix"))]
+pub type DirId = PathBuf;
+
+#[cfg(target_family = "unix")]
+pub fn dir_id(_path: &Path, metadata: &Metadata) -> DirId {
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn dir_id(path: &Path, _metadata: &Metadata) -> DirId {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
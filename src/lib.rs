@@ -1,26 +1,99 @@
+#![allow(clippy::needless_return, clippy::module_inception)]
+
+use std::io::Error;
+use std::path::Path;
 use std::sync::Mutex;
-use std::env;
+use std::time::Duration;
 
-use actix_web::{web::Data};
+use actix_web::{rt::task::spawn_blocking, web::Data};
+use tracing::{info, warn};
 
+use config::Config;
 use data::DataService;
-use file::FileService;
+use file::chunks::ChunkFileService;
+use file::{FileService, ProvideFile};
 use ws::start_websocket_server;
 use api::start_api_server;
+use logging::init_logging;
 
 pub mod api;
+pub mod cli;
+pub mod config;
 pub mod data;
 pub mod file;
+pub mod logging;
+pub mod metrics;
+pub mod throttle;
 pub mod ws;
 
-pub async fn run() -> std::io::Result<()> {
-    let data_ins = Data::new(Mutex::new(DataService::new()));
-    let data_path: String = env::var("DATA_PATH").unwrap_or("./data".to_string());
-    let file_ins = Data::new(Mutex::new(FileService::new(data_path)));
+pub async fn run(config: Config) -> std::io::Result<()> {
+    init_logging(config.log_format, &config.log_level);
+
+    let data_service = DataService::open(config.db_path(), config.key_rotation_grace)
+        .map_err(Error::other)?;
+    let data_ins = Data::new(Mutex::new(data_service));
+    let mut file_service = FileService::with_cache_timeout(
+        config.data_path.clone(),
+        Duration::from_secs(config.hash_cache_timeout),
+    );
+    file_service.set_shares(data_ins.lock().unwrap().get_shares());
+    file_service.set_include_owner(config.tree_owner);
+    file_service.set_ignore(&config.ignore).map_err(Error::other)?;
+    file_service.set_settle_time(Duration::from_secs(config.settle_time));
+    file_service.set_symlink_policy(config.symlinks);
+    file_service.set_hash_algorithm(config.hash_algorithm);
+    file_service.set_hash_workers(config.hash_workers);
+    file_service.set_snapshot_dir(config.snapshot_dir.clone()).map_err(Error::other)?;
+    file_service.set_verify_hash(config.verify_hash);
+    file_service.set_versions(config.versions_dir.clone(), config.retention()).map_err(Error::other)?;
+    file_service.load_index(data_ins.lock().unwrap().get_file_index());
+
+    let index = match &config.chunk_dir {
+        Some(chunk_dir) => {
+            let chunk_service = ChunkFileService::new(file_service, Path::new(chunk_dir)).map_err(Error::other)?;
+            let file_ins = Data::new(Mutex::new(chunk_service));
+            serve(&config, &data_ins, &file_ins).await?;
+            let index = file_ins.lock().unwrap().files().index_entries();
+            index
+        }
+        None => {
+            let file_ins = Data::new(Mutex::new(file_service));
+            serve(&config, &data_ins, &file_ins).await?;
+            let index = file_ins.lock().unwrap().index_entries();
+            index
+        }
+    };
+
+    // keep the hashes computed while running for the next start
+    if let Err(err) = data_ins.lock().unwrap().save_file_index(&index) {
+        warn!(error = %err, "Problems saving the file index");
+    }
+    info!("Shutdown complete");
+    return Ok(());
+}
+
+/** Serve the files until the service is asked to stop, and the connections are drained */
+async fn serve<T: ProvideFile + Sync + Send + 'static>(
+    config: &Config,
+    data_ins: &Data<Mutex<DataService>>,
+    file_ins: &Data<Mutex<T>>,
+) -> std::io::Result<()> {
+    let ws_server = start_websocket_server(Data::clone(data_ins), Data::clone(file_ins), config.ws_options())
+        .map_err(Error::other)?;
 
-    let websocket_port: String = env::var("WS_PORT").unwrap_or("4001".to_string());
-    start_websocket_server(Data::clone(&data_ins), Data::clone(&file_ins), websocket_port.parse().unwrap());
+    // returns once the web server stopped, on SIGINT or SIGTERM
+    start_api_server(
+        config.web_bind.clone(),
+        config.web_port,
+        Data::clone(data_ins),
+        Data::clone(file_ins),
+        &ws_server,
+        config.shutdown_timeout,
+    ).await?;
 
-    let webserver_port: String = env::var("WEB_PORT").unwrap_or("4000".to_string());
-    return start_api_server(webserver_port, data_ins).await;
+    let drain_timeout = Duration::from_secs(config.shutdown_timeout);
+    spawn_blocking(move || ws_server.shutdown(drain_timeout))
+        .await
+        .map_err(Error::other)?;
+    return Ok(());
 }
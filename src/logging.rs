@@ -0,0 +1,33 @@
+use tracing_subscriber::EnvFilter;
+
+/** Output format of the service logs */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Result<LogFormat, String> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format '{}', expected 'text' or 'json'", value)),
+        }
+    }
+}
+
+/** Install the global logger, the level uses the `tracing` filter syntax
+(like `info` or `cs::ws=debug`) */
+pub fn init_logging(format: LogFormat, level: &str) {
+    let filter = EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_thread_names(true);
+
+    // ignore the error when a logger was already installed (like in the tests)
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+}
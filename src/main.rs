@@ -1,6 +1,38 @@
+use std::process::exit;
+
+use clap::Parser;
+
+use cs::cli::{run_command, Cli};
+use cs::config::Config;
 use cs::run;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    run().await
+    let cli = Cli::parse();
+    let config = match cli.command {
+        Some(_) => Config::load_for_command(cli.config.as_deref(), cli.overrides),
+        None => Config::load(cli.config.as_deref(), cli.overrides),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            exit(2);
+        }
+    };
+    match cli.command {
+        Some(command) => match run_command(command, &config, cli.json) {
+            Ok(output) => {
+                if !output.is_empty() {
+                    println!("{}", output);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                exit(1);
+            }
+        },
+        None => run(config).await,
+    }
 }
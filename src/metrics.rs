@@ -0,0 +1,71 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter_vec, register_int_gauge, Encoder, Histogram,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static! {
+    // connections
+    pub static ref ACTIVE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "copy_service_ws_active_connections",
+        "WebSocket connections currently open"
+    ).unwrap();
+    pub static ref AUTH_ATTEMPTS: IntCounterVec = register_int_counter_vec!(
+        "copy_service_auth_attempts_total",
+        "Authentication attempts by result",
+        &["result"]
+    ).unwrap();
+
+    // traffic
+    pub static ref MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "copy_service_ws_messages_total",
+        "WebSocket messages handled by type",
+        &["type"]
+    ).unwrap();
+    pub static ref BYTES_SERVED: IntCounterVec = register_int_counter_vec!(
+        "copy_service_bytes_served_total",
+        "File bytes sent to each client",
+        &["client"]
+    ).unwrap();
+    pub static ref THROTTLED_CHUNKS: IntCounterVec = register_int_counter_vec!(
+        "copy_service_throttled_chunks_total",
+        "Chunks sent to each client that had to wait for the bandwidth limit",
+        &["client"]
+    ).unwrap();
+    pub static ref FILE_DATA_SECONDS: Histogram = register_histogram!(
+        "copy_service_get_file_data_seconds",
+        "Time spent reading a chunk of file data",
+        vec![0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+    ).unwrap();
+
+    // hash index
+    pub static ref HASH_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "copy_service_hash_cache_lookups_total",
+        "Lookups of a file hash in the hash cache by result (hit or miss)",
+        &["result"]
+    ).unwrap();
+    pub static ref TREE_SCAN_SECONDS: Histogram = register_histogram!(
+        "copy_service_tree_scan_seconds",
+        "Time spent scanning and hashing the files tree",
+        vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0]
+    ).unwrap();
+}
+
+/** Metrics of the default registry in the Prometheus text format */
+pub fn render() -> String {
+    // register the metrics that were not used yet, so they are always exported
+    lazy_static::initialize(&ACTIVE_CONNECTIONS);
+    lazy_static::initialize(&AUTH_ATTEMPTS);
+    lazy_static::initialize(&MESSAGES);
+    lazy_static::initialize(&BYTES_SERVED);
+    lazy_static::initialize(&THROTTLED_CHUNKS);
+    lazy_static::initialize(&FILE_DATA_SECONDS);
+    lazy_static::initialize(&HASH_CACHE_LOOKUPS);
+    lazy_static::initialize(&TREE_SCAN_SECONDS);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    return String::from_utf8(buffer).unwrap();
+}
@@ -0,0 +1,235 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::now_secs;
+
+/** Time of the day, at the configured UTC offset, when a different rate applies.
+The window can go past midnight, like from `22:00` to `06:00` */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RateWindow {
+    /// `HH:MM`, included
+    pub from: String,
+    /// `HH:MM`, excluded
+    pub to: String,
+    /// bytes per second, unlimited when missing
+    pub rate: Option<u64>,
+}
+
+/** Rate limit of the data sent to the clients */
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct RateSchedule {
+    /// bytes per second outside the windows, unlimited when missing
+    pub rate: Option<u64>,
+    /// the first window containing the current time wins
+    #[serde(default)]
+    pub windows: Vec<RateWindow>,
+}
+
+/** Rate limits in place, as shown by the admin API */
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ThrottleSettings {
+    pub global: RateSchedule,
+    /// by client id
+    pub clients: BTreeMap<i64, RateSchedule>,
+}
+
+fn parse_minute(value: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid time '{}', expected HH:MM", value);
+    let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+    return Ok(hours * 60 + minutes);
+}
+
+/** Offset from UTC in minutes, from `+HH:MM` or `-HH:MM` */
+pub fn parse_utc_offset(value: &str) -> Result<i32, String> {
+    let invalid = || format!("Invalid UTC offset '{}', expected +HH:MM or -HH:MM", value);
+    let (sign, time) = if let Some(time) = value.strip_prefix('+') {
+        (1, time)
+    } else if let Some(time) = value.strip_prefix('-') {
+        (-1, time)
+    } else {
+        return Err(invalid());
+    };
+    let minutes = parse_minute(time).map_err(|_| invalid())?;
+    return Ok(sign * minutes as i32);
+}
+
+/** Minutes since midnight, at the given offset from UTC in minutes */
+pub fn minute_of_day(utc_offset: i32) -> u32 {
+    let local_secs = now_secs() + utc_offset as i64 * 60;
+    return (local_secs.rem_euclid(24 * 60 * 60) / 60) as u32;
+}
+
+impl RateWindow {
+    fn contains(self: &RateWindow, minute: u32) -> bool {
+        let (from, to) = match (parse_minute(&self.from), parse_minute(&self.to)) {
+            (Ok(from), Ok(to)) => (from, to),
+            _ => return false,
+        };
+        if from <= to {
+            return from <= minute && minute < to;
+        }
+        // past midnight
+        return minute >= from || minute < to;
+    }
+}
+
+impl RateSchedule {
+    /** Unlimited rate */
+    pub fn unlimited() -> RateSchedule {
+        RateSchedule::default()
+    }
+
+    pub fn validate(self: &RateSchedule) -> Result<(), String> {
+        let rates = self.windows.iter().map(|window| window.rate).chain([self.rate]);
+        for rate in rates {
+            if rate == Some(0) {
+                return Err("The rate has to be greater than 0, leave it out for no limit".to_string());
+            }
+        }
+        for window in &self.windows {
+            parse_minute(&window.from)?;
+            parse_minute(&window.to)?;
+        }
+        Ok(())
+    }
+
+    /** Rate in bytes per second at the given minute of the day */
+    pub fn rate_at(self: &RateSchedule, minute: u32) -> Option<u64> {
+        match self.windows.iter().find(|window| window.contains(minute)) {
+            Some(window) => window.rate,
+            None => self.rate,
+        }
+    }
+}
+
+/** Token bucket holding up to one second of data. The data is sent in pieces no
+bigger than the bucket, a piece can take the tokens below zero and the debt is paid
+by waiting before the next one */
+struct TokenBucket {
+    /// `None` for a full bucket, one second of the rate in place when it is next used
+    tokens: Option<f64>,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /** Full bucket, the first second of data goes out right away */
+    fn new(now: Instant) -> TokenBucket {
+        TokenBucket { tokens: None, last: now }
+    }
+
+    /** Take the bytes from the bucket, returns how long to wait until it is no longer in debt */
+    fn take(self: &mut TokenBucket, rate: Option<u64>, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let rate = match rate {
+            Some(rate) => rate as f64,
+            None => {
+                // the next limited period starts with a full bucket
+                self.tokens = None;
+                return Duration::ZERO;
+            }
+        };
+
+        let tokens = match self.tokens {
+            Some(tokens) => (tokens + elapsed * rate).min(rate),
+            None => rate,
+        } - bytes as f64;
+        self.tokens = Some(tokens);
+        if tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        return Duration::from_secs_f64(-tokens / rate);
+    }
+}
+
+/** Limits the data sent to the clients, shared by every connection. The limits
+are looked up on each chunk, so changes apply to the transfers in flight */
+pub struct Throttle {
+    settings: ThrottleSettings,
+    global_bucket: TokenBucket,
+    client_buckets: HashMap<i64, TokenBucket>,
+    /// minutes east of UTC of the clock of the windows, fixed, there are no daylight saving changes
+    utc_offset: i32,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(ThrottleSettings::default())
+    }
+}
+
+impl Throttle {
+    pub fn new(settings: ThrottleSettings) -> Throttle {
+        Throttle {
+            settings,
+            global_bucket: TokenBucket::new(Instant::now()),
+            client_buckets: HashMap::new(),
+            utc_offset: 0,
+        }
+    }
+
+    /** Read the windows at the given offset from UTC in minutes */
+    pub fn set_utc_offset(self: &mut Throttle, utc_offset: i32) {
+        self.utc_offset = utc_offset;
+    }
+
+    pub fn settings(self: &Throttle) -> ThrottleSettings {
+        self.settings.clone()
+    }
+
+    /** Replace the limits, the buckets keep their state */
+    pub fn set_settings(self: &mut Throttle, settings: ThrottleSettings) {
+        self.client_buckets.retain(|client, _| settings.clients.contains_key(client));
+        self.settings = settings;
+    }
+
+    /** Account bytes sent to the client, returns how long the connection has to
+    wait before sending more data */
+    pub fn take(self: &mut Throttle, client_id: Option<i64>, bytes: u64) -> Duration {
+        let minute = minute_of_day(self.utc_offset);
+        self.take_at(client_id, bytes, Instant::now(), minute)
+    }
+
+    /** Most bytes to send the client at once, the size of its smallest bucket.
+    A bigger piece would go out at full speed, `None` without limits */
+    pub fn max_piece(self: &Throttle, client_id: Option<i64>) -> Option<u64> {
+        self.max_piece_at(client_id, minute_of_day(self.utc_offset))
+    }
+
+    pub fn max_piece_at(self: &Throttle, client_id: Option<i64>, minute: u32) -> Option<u64> {
+        let client_rate = client_id
+            .and_then(|client_id| self.settings.clients.get(&client_id))
+            .and_then(|schedule| schedule.rate_at(minute));
+        return self.settings.global.rate_at(minute).into_iter().chain(client_rate).min();
+    }
+
+    pub fn take_at(
+        self: &mut Throttle,
+        client_id: Option<i64>,
+        bytes: u64,
+        now: Instant,
+        minute: u32,
+    ) -> Duration {
+        let global_rate = self.settings.global.rate_at(minute);
+        let mut wait = self.global_bucket.take(global_rate, bytes, now);
+
+        let client_schedule = client_id.and_then(|client_id| {
+            self.settings.clients.get(&client_id).map(|schedule| (client_id, schedule))
+        });
+        if let Some((client_id, schedule)) = client_schedule {
+            let client_rate = schedule.rate_at(minute);
+            let bucket = self.client_buckets
+                .entry(client_id)
+                .or_insert_with(|| TokenBucket::new(now));
+            wait = wait.max(bucket.take(client_rate, bytes, now));
+        }
+        return wait;
+    }
+}
@@ -1,217 +1,1268 @@
-use std::net::{TcpListener, TcpStream};
-use std::sync::Mutex;
-use std::thread::spawn;
+use std::borrow::Cow;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
 
 use actix_web::web::Data;
-use tungstenite::{accept, Message, WebSocket};
+use serde::Serialize;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tracing::{debug, field, info, info_span, warn};
+use tungstenite::{accept, Error, Message, WebSocket};
 
-use crate::data::DataService;
-use crate::file::ProvideFile;
-use crate::ws::ws_message::{CopyRes, TreeRes};
+use crate::data::audit::{AuditEntry, AUTH_FAILURE, AUTH_LOCKOUT, AUTH_SUCCESS, FILE_TRANSFER, TREE_REQUEST};
+use crate::data::{now_secs, DataService};
+use crate::file::hash::negotiate;
+use crate::file::ignore::ClientScope;
+use crate::file::scan::ScanProgress;
+use crate::file::{read_file_data, requested_range, FileError, ProvideFile};
+use crate::metrics::{ACTIVE_CONNECTIONS, AUTH_ATTEMPTS, BYTES_SERVED, MESSAGES, THROTTLED_CHUNKS};
+use crate::throttle::Throttle;
+use crate::api::api::LimitPolicy;
+use crate::ws::auth_guard::{peer_host, AuthGuard, AuthPolicy, LOCKOUT_CLIENT, LOCKOUT_PEER, UNKNOWN_CLIENT};
+use crate::ws::keepalive::{Keepalive, KeepaliveAction};
+use crate::ws::session::{AuthOutcome, Session, SessionRegistry};
+use crate::ws::stream::{FileStream, StreamFailure};
+use crate::ws::ws_message::{CopyRes, Directory, NoticeRes, TreeRes, NOTICE_GOING_AWAY, NOTICE_SCAN_PROGRESS};
 use ws_message::{AuthMsg, AuthRes, Message as Msg};
 
-use self::ws_message::{CopyMsg, ErrRes, TreeMsg};
+use self::ws_message::{
+    CancelMsg, CancelRes, ChunksMsg, ChunksRes, CopyMsg, CreditMsg, ErrRes, StreamMsg, TreeMsg, VersionsMsg,
+    VersionsRes,
+};
 
+pub mod auth_guard;
+pub mod keepalive;
+pub mod session;
+pub mod stream;
+pub mod test_server;
 pub mod ws_message;
 
+/// How often a connection thread wakes up from a blocking read to check for
+/// requests coming from outside the connection (like closing the session).
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How often a connection waiting for a scan checks whether it is over.
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Read timeout while a stream has credit, the thread mostly sends chunks.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
 enum MessageError {
     AuthError(),
-    ReadFileError(String),
+    ReadFileError(FileError),
+    /// request that doesn't fit the state of the connection, with the error code
+    InvalidRequest(&'static str, String),
+    /// the response can't be sent, the connection is broken
+    ConnectionError(String),
+}
+
+/** Send a response to the client as a JSON text message */
+fn send_json<M: Serialize>(websocket: &mut WebSocket<TcpStream>, msg: &M) -> Result<(), MessageError> {
+    websocket
+        .send(Message::Text(serde_json::to_string(msg).unwrap()))
+        .map_err(|err| MessageError::ConnectionError(err.to_string()))
+}
+
+/** Id of a message that can't be parsed, when it has one */
+fn raw_message_id(text: &str) -> i32 {
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|value| value.get("id").and_then(|id| id.as_i64()))
+        .and_then(|id| i32::try_from(id).ok())
+        .unwrap_or(0)
+}
+
+/** Settings of the WebSocket server */
+#[derive(Clone, Debug)]
+pub struct WsOptions {
+    pub bind: String,
+    pub port: u16,
+    /// biggest chunk of a file a client can request in one message, in bytes
+    pub max_chunk_size: u64,
+    /// maximum time waiting for the client to acknowledge a close frame
+    pub close_timeout: Duration,
+    pub auth_policy: AuthPolicy,
+    /// a ping is sent after this time without hearing from the client, which has as long to answer
+    pub ping_interval: Duration,
+    /// connections without requests nor transfers are closed after this time
+    pub idle_timeout: Duration,
+    /// connections are closed when they don't authenticate in this time
+    pub auth_timeout: Duration,
+    /// how often a client waiting for a tree is told how far the scan is
+    pub scan_notice_interval: Duration,
+    /// minutes east of UTC of the clock of the rate limit windows
+    pub throttle_utc_offset: i32,
+}
+
+impl Default for WsOptions {
+    fn default() -> Self {
+        WsOptions {
+            bind: "0.0.0.0".to_string(),
+            port: 4001,
+            max_chunk_size: 4 * 1024 * 1024,
+            close_timeout: Duration::from_secs(5),
+            auth_policy: AuthPolicy::default(),
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(10 * 60),
+            auth_timeout: Duration::from_secs(10),
+            scan_notice_interval: Duration::from_secs(1),
+            throttle_utc_offset: 0,
+        }
+    }
+}
+
+/** State of the WebSocket listener shared with the admin API */
+pub struct WsServerState {
+    /// the listener thread is running and accepting connections
+    pub accepting: AtomicBool,
+    /// the server is shutting down, no new connections are accepted
+    pub shutting_down: AtomicBool,
+    /// while shutting down, transfers in flight can go on until this instant
+    drain_deadline: Mutex<Option<Instant>>,
+}
+
+impl Default for WsServerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WsServerState {
+    pub fn new() -> WsServerState {
+        WsServerState {
+            accepting: AtomicBool::new(true),
+            shutting_down: AtomicBool::new(false),
+            drain_deadline: Mutex::new(None),
+        }
+    }
+
+    fn drain_expired(self: &WsServerState) -> bool {
+        self.drain_deadline
+            .lock()
+            .unwrap()
+            .map(|deadline| Instant::now() >= deadline)
+            .unwrap_or(false)
+    }
+}
+
+/** Handle to the running WebSocket server */
+#[derive(Clone)]
+pub struct WsServerHandle {
+    pub sessions: Data<Mutex<SessionRegistry>>,
+    pub state: Data<WsServerState>,
+    /// bandwidth limits of the connections, adjustable while running
+    pub throttle: Data<Mutex<Throttle>>,
+    /// failed authentication counters
+    pub auth_guard: Data<Mutex<AuthGuard>>,
+    close_timeout: Duration,
+    local_addr: SocketAddr,
+}
+
+impl WsServerHandle {
+    /** Address the server listens on, the port is the one picked by the system when
+    the options ask for port 0 */
+    pub fn local_addr(self: &WsServerHandle) -> SocketAddr {
+        self.local_addr
+    }
+
+    /** Stop accepting connections and close the open ones. The connections in the
+    middle of a file transfer are closed once the transfer ends or the drain timeout
+    is over. Blocks until every connection is closed, returns how many were left open */
+    pub fn shutdown(self: &WsServerHandle, drain_timeout: Duration) -> usize {
+        info!(?drain_timeout, "Shutting down the WebSocket server");
+        *self.state.drain_deadline.lock().unwrap() = Some(Instant::now() + drain_timeout);
+        self.state.shutting_down.store(true, Ordering::SeqCst);
+
+        let deadline = Instant::now() + drain_timeout + self.close_timeout + POLL_INTERVAL;
+        loop {
+            let open = self.sessions.lock().unwrap().list().len();
+            if open == 0 {
+                return 0;
+            }
+            if Instant::now() >= deadline {
+                warn!(open, "Connections still open after the drain timeout");
+                return open;
+            }
+            sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/** Flags the listener as stopped when its thread ends, even when panicking */
+struct AcceptingGuard(Data<WsServerState>);
+
+impl Drop for AcceptingGuard {
+    fn drop(&mut self) {
+        self.0.accepting.store(false, Ordering::SeqCst);
+        if self.0.shutting_down.load(Ordering::SeqCst) {
+            info!("WebSocket listener stopped");
+        } else {
+            warn!("WebSocket listener stopped");
+        }
+    }
+}
+
+/** Bandwidth limit of one connection */
+struct ConnectionThrottle {
+    throttle: Data<Mutex<Throttle>>,
+    /// the connection can't send more data until then
+    until: Option<Instant>,
+}
+
+impl ConnectionThrottle {
+    /** Account a chunk sent to the client against the limits */
+    fn take(self: &mut ConnectionThrottle, session: &Session, bytes: u64) {
+        let wait = self.throttle.lock().unwrap().take(session.client_id, bytes);
+        if wait.is_zero() {
+            self.until = None;
+            return;
+        }
+        if let Some(client) = &session.client {
+            THROTTLED_CHUNKS.with_label_values(&[client]).inc();
+        }
+        self.until = Some(Instant::now() + wait);
+    }
+
+    /** Most bytes of the file to send at once to the client of the session */
+    fn max_piece(self: &ConnectionThrottle, sessions: &Data<Mutex<SessionRegistry>>, session_id: u64) -> Option<u64> {
+        let client_id = sessions.lock().unwrap().get(session_id).and_then(|session| session.client_id);
+        self.throttle.lock().unwrap().max_piece(client_id)
+    }
+
+    /** Time left until the connection can send data again */
+    fn remaining(self: &ConnectionThrottle) -> Option<Duration> {
+        self.until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/** What the client of the session can see of the tree, read for every request
+so changes to its ignore patterns apply right away */
+fn client_scope(
+    data_service: &Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+) -> Result<ClientScope, MessageError> {
+    let session = sessions.lock().unwrap().get(session_id);
+    let (client, hash_algorithms) = match session {
+        Some(Session { client: Some(client), hash_algorithms, .. }) => (client, hash_algorithms),
+        _ => return Err(MessageError::AuthError()),
+    };
+    let mut scope = data_service
+        .lock()
+        .unwrap()
+        .get_client_scope(&client)
+        .map_err(|err| MessageError::InvalidRequest("io_error", err))?;
+    scope.hash_algorithms = Some(hash_algorithms);
+    Ok(scope)
+}
+
+/** Locked out peer or client, with the seconds left */
+fn find_lockout(data_service: &DataService, subjects: &[(&'static str, String)]) -> Option<(String, u64)> {
+    let now = now_secs();
+    return subjects
+        .iter()
+        .filter_map(|(kind, subject)| data_service.active_lockout(kind, subject))
+        .map(|lockout| (format!("{} {}", lockout.kind, lockout.subject), (lockout.locked_until - now) as u64))
+        .max_by_key(|(_, remaining)| *remaining);
+}
+
+/** Subject of the counters of a client name, the names that are not a client share one
+so the names sent by the peers don't add counters */
+fn client_subject(data_service: &DataService, name: &str) -> String {
+    match data_service.get_client_by_name(name.to_string()) {
+        Some(client) => client.id.unwrap().to_string(),
+        None => UNKNOWN_CLIENT.to_string(),
+    }
+}
+
+/** Cleans up the state of a connection when its thread ends, even when panicking */
+struct SessionGuard {
+    sessions: Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        // the lock is poisoned when the thread panicked while holding it
+        let mut sessions = self.sessions.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        sessions.unregister(self.session_id);
+        ACTIVE_CONNECTIONS.dec();
+        info!("Connection closed");
+    }
 }
 
 fn handle_auth_msg(
     msg: AuthMsg,
     data_service: Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
     websocket: &mut WebSocket<TcpStream>,
+    auth_guard: &Data<Mutex<AuthGuard>>,
 ) -> Result<(), MessageError> {
-    println!("AuthMsg: {:?}", msg);
+    debug!(?msg, "AuthMsg");
     let data_service = data_service.lock().unwrap();
-    let accept = data_service.validate_user_auth(msg.name.clone(), msg.key.clone());
+    let peer = sessions.lock().unwrap().get(session_id).and_then(|session| session.peer);
+    let mut subjects = vec![(LOCKOUT_CLIENT, client_subject(&data_service, &msg.name))];
+    if let Some(peer) = &peer {
+        subjects.push((LOCKOUT_PEER, peer_host(peer)));
+    }
 
-    let res: AuthRes;
+    // the key is not even checked while locked out or backing off
+    let now = Instant::now();
+    let backoff = auth_guard.lock().unwrap().backoff_remaining(&subjects, now);
+    let mut retry_after = None;
+    let (status, detail) = if let Some((locked, remaining)) = find_lockout(&data_service, &subjects) {
+        retry_after = Some(remaining);
+        ("locked_out", Some(format!("{} locked out", locked)))
+    } else if let Some(remaining) = backoff {
+        retry_after = Some(remaining.as_secs_f64().ceil() as u64);
+        ("retry_later", Some("too many failed attempts".to_string()))
+    } else if data_service.validate_user_auth(msg.name.clone(), msg.key.clone()) {
+        auth_guard.lock().unwrap().reset(&subjects);
+        let client = data_service.get_client_by_name(msg.name.clone()).unwrap();
+        let outcome = sessions.lock().unwrap().authenticate(
+            session_id,
+            msg.name.clone(),
+            client.id,
+            client.max_connections,
+            client.limit_policy.unwrap_or(LimitPolicy::Reject),
+        );
+        match outcome {
+            AuthOutcome::Accepted => ("accepted", None),
+            AuthOutcome::AcceptedEvicting(evicted) => {
+                ("accepted", Some(format!("evicted session {}", evicted)))
+            }
+            AuthOutcome::LimitExceeded => {
+                ("limit_exceeded", Some("connection limit exceeded".to_string()))
+            }
+        }
+    } else {
+        let mut auth_guard = auth_guard.lock().unwrap();
+        let lockout = auth_guard.policy.lockout;
+        for ((kind, subject), failures) in auth_guard.record_failure(&subjects, now) {
+            let lockout = data_service.add_lockout(kind, &subject, failures, lockout.as_secs() as i64);
+            warn!(kind, subject, failures, locked_until = lockout.locked_until, "Authentication locked out");
+            let mut entry = AuditEntry::new(AUTH_LOCKOUT, Some(msg.name.clone()), peer.clone());
+            entry.detail = Some(format!("{} {} locked out after {} failures", kind, subject, failures));
+            data_service.add_audit_entry(entry);
+        }
+        ("denied", None)
+    };
+    // a connection over the limit keeps the state it had, like the auth of another client
+    if !matches!(status, "accepted" | "limit_exceeded") {
+        sessions.lock().unwrap().deauthenticate(session_id);
+    }
+
+    let accept = status == "accepted";
+    AUTH_ATTEMPTS.with_label_values(&[status]).inc();
     if accept {
-        res = AuthRes {
-            id: msg.id,
-            status: "accepted".to_string(),
-        };
+        info!(client = %msg.name, "Client authenticated");
     } else {
-        res = AuthRes {
-            id: msg.id,
-            status: "denied".to_string(),
+        warn!(client = %msg.name, status, "Client authentication rejected");
+    }
+    // the attempts turned away without checking the key can come in floods, they are aggregated
+    let audited_attempts = match status {
+        "retry_later" | "locked_out" => auth_guard.lock().unwrap().turn_away(&subjects, now),
+        _ => Some(1),
+    };
+    if let Some(attempts) = audited_attempts {
+        let mut entry = AuditEntry::new(
+            if accept { AUTH_SUCCESS } else { AUTH_FAILURE },
+            Some(msg.name.clone()),
+            peer,
+        );
+        entry.detail = match detail {
+            Some(detail) if attempts > 1 => Some(format!("{}, {} attempts turned away", detail, attempts)),
+            detail => detail,
         };
+        data_service.add_audit_entry(entry);
     }
-    websocket
-        .send(Message::Text(serde_json::to_string(&res).unwrap()))
-        .unwrap();
+
+    let mut hash_algorithms = None;
+    if accept {
+        let negotiated = negotiate(msg.hash_algorithms.as_deref());
+        hash_algorithms = Some(negotiated.iter().map(|algorithm| algorithm.as_str().to_string()).collect());
+        sessions.lock().unwrap().set_hash_algorithms(session_id, negotiated);
+    }
+    let res = AuthRes {
+        id: msg.id,
+        status: status.to_string(),
+        retry_after,
+        hash_algorithms,
+    };
+    send_json(websocket, &res)?;
     if !accept {
         return Err(MessageError::AuthError());
     }
     Ok(())
 }
 
-fn handle_tree_msg<T: ProvideFile>(
+/** Where a connection waiting for a tree learns how far the scan is */
+struct ScanNotices {
+    progress: Option<Arc<ScanProgress>>,
+    interval: Duration,
+}
+
+/** Tree of the scope with the directories it serves, the client is sent the progress of
+the scan while it waits. The scan can be its own or the one of another connection holding
+the file service */
+fn scan_tree<T: ProvideFile + Sync + Send>(
+    msg_id: i32,
+    scope: &ClientScope,
+    file_service: &Data<Mutex<T>>,
+    notices: &ScanNotices,
+    websocket: &mut WebSocket<TcpStream>,
+) -> Result<(Directory, Vec<String>), MessageError> {
+    let scan_tree = || {
+        let file_service = file_service.lock().unwrap();
+        return file_service.get_tree(scope).map(|root| (root, file_service.served_paths()));
+    };
+    let progress = match &notices.progress {
+        Some(progress) => progress,
+        None => return scan_tree().map_err(|err| MessageError::InvalidRequest("io_error", err)),
+    };
+    return std::thread::scope(|threads| {
+        let scan = threads.spawn(scan_tree);
+        let mut last_notice = Instant::now();
+        while !scan.is_finished() {
+            sleep(SCAN_POLL_INTERVAL.min(notices.interval));
+            if last_notice.elapsed() < notices.interval {
+                continue;
+            }
+            last_notice = Instant::now();
+            if let Some((done, total)) = progress.current() {
+                let notice = NoticeRes {
+                    id: msg_id,
+                    notice: NOTICE_SCAN_PROGRESS.to_string(),
+                    detail: format!("scan in progress: {} of {} files", done, total),
+                    done: Some(done),
+                    total: Some(total),
+                };
+                send_json(websocket, &notice)?;
+            }
+        }
+        return scan
+            .join()
+            .map_err(|_| MessageError::InvalidRequest("io_error", "The scan of the files failed".to_string()))?
+            .map_err(|err| MessageError::InvalidRequest("io_error", err));
+    });
+}
+
+fn handle_tree_msg<T: ProvideFile + Sync + Send>(
     msg: TreeMsg,
     file_service: Data<Mutex<T>>,
+    data_service: Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+    scan_notices: &ScanNotices,
     websocket: &mut WebSocket<TcpStream>,
 ) -> Result<(), MessageError> {
-    println!("TreeMsg: {:?}", msg);
+    debug!(?msg, "TreeMsg");
+    let scope = client_scope(&data_service, sessions, session_id)?;
 
-    // setting up tree
-    let tree = TreeRes {
-        id: msg.id,
-        root: file_service.lock().unwrap().get_tree().unwrap(),
-    };
+    let (root, served_paths) = scan_tree(msg.id, &scope, &file_service, scan_notices, websocket)?;
+    let tree = TreeRes { id: msg.id, root };
 
-    websocket
-        .send(Message::Text(serde_json::to_string(&tree).unwrap()))
-        .unwrap();
+    let session = sessions.lock().unwrap().get(session_id).unwrap();
+    let mut entry = AuditEntry::new(TREE_REQUEST, session.client, session.peer);
+    entry.detail = Some(served_paths.join(", ")).filter(|detail| !detail.is_empty());
+    data_service.lock().unwrap().add_audit_entry(entry);
 
-    Ok(())
+    send_json(websocket, &tree)
+}
+
+/** File copied with successive `CopyMsg`, audited once its last chunk is sent */
+struct CopyTransfer {
+    file_hash: String,
+    started: Instant,
+    bytes: u64,
+}
+
+/** Audit the copy left before its last chunk, when the client copies another file or leaves */
+fn audit_unfinished_copy(
+    copy_transfer: &mut Option<CopyTransfer>,
+    data_service: &Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+) {
+    let session = sessions.lock().unwrap().get(session_id);
+    if let (Some(unfinished), Some(session)) = (copy_transfer.take(), session) {
+        let detail = Some("incomplete".to_string());
+        audit_transfer(data_service, session, unfinished.file_hash, unfinished.bytes, unfinished.started, detail);
+    }
 }
 
+/** Send the requested chunk of the file, the requests without an end are streams */
+#[allow(clippy::too_many_arguments)]
 fn handle_copy_msg<T: ProvideFile>(
     msg: CopyMsg,
     file_service: Data<Mutex<T>>,
+    data_service: Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
     websocket: &mut WebSocket<TcpStream>,
+    max_chunk_size: u64,
+    throttle: &mut ConnectionThrottle,
+    copy_transfer: &mut Option<CopyTransfer>,
 ) -> Result<(), MessageError> {
-    println!("CopyMsg: {:?}", msg);
+    debug!(?msg, "CopyMsg");
+    let started = Instant::now();
+    let scope = client_scope(&data_service, sessions, session_id)?;
 
-    let data_res = file_service
-        .lock()
-        .unwrap()
-        .get_file_data(msg.start, msg.end, msg.file_hash)
-        .unwrap();
+    let (start, end) = requested_range(msg.start, msg.end, max_chunk_size).map_err(MessageError::ReadFileError)?;
+    // the client asks for the rest of the range in its next request
+    let end = match throttle.max_piece(sessions, session_id) {
+        Some(max_piece) => min(end, start.saturating_add(max_piece)),
+        None => end,
+    };
+    let data_res = read_file_data(&file_service, start, end, msg.file_hash.clone(), &scope)
+        .map_err(MessageError::ReadFileError)?;
+    let bytes = data_res.end.saturating_sub(start);
 
     let copy_res = CopyRes {
         id: msg.id,
-        start: msg.start,
+        start,
         end: data_res.end,
         data: data_res.data,
         last_data: data_res.last_data,
     };
 
-    websocket
-        .send(Message::Text(serde_json::to_string(&copy_res).unwrap()))
-        .unwrap();
+    send_json(websocket, &copy_res)?;
+
+    let session = record_chunk(sessions, session_id, &msg.file_hash, bytes, copy_res.last_data);
+    throttle.take(&session, bytes);
+
+    if copy_transfer.as_ref().map(|transfer| transfer.file_hash != msg.file_hash).unwrap_or(false) {
+        audit_unfinished_copy(copy_transfer, &data_service, sessions, session_id);
+    }
+    let transfer = copy_transfer.get_or_insert(CopyTransfer {
+        file_hash: msg.file_hash,
+        started,
+        bytes: 0,
+    });
+    transfer.bytes += bytes;
+    if copy_res.last_data {
+        let transfer = copy_transfer.take().unwrap();
+        audit_transfer(&data_service, session, transfer.file_hash, transfer.bytes, transfer.started, None);
+    }
 
     Ok(())
 }
 
-fn user_is_auth(data_service: Data<Mutex<DataService>>, client_name: String) -> bool {
-    data_service
+/** Account a chunk sent to the client, returns the updated session */
+fn record_chunk(
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+    file_hash: &str,
+    bytes: u64,
+    last_data: bool,
+) -> Session {
+    let session = {
+        let mut sessions = sessions.lock().unwrap();
+        sessions.add_bytes_sent(session_id, bytes);
+        sessions.set_current_transfer(
+            session_id,
+            if last_data { None } else { Some(file_hash.to_string()) },
+        );
+        sessions.get(session_id).unwrap()
+    };
+
+    if let Some(client) = &session.client {
+        BYTES_SERVED.with_label_values(&[client]).inc_by(bytes);
+    }
+    return session;
+}
+
+fn audit_transfer(
+    data_service: &Data<Mutex<DataService>>,
+    session: Session,
+    file_hash: String,
+    bytes: u64,
+    started: Instant,
+    detail: Option<String>,
+) {
+    let mut entry = AuditEntry::new(FILE_TRANSFER, session.client, session.peer);
+    entry.file_hash = Some(file_hash);
+    entry.bytes = Some(bytes as i64);
+    entry.duration_ms = Some(started.elapsed().as_millis() as i64);
+    entry.detail = detail;
+    data_service.lock().unwrap().add_audit_entry(entry);
+}
+
+fn handle_stream_msg(
+    msg: StreamMsg,
+    file_stream: &mut Option<FileStream>,
+    max_chunk_size: u64,
+    data_service: &Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+) -> Result<(), MessageError> {
+    debug!(?msg, "StreamMsg");
+    if let Some(active) = file_stream {
+        return Err(MessageError::InvalidRequest(
+            "stream_in_progress",
+            format!("The stream {} has to finish or be cancelled first", active.id),
+        ));
+    }
+    let scope = client_scope(data_service, sessions, session_id)?;
+    *file_stream = Some(FileStream::new(msg, max_chunk_size, scope));
+    Ok(())
+}
+
+fn handle_credit_msg(msg: CreditMsg, file_stream: &mut Option<FileStream>) -> Result<(), MessageError> {
+    debug!(?msg, "CreditMsg");
+    match file_stream {
+        Some(active) if active.id == msg.id => {
+            active.add_credit(msg.credit);
+            Ok(())
+        }
+        _ => Err(unknown_stream(msg.id)),
+    }
+}
+
+fn handle_cancel_msg(
+    msg: CancelMsg,
+    file_stream: &mut Option<FileStream>,
+    data_service: &Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+    websocket: &mut WebSocket<TcpStream>,
+) -> Result<(), MessageError> {
+    debug!(?msg, "CancelMsg");
+    let active = match file_stream.take() {
+        Some(active) if active.id == msg.id => active,
+        other => {
+            *file_stream = other;
+            return Err(unknown_stream(msg.id));
+        }
+    };
+
+    let session = {
+        let mut sessions = sessions.lock().unwrap();
+        sessions.set_current_transfer(session_id, None);
+        sessions.get(session_id).unwrap()
+    };
+    audit_transfer(data_service, session, active.file_hash, active.bytes, active.started, Some("cancelled".to_string()));
+
+    let cancel_res = CancelRes {
+        id: msg.id,
+        end: active.next_start,
+    };
+    send_json(websocket, &cancel_res)
+}
+
+fn handle_versions_msg<T: ProvideFile>(
+    msg: VersionsMsg,
+    file_service: &Data<Mutex<T>>,
+    data_service: &Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+    websocket: &mut WebSocket<TcpStream>,
+) -> Result<(), MessageError> {
+    debug!(?msg, "VersionsMsg");
+    let scope = client_scope(data_service, sessions, session_id)?;
+    let versions = file_service
         .lock()
         .unwrap()
-        .connection_status
-        .contains(&client_name)
+        .get_versions(&msg.path, &scope)
+        .map_err(MessageError::ReadFileError)?;
+    let versions_res = VersionsRes {
+        id: msg.id,
+        path: msg.path,
+        versions,
+    };
+    send_json(websocket, &versions_res)
+}
+
+fn handle_chunks_msg<T: ProvideFile>(
+    msg: ChunksMsg,
+    file_service: &Data<Mutex<T>>,
+    data_service: &Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+    websocket: &mut WebSocket<TcpStream>,
+) -> Result<(), MessageError> {
+    debug!(?msg, "ChunksMsg");
+    let scope = client_scope(data_service, sessions, session_id)?;
+    let chunks = file_service
+        .lock()
+        .unwrap()
+        .get_chunks(&msg.file_hash, &scope)
+        .map_err(MessageError::ReadFileError)?;
+    let chunks_res = ChunksRes {
+        id: msg.id,
+        file_hash: msg.file_hash,
+        chunks,
+    };
+    send_json(websocket, &chunks_res)
+}
+
+fn unknown_stream(id: i32) -> MessageError {
+    MessageError::InvalidRequest("unknown_stream", format!("There is no stream with id {}", id))
+}
+
+/** Send the next chunk of the active stream, ending the stream once it is complete
+or failed. Returns false when the connection is broken */
+fn pump_stream<T: ProvideFile>(
+    file_stream: &mut Option<FileStream>,
+    file_service: &Data<Mutex<T>>,
+    data_service: &Data<Mutex<DataService>>,
+    sessions: &Data<Mutex<SessionRegistry>>,
+    session_id: u64,
+    websocket: &mut WebSocket<TcpStream>,
+    throttle: &mut ConnectionThrottle,
+) -> bool {
+    let active = match file_stream {
+        Some(active) => active,
+        None => return true,
+    };
+    if throttle.remaining().is_some() {
+        return true;
+    }
+
+    let max_piece = throttle.max_piece(sessions, session_id);
+    let failure = match active.step(file_service, websocket, max_piece) {
+        Ok(Some(chunk)) => {
+            let session = record_chunk(sessions, session_id, &active.file_hash, chunk.bytes, chunk.last_data);
+            throttle.take(&session, chunk.bytes);
+            None
+        }
+        Ok(None) => None,
+        Err(failure) => Some(failure),
+    };
+
+    let (detail, connected) = match failure {
+        None if active.is_finished() => (None, true),
+        None => return true,
+        Some(StreamFailure::File(err)) => {
+            warn!(error = %err, code = err.code(), "Problems streaming the file");
+            let err_res = ErrRes {
+                id: active.id,
+                err: err.to_string(),
+                code: err.code().to_string(),
+            };
+            let connected = send_json(websocket, &err_res).is_ok();
+            (Some(err.code().to_string()), connected)
+        }
+        Some(StreamFailure::Socket(err)) => {
+            warn!(error = %err, "Problems sending the stream");
+            (Some("connection lost".to_string()), false)
+        }
+    };
+
+    let active = file_stream.take().unwrap();
+    let session = {
+        let mut sessions = sessions.lock().unwrap();
+        sessions.set_current_transfer(session_id, None);
+        sessions.get(session_id).unwrap()
+    };
+    audit_transfer(data_service, session, active.file_hash, active.bytes, active.started, detail);
+    return connected;
+}
+
+/** Answer to a message that failed, `None` when the connection can't go on */
+fn error_response(err: MessageError, id: i32) -> Option<ErrRes> {
+    match err {
+        // todo: close connection with websocket
+        MessageError::AuthError() => None,
+        MessageError::ConnectionError(err) => {
+            warn!(error = %err, "Problems sending the response");
+            None
+        }
+        MessageError::ReadFileError(err) => {
+            // the client can go on with other requests
+            warn!(error = %err, code = err.code(), "Problems handling the message");
+            Some(ErrRes {
+                err: err.to_string(),
+                code: err.code().to_string(),
+                id,
+            })
+        }
+        MessageError::InvalidRequest(code, err) => {
+            warn!(error = %err, code, "Problems handling the message");
+            Some(ErrRes {
+                err,
+                code: code.to_string(),
+                id,
+            })
+        }
+    }
+}
+
+fn user_is_auth(sessions: &Data<Mutex<SessionRegistry>>, session_id: u64) -> bool {
+    sessions.lock().unwrap().is_authenticated(session_id)
+}
+
+/** Send a close frame and wait for the client to acknowledge it */
+fn close_websocket(
+    websocket: &mut WebSocket<TcpStream>,
+    code: CloseCode,
+    reason: String,
+    close_timeout: Duration,
+) {
+    let frame = CloseFrame {
+        code,
+        reason: Cow::from(reason),
+    };
+    if websocket.close(Some(frame)).is_err() {
+        return;
+    }
+    let started = Instant::now();
+    while started.elapsed() < close_timeout {
+        match websocket.read() {
+            Ok(_) => continue,
+            Err(Error::Io(err)) if is_timeout(&err) => continue,
+            Err(_) => break,
+        }
+    }
+}
+
+fn is_timeout(err: &std::io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
 }
 
 pub fn start_websocket_server<T: ProvideFile + Sync + Send + 'static>(
     data_service_ins: Data<Mutex<DataService>>,
     file_service_ins: Data<Mutex<T>>,
-    port: i32,
-) {
-    let server = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-    println!("WebSocket running in port: {}", port);
+    options: WsOptions,
+) -> Result<WsServerHandle, String> {
+    let server = TcpListener::bind((options.bind.as_str(), options.port))
+        .map_err(|err| format!("Problems binding the WebSocket server to {}:{}: {}", options.bind, options.port, err))?;
+    let local_addr = server.local_addr()
+        .map_err(|err| format!("Problems reading the address of the WebSocket server: {}", err))?;
+    info!(bind = %options.bind, port = local_addr.port(), "WebSocket running");
+
+    let throttle_settings = data_service_ins.lock().unwrap().get_throttle_settings()?;
+    let mut throttle = Throttle::new(throttle_settings);
+    throttle.set_utc_offset(options.throttle_utc_offset);
+    let throttle_ins = Data::new(Mutex::new(throttle));
+    let auth_guard_ins = Data::new(Mutex::new(AuthGuard::new(options.auth_policy.clone())));
+
+    let sessions_ins = Data::new(Mutex::new(SessionRegistry::new()));
+    // taken before any scan can hold the file service
+    let scan_progress = file_service_ins.lock().unwrap().scan_progress();
+    // polling the listener lets the thread notice the shutdown
+    server.set_nonblocking(true)
+        .map_err(|err| format!("Problems configuring the WebSocket server: {}", err))?;
+    let state_ins = Data::new(WsServerState::new());
+    let handle = WsServerHandle {
+        sessions: Data::clone(&sessions_ins),
+        state: Data::clone(&state_ins),
+        throttle: Data::clone(&throttle_ins),
+        auth_guard: Data::clone(&auth_guard_ins),
+        close_timeout: options.close_timeout,
+        local_addr,
+    };
 
     spawn(move || {
-        for stream in server.incoming() {
+        let _accepting_guard = AcceptingGuard(Data::clone(&state_ins));
+        while !state_ins.shutting_down.load(Ordering::SeqCst) {
+            let stream = match server.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if is_timeout(&err) => {
+                    sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(err) => {
+                    warn!(error = %err, "Problems accepting a connection");
+                    continue;
+                }
+            };
             let data_service_ins_clone = data_service_ins.clone();
             let file_service_ins_clone = file_service_ins.clone();
+            let sessions_ins_clone = sessions_ins.clone();
+            let state_ins_clone = state_ins.clone();
+            let throttle_ins_clone = throttle_ins.clone();
+            let auth_guard_ins_clone = auth_guard_ins.clone();
+            let options = options.clone();
+            let scan_notices = ScanNotices {
+                progress: scan_progress.clone(),
+                interval: options.scan_notice_interval,
+            };
             spawn(move || {
-                let mut websocket = accept(stream.unwrap()).unwrap();
-                let mut client_name: Option<String> = None;
+                // some platforms pass the non blocking mode of the listener to the stream,
+                // a peer that doesn't finish the handshake in time is dropped
+                let configured = stream.set_nonblocking(false)
+                    .and_then(|_| stream.set_read_timeout(Some(options.auth_timeout)))
+                    .and_then(|_| stream.set_write_timeout(Some(options.ping_interval)));
+                if let Err(err) = configured {
+                    warn!(error = %err, "Problems configuring the connection");
+                    return;
+                }
+                let mut websocket = match accept(stream) {
+                    Ok(websocket) => websocket,
+                    Err(err) => {
+                        warn!(error = %err, "Problems with the WebSocket handshake");
+                        return;
+                    }
+                };
+                let peer = websocket.get_ref().peer_addr().map(|addr| addr.to_string()).ok();
+                let session_id = sessions_ins_clone.lock().unwrap().register(peer.clone());
+                ACTIVE_CONNECTIONS.inc();
+
+                let connection_span = info_span!(
+                    "connection",
+                    session_id,
+                    peer = peer.as_deref().unwrap_or("unknown"),
+                    client = field::Empty,
+                );
+                let _connection_guard = connection_span.enter();
+                // declared after entering the span, so it is dropped before leaving it
+                let _session_guard = SessionGuard {
+                    sessions: Data::clone(&sessions_ins_clone),
+                    session_id,
+                };
+                info!("Connection opened");
+                let mut going_away_sent = false;
+                let mut file_stream: Option<FileStream> = None;
+                let mut copy_transfer: Option<CopyTransfer> = None;
+                // copy requests arrived while the connection has to wait for the rate limits
+                let mut pending_copies: VecDeque<CopyMsg> = VecDeque::new();
+                let mut throttle = ConnectionThrottle {
+                    throttle: throttle_ins_clone,
+                    until: None,
+                };
+                let mut keepalive = Keepalive::new(
+                    options.ping_interval,
+                    options.idle_timeout,
+                    options.auth_timeout,
+                    Instant::now(),
+                );
                 loop {
-                    let msg = websocket.read().unwrap();
+                    if state_ins_clone.shutting_down.load(Ordering::SeqCst) {
+                        if !going_away_sent {
+                            going_away_sent = true;
+                            let notice = NoticeRes {
+                                id: 0,
+                                notice: NOTICE_GOING_AWAY.to_string(),
+                                detail: "The server is shutting down".to_string(),
+                                done: None,
+                                total: None,
+                            };
+                            let _ = send_json(&mut websocket, &notice);
+                        }
+                        let transferring = sessions_ins_clone
+                            .lock()
+                            .unwrap()
+                            .get(session_id)
+                            .map(|session| session.current_transfer.is_some())
+                            .unwrap_or(false);
+                        if !transferring || state_ins_clone.drain_expired() {
+                            close_websocket(
+                                &mut websocket,
+                                CloseCode::Away,
+                                "The server is shutting down".to_string(),
+                                options.close_timeout,
+                            );
+                            break;
+                        }
+                    }
+
+                    let close_request = sessions_ins_clone
+                        .lock()
+                        .unwrap()
+                        .take_close_request(session_id);
+                    if let Some(reason) = close_request {
+                        close_websocket(&mut websocket, CloseCode::Policy, reason, options.close_timeout);
+                        break;
+                    }
 
-                    if msg.is_close() {
-                        if client_name.is_some() {
-                            data_service_ins_clone
-                                .lock()
-                                .unwrap()
-                                .connection_status
-                                .remove(&client_name.clone().unwrap());
+                    let authenticated = sessions_ins_clone
+                        .lock()
+                        .unwrap()
+                        .get(session_id)
+                        .map(|session| session.client.is_some())
+                        .unwrap_or(false);
+                    // only data going out keeps the connection busy, a stream without credit
+                    // or an unfinished copy waits for the client, which can leave it idle
+                    let streaming = file_stream.as_ref().map(|active| active.is_active()).unwrap_or(false);
+                    let busy = streaming || !pending_copies.is_empty();
+                    match keepalive.next_action(authenticated, busy, Instant::now()) {
+                        KeepaliveAction::Nothing => {}
+                        KeepaliveAction::Ping => {
+                            if websocket.send(Message::Ping(Vec::new())).is_err() {
+                                break;
+                            }
+                        }
+                        KeepaliveAction::Close(code, reason) => {
+                            info!(reason, "Closing the connection");
+                            close_websocket(&mut websocket, code, reason.to_string(), options.close_timeout);
+                            break;
                         }
+                        KeepaliveAction::Dead => {
+                            warn!("The peer doesn't answer, dropping the connection");
+                            break;
+                        }
+                    }
+
+                    if throttle.remaining().is_none() {
+                        if let Some(msg) = pending_copies.pop_front() {
+                            let id = msg.id;
+                            let _message_guard = info_span!("message", message_id = id, message_type = "CopyMsg").entered();
+                            let copy_result = handle_copy_msg(
+                                msg,
+                                file_service_ins_clone.clone(),
+                                data_service_ins_clone.clone(),
+                                &sessions_ins_clone,
+                                session_id,
+                                &mut websocket,
+                                options.max_chunk_size,
+                                &mut throttle,
+                                &mut copy_transfer,
+                            );
+                            if let Err(err) = copy_result {
+                                match error_response(err, id) {
+                                    Some(err_res) if send_json(&mut websocket, &err_res).is_ok() => {}
+                                    _ => break,
+                                }
+                            }
+                        }
+                    }
+
+                    if !pump_stream(
+                        &mut file_stream,
+                        &file_service_ins_clone,
+                        &data_service_ins_clone,
+                        &sessions_ins_clone,
+                        session_id,
+                        &mut websocket,
+                        &mut throttle,
+                    ) {
                         break;
                     }
+                    // while data can go out only look for new messages in passing
+                    let sending = !pending_copies.is_empty()
+                        || file_stream.as_ref().map(|active| active.is_active()).unwrap_or(false);
+                    let read_timeout = match throttle.remaining() {
+                        Some(remaining) if sending => remaining.clamp(STREAM_POLL_INTERVAL, POLL_INTERVAL),
+                        _ if sending => STREAM_POLL_INTERVAL,
+                        _ => POLL_INTERVAL,
+                    };
+                    if websocket.get_ref().set_read_timeout(Some(read_timeout)).is_err() {
+                        break;
+                    }
+
+                    let msg = match websocket.read() {
+                        Ok(msg) => msg,
+                        Err(Error::Io(err)) if is_timeout(&err) => continue,
+                        Err(Error::ConnectionClosed) => break,
+                        Err(err) => {
+                            warn!(error = %err, "Problems reading from the connection");
+                            break;
+                        }
+                    };
+                    let now = Instant::now();
+                    keepalive.seen(now);
+
+                    let text = match msg {
+                        Message::Text(text) => text,
+                        Message::Close(_) => break,
+                        // the pings are answered by tungstenite, the pongs only tell the peer is alive
+                        Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+                        Message::Binary(_) => {
+                            keepalive.active(now);
+                            let err_res = ErrRes {
+                                id: 0,
+                                err: "Binary messages are not supported".to_string(),
+                                code: "invalid_message".to_string(),
+                            };
+                            if send_json(&mut websocket, &err_res).is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
+                    keepalive.active(now);
 
                     // handle message
-                    let msg_ins: Msg = serde_json::from_str(&*msg.to_string()).expect("some error");
+                    let msg_ins: Msg = match serde_json::from_str(&text) {
+                        Ok(msg_ins) => msg_ins,
+                        Err(err) => {
+                            warn!(error = %err, "Invalid message");
+                            let err_res = ErrRes {
+                                id: raw_message_id(&text),
+                                err: format!("Invalid message: {}", err),
+                                code: "invalid_message".to_string(),
+                            };
+                            if send_json(&mut websocket, &err_res).is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    };
 
                     let id;
+                    MESSAGES.with_label_values(&[msg_ins.type_name()]).inc();
+                    let message_span = info_span!(
+                        "message",
+                        message_id = msg_ins.id(),
+                        message_type = msg_ins.type_name(),
+                    );
+                    let _message_guard = message_span.enter();
                     let msg_result = match msg_ins {
                         Msg::AuthMsg(msg) => {
                             id = msg.id;
-                            client_name = Some(msg.name.clone());
-                            handle_auth_msg(msg, data_service_ins_clone.clone(), &mut websocket)
+                            let client = msg.name.clone();
+                            let auth_result = handle_auth_msg(
+                                msg,
+                                data_service_ins_clone.clone(),
+                                &sessions_ins_clone,
+                                session_id,
+                                &mut websocket,
+                                &auth_guard_ins_clone,
+                            );
+                            if auth_result.is_ok() {
+                                connection_span.record("client", client.as_str());
+                            }
+                            auth_result
                         }
                         Msg::TreeMsg(msg) => {
                             id = msg.id;
-                            if !user_is_auth(
-                                data_service_ins_clone.clone(),
-                                client_name.clone().unwrap(),
-                            ) {
+                            if !user_is_auth(&sessions_ins_clone, session_id) {
                                 Err(MessageError::AuthError())
                             } else {
-                                handle_tree_msg(msg, file_service_ins_clone.clone(), &mut websocket)
+                                handle_tree_msg(
+                                    msg,
+                                    file_service_ins_clone.clone(),
+                                    data_service_ins_clone.clone(),
+                                    &sessions_ins_clone,
+                                    session_id,
+                                    &scan_notices,
+                                    &mut websocket,
+                                )
                             }
                         }
                         Msg::CopyMsg(msg) => {
                             id = msg.id;
-                            if !user_is_auth(
-                                data_service_ins_clone.clone(),
-                                client_name.clone().unwrap(),
-                            ) {
+                            if !user_is_auth(&sessions_ins_clone, session_id) {
                                 Err(MessageError::AuthError())
+                            } else if msg.end.is_none() {
+                                // the rest of the file goes as a stream, which can be cancelled,
+                                // waits for the socket and lets the connection handle the rest
+                                let stream_msg = StreamMsg {
+                                    id: msg.id,
+                                    file_hash: msg.file_hash,
+                                    start: msg.start,
+                                    credit: u64::MAX,
+                                    chunk_size: None,
+                                };
+                                handle_stream_msg(
+                                    stream_msg,
+                                    &mut file_stream,
+                                    options.max_chunk_size,
+                                    &data_service_ins_clone,
+                                    &sessions_ins_clone,
+                                    session_id,
+                                )
+                            } else if throttle.remaining().is_some() || !pending_copies.is_empty() {
+                                // served in order once the connection can send again
+                                pending_copies.push_back(msg);
+                                Ok(())
                             } else {
-                                let handle_result = handle_copy_msg(
-                                    msg.clone(),
+                                handle_copy_msg(
+                                    msg,
                                     file_service_ins_clone.clone(),
+                                    data_service_ins_clone.clone(),
+                                    &sessions_ins_clone,
+                                    session_id,
                                     &mut websocket,
-                                );
-                                if handle_result.is_ok() {
-                                    Ok(())
-                                } else {
-                                    Err(MessageError::ReadFileError(
-                                        format!(
-                                            "Problems to find the file with hash: {}",
-                                            msg.file_hash.clone()
-                                        )
-                                            .to_string(),
-                                    ))
-                                }
+                                    options.max_chunk_size,
+                                    &mut throttle,
+                                    &mut copy_transfer,
+                                )
+                            }
+                        }
+                        Msg::StreamMsg(msg) => {
+                            id = msg.id;
+                            if !user_is_auth(&sessions_ins_clone, session_id) {
+                                Err(MessageError::AuthError())
+                            } else {
+                                handle_stream_msg(
+                                    msg,
+                                    &mut file_stream,
+                                    options.max_chunk_size,
+                                    &data_service_ins_clone,
+                                    &sessions_ins_clone,
+                                    session_id,
+                                )
+                            }
+                        }
+                        Msg::CreditMsg(msg) => {
+                            id = msg.id;
+                            handle_credit_msg(msg, &mut file_stream)
+                        }
+                        Msg::CancelMsg(msg) => {
+                            id = msg.id;
+                            handle_cancel_msg(
+                                msg,
+                                &mut file_stream,
+                                &data_service_ins_clone,
+                                &sessions_ins_clone,
+                                session_id,
+                                &mut websocket,
+                            )
+                        }
+                        Msg::VersionsMsg(msg) => {
+                            id = msg.id;
+                            if !user_is_auth(&sessions_ins_clone, session_id) {
+                                Err(MessageError::AuthError())
+                            } else {
+                                handle_versions_msg(
+                                    msg,
+                                    &file_service_ins_clone,
+                                    &data_service_ins_clone,
+                                    &sessions_ins_clone,
+                                    session_id,
+                                    &mut websocket,
+                                )
+                            }
+                        }
+                        Msg::ChunksMsg(msg) => {
+                            id = msg.id;
+                            if !user_is_auth(&sessions_ins_clone, session_id) {
+                                Err(MessageError::AuthError())
+                            } else {
+                                handle_chunks_msg(
+                                    msg,
+                                    &file_service_ins_clone,
+                                    &data_service_ins_clone,
+                                    &sessions_ins_clone,
+                                    session_id,
+                                    &mut websocket,
+                                )
                             }
                         }
                     };
 
                     // handle message analisis result
-                    match msg_result {
-                        Ok(_) => {
-                            data_service_ins_clone
-                                .lock()
-                                .unwrap()
-                                .connection_status
-                                .insert(client_name.clone().unwrap());
-                        }
-                        Err(error_type) => {
-                            match error_type {
-                                MessageError::AuthError() => {
-                                    // todo: close connection with websocket
-                                }
-                                MessageError::ReadFileError(err) => {
-                                    println!("Error: {}", err);
-                                    let err_res = ErrRes { err, id };
-                                    websocket
-                                        .send(Message::Text(
-                                            serde_json::to_string(&err_res).unwrap(),
-                                        ))
-                                        .unwrap();
-                                }
-                            }
-                            break;
-                        }
+                    let err_res = match msg_result {
+                        Ok(_) => continue,
+                        Err(err) => match error_response(err, id) {
+                            Some(err_res) => err_res,
+                            None => break,
+                        },
+                    };
+                    if send_json(&mut websocket, &err_res).is_err() {
+                        break;
                     }
                 }
+                audit_unfinished_copy(&mut copy_transfer, &data_service_ins_clone, &sessions_ins_clone, session_id);
+                let session = sessions_ins_clone.lock().unwrap().get(session_id);
+                if let (Some(active), Some(session)) = (file_stream.take(), session) {
+                    let detail = Some("connection closed".to_string());
+                    audit_transfer(&data_service_ins_clone, session, active.file_hash, active.bytes, active.started, detail);
+                }
             });
         }
     });
+
+    return Ok(handle);
 }
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// failed attempts are counted by the address of the peer
+pub const LOCKOUT_PEER: &str = "peer";
+/// failed attempts are counted by the client of the name in the `AuthMsg`
+pub const LOCKOUT_CLIENT: &str = "client";
+/// subject of the names in the `AuthMsg` that are not a client, they share the counter
+pub const UNKNOWN_CLIENT: &str = "unknown";
+/// peers and clients tracked at most, the ones without failures for longest are forgotten first
+pub const MAX_TRACKED_SUBJECTS: usize = 10000;
+
+/** Limits on failed authentication attempts */
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthPolicy {
+    /// failed attempts in a row that lock out the peer or the client
+    pub max_failures: u32,
+    /// wait after the first failed attempt, doubled after each following one
+    pub backoff: Duration,
+    /// how long a lockout lasts, the failures are forgotten after this time without new ones
+    pub lockout: Duration,
+}
+
+impl Default for AuthPolicy {
+    fn default() -> Self {
+        AuthPolicy {
+            max_failures: 5,
+            backoff: Duration::from_secs(1),
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/** Failed attempts in a row of one peer or client */
+struct FailureCounter {
+    failures: u32,
+    last_failure: Instant,
+    /// no new attempt is checked before then
+    retry_at: Instant,
+}
+
+/// the attempts turned away are audited at most once in this time for each subject
+pub const TURNED_AWAY_AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/** Attempts of one peer or client turned away without checking the key */
+struct TurnedAway {
+    /// attempts since the audited one
+    attempts: u64,
+    audited_at: Instant,
+}
+
+/** Counts the failed authentication attempts per peer address and per client,
+asking them to back off exponentially. Reaching the maximum failures is reported
+so the caller can record a lockout */
+pub struct AuthGuard {
+    pub policy: AuthPolicy,
+    counters: HashMap<(&'static str, String), FailureCounter>,
+    turned_away: HashMap<(&'static str, String), TurnedAway>,
+}
+
+/** Address of the peer without the port, connections from the same host share the counter */
+pub fn peer_host(peer: &str) -> String {
+    match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_string(),
+    }
+}
+
+/** Make room for a new subject, forgetting the one seen longest ago */
+fn make_room<T>(subjects: &mut HashMap<(&'static str, String), T>, seen_at: impl Fn(&T) -> Instant) {
+    if subjects.len() < MAX_TRACKED_SUBJECTS {
+        return;
+    }
+    let oldest = subjects.iter().min_by_key(|(_, value)| seen_at(value)).map(|(subject, _)| subject.clone());
+    if let Some(oldest) = oldest {
+        subjects.remove(&oldest);
+    }
+}
+
+impl AuthGuard {
+    pub fn new(policy: AuthPolicy) -> AuthGuard {
+        AuthGuard {
+            policy,
+            counters: HashMap::new(),
+            turned_away: HashMap::new(),
+        }
+    }
+
+    fn forget_expired(self: &mut AuthGuard, now: Instant) {
+        let lockout = self.policy.lockout;
+        self.counters.retain(|_, counter| now.saturating_duration_since(counter.last_failure) < lockout);
+    }
+
+    /** Subjects tracked, peers and clients */
+    pub fn tracked(self: &AuthGuard) -> usize {
+        self.counters.len().max(self.turned_away.len())
+    }
+
+    /** Time left before any of the subjects can try again */
+    pub fn backoff_remaining(
+        self: &mut AuthGuard,
+        subjects: &[(&'static str, String)],
+        now: Instant,
+    ) -> Option<Duration> {
+        self.forget_expired(now);
+        return subjects
+            .iter()
+            .filter_map(|subject| self.counters.get(subject))
+            .map(|counter| counter.retry_at.saturating_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+            .max();
+    }
+
+    /** Count a failed attempt, returns the subjects that reached the maximum
+    failures with their count. Their counters start over */
+    pub fn record_failure(
+        self: &mut AuthGuard,
+        subjects: &[(&'static str, String)],
+        now: Instant,
+    ) -> Vec<((&'static str, String), u32)> {
+        self.forget_expired(now);
+        let mut locked = Vec::new();
+        for subject in subjects {
+            if !self.counters.contains_key(subject) {
+                make_room(&mut self.counters, |counter| counter.last_failure);
+            }
+            let counter = self.counters.entry(subject.clone()).or_insert(FailureCounter {
+                failures: 0,
+                last_failure: now,
+                retry_at: now,
+            });
+            counter.failures += 1;
+            counter.last_failure = now;
+            let exponent = (counter.failures - 1).min(16);
+            counter.retry_at = now + self.policy.backoff.saturating_mul(1 << exponent).min(self.policy.lockout);
+
+            if counter.failures >= self.policy.max_failures {
+                locked.push((subject.clone(), counter.failures));
+                self.counters.remove(subject);
+            }
+        }
+        return locked;
+    }
+
+    /** Count an attempt turned away during a backoff or a lockout. When it has to be audited,
+    returns the attempts turned away since the last audited one, this one included. The
+    attempts of a subject audited less than `TURNED_AWAY_AUDIT_INTERVAL` ago are only counted */
+    pub fn turn_away(self: &mut AuthGuard, subjects: &[(&'static str, String)], now: Instant) -> Option<u64> {
+        // the counts of the last interval are kept for the next audited attempt
+        self.turned_away.retain(|_, turned_away| {
+            now.saturating_duration_since(turned_away.audited_at) < TURNED_AWAY_AUDIT_INTERVAL * 2
+        });
+        let is_recent = |turned_away: &TurnedAway| {
+            now.saturating_duration_since(turned_away.audited_at) < TURNED_AWAY_AUDIT_INTERVAL
+        };
+        if subjects.iter().any(|subject| self.turned_away.get(subject).map(is_recent).unwrap_or(false)) {
+            for subject in subjects {
+                if let Some(turned_away) = self.turned_away.get_mut(subject) {
+                    turned_away.attempts += 1;
+                }
+            }
+            return None;
+        }
+        let mut attempts = 1;
+        for subject in subjects {
+            if !self.turned_away.contains_key(subject) {
+                make_room(&mut self.turned_away, |turned_away| turned_away.audited_at);
+            }
+            let previous = self.turned_away.insert(subject.clone(), TurnedAway { attempts: 0, audited_at: now });
+            attempts = attempts.max(previous.map(|turned_away| turned_away.attempts + 1).unwrap_or(1));
+        }
+        return Some(attempts);
+    }
+
+    /** Forget the failures of the subjects, after a successful attempt or a lifted lockout */
+    pub fn reset(self: &mut AuthGuard, subjects: &[(&'static str, String)]) {
+        for subject in subjects {
+            self.counters.remove(subject);
+        }
+    }
+}
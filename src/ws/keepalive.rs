@@ -0,0 +1,79 @@
+use std::time::{Duration, Instant};
+
+use tungstenite::protocol::frame::coding::CloseCode;
+
+/** What the connection has to do to stay healthy */
+#[derive(Debug, PartialEq)]
+pub enum KeepaliveAction {
+    Nothing,
+    /// check the peer is still there
+    Ping,
+    /// close the connection with the given code and reason
+    Close(CloseCode, &'static str),
+    /// the peer didn't answer the last ping, the connection is dropped without a close handshake
+    Dead,
+}
+
+/** Timeouts of a connection, tracking when the peer was last heard of */
+pub struct Keepalive {
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    auth_timeout: Duration,
+    opened: Instant,
+    /// last frame of any kind received from the peer
+    last_seen: Instant,
+    /// last request of the client or data sent to it
+    last_activity: Instant,
+    /// ping waiting for an answer
+    ping_sent: Option<Instant>,
+}
+
+impl Keepalive {
+    pub fn new(ping_interval: Duration, idle_timeout: Duration, auth_timeout: Duration, now: Instant) -> Keepalive {
+        Keepalive {
+            ping_interval,
+            idle_timeout,
+            auth_timeout,
+            opened: now,
+            last_seen: now,
+            last_activity: now,
+            ping_sent: None,
+        }
+    }
+
+    /** A frame arrived, the peer is alive */
+    pub fn seen(self: &mut Keepalive, now: Instant) {
+        self.last_seen = now;
+        self.ping_sent = None;
+    }
+
+    /** The connection did something useful, it is not idle */
+    pub fn active(self: &mut Keepalive, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /** `busy` connections are sending data, they are not idle while waiting and the idle
+    time counts from the moment they stop */
+    pub fn next_action(self: &mut Keepalive, authenticated: bool, busy: bool, now: Instant) -> KeepaliveAction {
+        if busy {
+            self.last_activity = now;
+        }
+        if !authenticated && now.saturating_duration_since(self.opened) >= self.auth_timeout {
+            return KeepaliveAction::Close(CloseCode::Policy, "Authentication timeout");
+        }
+        if let Some(ping_sent) = self.ping_sent {
+            if now.saturating_duration_since(ping_sent) >= self.ping_interval {
+                return KeepaliveAction::Dead;
+            }
+            return KeepaliveAction::Nothing;
+        }
+        if !busy && now.saturating_duration_since(self.last_activity) >= self.idle_timeout {
+            return KeepaliveAction::Close(CloseCode::Normal, "Idle timeout");
+        }
+        if now.saturating_duration_since(self.last_seen) >= self.ping_interval {
+            self.ping_sent = Some(now);
+            return KeepaliveAction::Ping;
+        }
+        return KeepaliveAction::Nothing;
+    }
+}
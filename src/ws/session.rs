@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::api::LimitPolicy;
+use crate::data::now_secs;
+use crate::file::hash::HashAlgorithm;
+
+/** Live WebSocket connection as exposed by the admin API */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Session {
+    pub id: u64,
+    pub client: Option<String>,
+    /// id of the client, the rate limits of the client follow it
+    #[serde(default)]
+    pub client_id: Option<i64>,
+    pub peer: Option<String>,
+    /// unix time in seconds
+    pub connected_at: i64,
+    /// file bytes served through this connection
+    pub bytes_sent: u64,
+    /// hash of the file being copied, until its last chunk is sent
+    pub current_transfer: Option<String>,
+    /// hash algorithms agreed with the client when authenticating
+    #[serde(default)]
+    pub hash_algorithms: Vec<HashAlgorithm>,
+}
+
+struct SessionEntry {
+    session: Session,
+    close_request: Option<String>,
+    /// a close was requested, the session no longer counts for the client limits
+    closing: bool,
+}
+
+/** Result of authenticating a session against the client connection limit */
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthOutcome {
+    Accepted,
+    /// accepted after asking the given (oldest) session of the client to close
+    AcceptedEvicting(u64),
+    LimitExceeded,
+}
+
+/** Registry of the connections currently handled by the WebSocket server */
+pub struct SessionRegistry {
+    next_id: u64,
+    sessions: HashMap<u64, SessionEntry>,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionRegistry {
+    pub fn new() -> SessionRegistry {
+        SessionRegistry {
+            next_id: 1,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn register(self: &mut SessionRegistry, peer: Option<String>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(id, SessionEntry {
+            session: Session {
+                id,
+                client: None,
+                client_id: None,
+                peer,
+                connected_at: now_secs(),
+                bytes_sent: 0,
+                current_transfer: None,
+                hash_algorithms: Vec::new(),
+            },
+            close_request: None,
+            closing: false,
+        });
+        return id;
+    }
+
+    pub fn unregister(self: &mut SessionRegistry, id: u64) -> Option<Session> {
+        self.sessions.remove(&id).map(|entry| entry.session)
+    }
+
+    pub fn get(self: &SessionRegistry, id: u64) -> Option<Session> {
+        self.sessions.get(&id).map(|entry| entry.session.clone())
+    }
+
+    /** Sessions ordered by connection time */
+    pub fn list(self: &SessionRegistry) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.values()
+            .map(|entry| entry.session.clone())
+            .collect();
+        sessions.sort_by_key(|session| (session.connected_at, session.id));
+        return sessions;
+    }
+
+    /** Whether the session has been authenticated by a client */
+    pub fn is_authenticated(self: &SessionRegistry, id: u64) -> bool {
+        self.sessions
+            .get(&id)
+            .map(|entry| entry.session.client.is_some() && !entry.closing)
+            .unwrap_or(false)
+    }
+
+    /** Open sessions authenticated by the client, oldest first */
+    pub fn client_sessions(self: &SessionRegistry, client: &str) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.values()
+            .filter(|entry| !entry.closing && entry.session.client.as_deref() == Some(client))
+            .map(|entry| entry.session.clone())
+            .collect();
+        sessions.sort_by_key(|session| (session.connected_at, session.id));
+        return sessions;
+    }
+
+    /** Mark the session as authenticated by the client, enforcing its connection limit */
+    pub fn authenticate(
+        self: &mut SessionRegistry,
+        id: u64,
+        client: String,
+        client_id: Option<i64>,
+        max_connections: Option<i64>,
+        policy: LimitPolicy,
+    ) -> AuthOutcome {
+        let others: Vec<Session> = self.client_sessions(&client)
+            .into_iter()
+            .filter(|session| session.id != id)
+            .collect();
+
+        let mut outcome = AuthOutcome::Accepted;
+        if let Some(max) = max_connections.filter(|max| *max > 0) {
+            if others.len() as i64 >= max {
+                match policy {
+                    LimitPolicy::Reject => return AuthOutcome::LimitExceeded,
+                    LimitPolicy::EvictOldest => {
+                        let oldest = others[0].id;
+                        self.request_close(oldest, "Evicted by a newer connection of the client".to_string());
+                        outcome = AuthOutcome::AcceptedEvicting(oldest);
+                    }
+                }
+            }
+        }
+
+        if let Some(entry) = self.sessions.get_mut(&id) {
+            entry.session.client = Some(client);
+            entry.session.client_id = client_id;
+        }
+        return outcome;
+    }
+
+    /** Forget the client of the session, it has to authenticate again */
+    pub fn deauthenticate(self: &mut SessionRegistry, id: u64) {
+        if let Some(entry) = self.sessions.get_mut(&id) {
+            entry.session.client = None;
+            entry.session.client_id = None;
+        }
+    }
+
+    pub fn add_bytes_sent(self: &mut SessionRegistry, id: u64, bytes: u64) {
+        if let Some(entry) = self.sessions.get_mut(&id) {
+            entry.session.bytes_sent += bytes;
+        }
+    }
+
+    pub fn set_hash_algorithms(self: &mut SessionRegistry, id: u64, hash_algorithms: Vec<HashAlgorithm>) {
+        if let Some(entry) = self.sessions.get_mut(&id) {
+            entry.session.hash_algorithms = hash_algorithms;
+        }
+    }
+
+    pub fn set_current_transfer(self: &mut SessionRegistry, id: u64, file_hash: Option<String>) {
+        if let Some(entry) = self.sessions.get_mut(&id) {
+            entry.session.current_transfer = file_hash;
+        }
+    }
+
+    /** Ask the connection thread to close the socket, returns the session if it exists */
+    pub fn request_close(self: &mut SessionRegistry, id: u64, reason: String) -> Option<Session> {
+        let entry = self.sessions.get_mut(&id)?;
+        entry.close_request = Some(reason);
+        entry.closing = true;
+        return Some(entry.session.clone());
+    }
+
+    /** Pending close request of the session, polled by its connection thread */
+    pub fn take_close_request(self: &mut SessionRegistry, id: u64) -> Option<String> {
+        self.sessions.get_mut(&id).and_then(|entry| entry.close_request.take())
+    }
+}
@@ -0,0 +1,146 @@
+use std::cmp::min;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use tungstenite::{Error, Message, WebSocket};
+
+use crate::file::ignore::ClientScope;
+use crate::file::{read_file_data, FileError, ProvideFile};
+use crate::ws::ws_message::{CopyRes, StreamMsg};
+
+/// How long a send can block before the socket is considered backed up.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(200);
+
+/** Chunk of a stream handed to the socket */
+pub struct SentChunk {
+    pub bytes: u64,
+    pub last_data: bool,
+}
+
+/** Why a stream can't go on */
+pub enum StreamFailure {
+    File(FileError),
+    /// the connection is broken
+    Socket(String),
+}
+
+/** File being sent to the client chunk after chunk, as long as the client grants credit */
+pub struct FileStream {
+    pub id: i32,
+    pub file_hash: String,
+    /// start of the next chunk to send
+    pub next_start: u64,
+    chunk_size: u64,
+    /// chunks the server can still send before waiting for a `CreditMsg`
+    credit: u64,
+    /// the last chunk didn't fit in the socket buffer yet, nothing is read from disk until it does
+    pending_flush: bool,
+    /// the last chunk was sent
+    done: bool,
+    pub started: Instant,
+    pub bytes: u64,
+    /// what the client could see when the stream started
+    scope: ClientScope,
+}
+
+fn is_timeout(err: &Error) -> bool {
+    matches!(err, Error::Io(err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut)
+}
+
+impl FileStream {
+    pub fn new(msg: StreamMsg, max_chunk_size: u64, scope: ClientScope) -> FileStream {
+        FileStream {
+            id: msg.id,
+            file_hash: msg.file_hash,
+            next_start: msg.start,
+            chunk_size: min(msg.chunk_size.unwrap_or(max_chunk_size), max_chunk_size).max(1),
+            credit: msg.credit,
+            pending_flush: false,
+            done: false,
+            started: Instant::now(),
+            bytes: 0,
+            scope,
+        }
+    }
+
+    pub fn add_credit(self: &mut FileStream, credit: u64) {
+        self.credit = self.credit.saturating_add(credit);
+    }
+
+    /** Whether the stream can make progress without hearing from the client */
+    pub fn is_active(self: &FileStream) -> bool {
+        self.pending_flush || (self.credit > 0 && !self.done)
+    }
+
+    /** Whether the last chunk was sent and flushed */
+    pub fn is_finished(self: &FileStream) -> bool {
+        self.done && !self.pending_flush
+    }
+
+    /** Send the next chunk if there is credit and the socket is not backed up. The chunk
+    is cut to `max_bytes`, when given, the rest goes in the next chunks */
+    pub fn step<T: ProvideFile>(
+        self: &mut FileStream,
+        file_service: &Data<Mutex<T>>,
+        websocket: &mut WebSocket<TcpStream>,
+        max_bytes: Option<u64>,
+    ) -> Result<Option<SentChunk>, StreamFailure> {
+        let socket_error = |err: std::io::Error| StreamFailure::Socket(err.to_string());
+        let write_timeout = websocket.get_ref().write_timeout().map_err(socket_error)?;
+        websocket.get_ref().set_write_timeout(Some(WRITE_TIMEOUT)).map_err(socket_error)?;
+        let result = self.send_chunk(file_service, websocket, max_bytes);
+        websocket.get_ref().set_write_timeout(write_timeout).map_err(socket_error)?;
+        return result;
+    }
+
+    fn send_chunk<T: ProvideFile>(
+        self: &mut FileStream,
+        file_service: &Data<Mutex<T>>,
+        websocket: &mut WebSocket<TcpStream>,
+        max_bytes: Option<u64>,
+    ) -> Result<Option<SentChunk>, StreamFailure> {
+        if self.pending_flush {
+            match websocket.flush() {
+                Ok(()) => self.pending_flush = false,
+                Err(err) if is_timeout(&err) => return Ok(None),
+                Err(err) => return Err(StreamFailure::Socket(err.to_string())),
+            }
+        }
+        if self.done || self.credit == 0 {
+            return Ok(None);
+        }
+
+        let start = self.next_start;
+        let chunk_size = max_bytes.map(|max_bytes| min(self.chunk_size, max_bytes)).unwrap_or(self.chunk_size);
+        let end = start.saturating_add(chunk_size);
+        let data_res = read_file_data(file_service, start, end, self.file_hash.clone(), &self.scope)
+            .map_err(StreamFailure::File)?;
+        let bytes = data_res.end.saturating_sub(start);
+        // an empty chunk before the end means the file got shorter
+        let last_data = data_res.last_data || bytes == 0;
+
+        let copy_res = CopyRes {
+            id: self.id,
+            start,
+            end: data_res.end,
+            data: data_res.data,
+            last_data,
+        };
+        let text = serde_json::to_string(&copy_res).unwrap();
+        match websocket.send(Message::Text(text)) {
+            Ok(()) => {}
+            // the chunk stays queued in the websocket, it is flushed in the next steps
+            Err(err) if is_timeout(&err) => self.pending_flush = true,
+            Err(err) => return Err(StreamFailure::Socket(err.to_string())),
+        }
+
+        self.credit -= 1;
+        self.next_start = data_res.end;
+        self.bytes += bytes;
+        self.done = last_data;
+        Ok(Some(SentChunk { bytes, last_data }))
+    }
+}
@@ -0,0 +1,113 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{connect, Message, WebSocket};
+
+use crate::api::api::Client;
+use crate::data::DataService;
+use crate::file::ProvideFile;
+use crate::ws::ws_message::AuthRes;
+use crate::ws::{start_websocket_server, WsOptions, WsServerHandle};
+
+pub type TestSocket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/** WebSocket server for the tests, on a port picked by the system and with its own
+in memory database, so the tests can run in parallel. The server shuts down when
+the harness is dropped */
+pub struct TestServer<T: ProvideFile + Sync + Send + 'static> {
+    pub data: Data<Mutex<DataService>>,
+    pub files: Data<Mutex<T>>,
+    pub handle: WsServerHandle,
+}
+
+impl<T: ProvideFile + Sync + Send + 'static> TestServer<T> {
+    pub fn start(provider: T) -> Result<TestServer<T>, String> {
+        TestServer::start_with(provider, WsOptions::default())
+    }
+
+    /** Start with the options, the address is always the loopback on a free port */
+    pub fn start_with(provider: T, options: WsOptions) -> Result<TestServer<T>, String> {
+        let data = Data::new(Mutex::new(DataService::open(":memory:".to_string(), 0)?));
+        let files = Data::new(Mutex::new(provider));
+        let handle = start_websocket_server(
+            Data::clone(&data),
+            Data::clone(&files),
+            WsOptions {
+                bind: "127.0.0.1".to_string(),
+                port: 0,
+                ..options
+            },
+        )?;
+        return Ok(TestServer { data, files, handle });
+    }
+
+    pub fn addr(self: &TestServer<T>) -> SocketAddr {
+        self.handle.local_addr()
+    }
+
+    pub fn url(self: &TestServer<T>) -> String {
+        format!("ws://{}/websocket", self.addr())
+    }
+
+    /** Create the client, or get the existing one, and return its key */
+    pub fn add_client(self: &TestServer<T>, name: &str) -> String {
+        let client = self.data.lock().unwrap().new_client(Client {
+            id: None,
+            key: None,
+            name: Some(name.to_string()),
+            max_connections: None,
+            limit_policy: None,
+        });
+        return client.key.unwrap();
+    }
+
+    /** Open a connection and authenticate, the answer tells whether it was accepted */
+    pub fn connect(self: &TestServer<T>, name: &str, key: &str) -> Result<(TestSocket, AuthRes), String> {
+        let (mut socket, _) = connect(self.url()).map_err(|err| format!("Problems connecting: {}", err))?;
+        let auth_msg = serde_json::json!({"type": "AuthMsg", "id": 1, "name": name, "key": key});
+        socket
+            .send(Message::Text(auth_msg.to_string()))
+            .map_err(|err| format!("Problems authenticating: {}", err))?;
+        let msg = socket.read().map_err(|err| format!("Problems authenticating: {}", err))?;
+        let auth_res = serde_json::from_str(&msg.to_string())
+            .map_err(|err| format!("Unexpected answer to the authentication: {}", err))?;
+        return Ok((socket, auth_res));
+    }
+
+    /** Open a connection authenticated as the client, any other answer is an error */
+    pub fn connect_accepted(self: &TestServer<T>, name: &str, key: &str) -> Result<TestSocket, String> {
+        let (socket, auth_res) = self.connect(name, key)?;
+        if auth_res.status != "accepted" {
+            return Err(format!("The authentication of {} was {}", name, auth_res.status));
+        }
+        return Ok(socket);
+    }
+}
+
+/** Read up to the close frame of the server, skipping its pings, and acknowledge it.
+Returns the close code, any other message is an error */
+pub fn read_close(socket: &mut TestSocket) -> Result<CloseCode, String> {
+    loop {
+        match socket.read().map_err(|err| format!("Problems reading the close frame: {}", err))? {
+            Message::Close(Some(frame)) => {
+                // reading again flushes the acknowledgement, then the connection is over
+                if socket.read().is_ok() {
+                    return Err("The connection goes on after the close frame".to_string());
+                }
+                return Ok(frame.code);
+            }
+            Message::Ping(_) => continue,
+            msg => return Err(format!("Expected a close frame, got {:?}", msg)),
+        }
+    }
+}
+
+impl<T: ProvideFile + Sync + Send + 'static> Drop for TestServer<T> {
+    fn drop(&mut self) {
+        self.handle.shutdown(Duration::ZERO);
+    }
+}
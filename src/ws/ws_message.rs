@@ -1,17 +1,40 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
 // AUTH MESSAGE
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct AuthMsg {
     pub id: i32,
     pub name: String,
     pub key: String,
+    /// hash algorithms the client knows, in its order of preference, only sha256 when missing
+    #[serde(default)]
+    pub hash_algorithms: Option<Vec<String>>,
+}
+
+impl fmt::Debug for AuthMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthMsg")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("key", &format_args!("<redacted>"))
+            .field("hash_algorithms", &self.hash_algorithms)
+            .finish()
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct AuthRes {
     pub id: i32,
+    /// `accepted`, `denied`, `limit_exceeded`, `retry_later` or `locked_out`
     pub status: String,
+    /// seconds to wait before trying again, with `retry_later` and `locked_out`
+    #[serde(default)]
+    pub retry_after: Option<u64>,
+    /// hash algorithms the server uses with the client once accepted, out of the offered ones
+    #[serde(default)]
+    pub hash_algorithms: Option<Vec<String>>,
 }
 // AUTH MESSAGE
 
@@ -21,6 +44,22 @@ pub struct TreeMsg {
     pub id: i32,
 }
 
+/** Attributes of an entry of the tree, the mode and the owner are only known on unix */
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    /// modification time, in nanoseconds since the unix epoch
+    #[serde(default)]
+    pub mtime_ns: i64,
+    /// permission bits, like `0o755`
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// owner user and group ids, only when the server is configured to send them
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct File {
     pub name: String,
@@ -28,6 +67,30 @@ pub struct File {
     pub path: Option<String>,
     pub hash: String,
     pub size: u64,
+    #[serde(flatten)]
+    pub attributes: Attributes,
+}
+
+/** Symbolic link, reported as is without following it */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Symlink {
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub path: Option<String>,
+    /// path the link points to, as stored in the link
+    pub target: String,
+    #[serde(flatten)]
+    pub attributes: Attributes,
+}
+
+/** Entry of a directory that can't be served, the rest of the tree still is */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct EntryError {
+    /// empty when the entry can't even be named
+    pub name: String,
+    pub err: String,
+    /// stable identifier of the error, like `io_error` or `symlink_loop`
+    pub code: String,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -37,6 +100,12 @@ pub struct Directory {
     pub path: Option<String>,
     pub dirs: Option<Vec<Directory>>,
     pub files: Option<Vec<File>>,
+    #[serde(default)]
+    pub links: Option<Vec<Symlink>>,
+    #[serde(default)]
+    pub errors: Option<Vec<EntryError>>,
+    #[serde(flatten)]
+    pub attributes: Attributes,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -51,13 +120,17 @@ pub struct TreeRes {
 pub struct CopyMsg {
     pub id: i32,
     pub start: u64,
-    pub end: u64,
+    /// without an end the rest of the file is sent like a `StreamMsg` with unlimited
+    /// credit, in `CopyRes` chunks up to the maximum chunk size, it can be cancelled
+    #[serde(default)]
+    pub end: Option<u64>,
     pub file_hash: String,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct CopyRes {
     pub id: i32,
+    /// effective range of the data, the end can be lower than the requested one
     pub start: u64,
     pub end: u64,
     pub data: String,
@@ -65,18 +138,168 @@ pub struct CopyRes {
 }
 // COPY FILE
 
+// STREAM FILE
+/** Ask for a file as a stream of `CopyRes` chunks starting at `start`. The server
+sends up to `credit` chunks and waits for a `CreditMsg` to send more */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct StreamMsg {
+    pub id: i32,
+    pub file_hash: String,
+    #[serde(default)]
+    pub start: u64,
+    pub credit: u64,
+    /// bytes per chunk, capped to the maximum chunk size of the server
+    #[serde(default)]
+    pub chunk_size: Option<u64>,
+}
+
+/** Allow the server to send more chunks of the stream with the given id */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CreditMsg {
+    pub id: i32,
+    pub credit: u64,
+}
+
+/** Stop the stream with the given id */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CancelMsg {
+    pub id: i32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CancelRes {
+    pub id: i32,
+    /// end of the data sent before the stream stopped
+    pub end: u64,
+}
+// STREAM FILE
+
+// FILE VERSIONS
+/** Ask for the versions the server kept of a file */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct VersionsMsg {
+    pub id: i32,
+    /// path of the file in the tree, like `docs/a.txt`, the shares under their name
+    pub path: String,
+}
+
+/** Content a file had, any version can be copied by its hash */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct FileVersion {
+    pub hash: String,
+    pub size: u64,
+    /// modification time of the file, in nanoseconds since the unix epoch
+    pub mtime_ns: i64,
+    /// seconds since the unix epoch when the server stored the version
+    pub stored_at: i64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct VersionsRes {
+    pub id: i32,
+    pub path: String,
+    /// the oldest first, empty when the server doesn't keep versions
+    pub versions: Vec<FileVersion>,
+}
+// FILE VERSIONS
+
+// FILE CHUNKS
+/** Ask for the chunks of a file, to copy only the ones the client doesn't have */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ChunksMsg {
+    pub id: i32,
+    pub file_hash: String,
+}
+
+/** Part of a file, stored once whatever the files it is in. It can be copied by its hash */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Chunk {
+    /// tagged like `chunk:<blake3 hex>`
+    pub hash: String,
+    /// position of the chunk in the file
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ChunksRes {
+    pub id: i32,
+    pub file_hash: String,
+    /// in the order of the file, empty when the server doesn't store the file in chunks
+    pub chunks: Vec<Chunk>,
+}
+// FILE CHUNKS
+
 // ERROR MESSAGE
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ErrRes {
     pub id: i32,
     pub err: String,
+    /// stable identifier of the error, like `file_not_found` or `invalid_range`
+    #[serde(default)]
+    pub code: String,
 }
 // ERROR MESSAGE
 
+// SERVER NOTICE
+/// the server is shutting down, the current transfer can be finished
+pub const NOTICE_GOING_AWAY: &str = "going_away";
+/// the files are being hashed, the response to the request comes after the scan
+pub const NOTICE_SCAN_PROGRESS: &str = "scan_progress";
+
+/** Message the server sends on its own, not as the response of a request */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct NoticeRes {
+    /// the request the notice is about, 0 when there is none
+    pub id: i32,
+    pub notice: String,
+    pub detail: String,
+    /// files hashed so far, for the scan progress
+    #[serde(default)]
+    pub done: Option<u64>,
+    /// files found so far, for the scan progress
+    #[serde(default)]
+    pub total: Option<u64>,
+}
+// SERVER NOTICE
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(tag = "type")]
 pub enum Message {
     AuthMsg(AuthMsg),
     TreeMsg(TreeMsg),
     CopyMsg(CopyMsg),
+    StreamMsg(StreamMsg),
+    CreditMsg(CreditMsg),
+    CancelMsg(CancelMsg),
+    VersionsMsg(VersionsMsg),
+    ChunksMsg(ChunksMsg),
+}
+
+impl Message {
+    pub fn id(self: &Message) -> i32 {
+        match self {
+            Message::AuthMsg(msg) => msg.id,
+            Message::TreeMsg(msg) => msg.id,
+            Message::CopyMsg(msg) => msg.id,
+            Message::StreamMsg(msg) => msg.id,
+            Message::CreditMsg(msg) => msg.id,
+            Message::CancelMsg(msg) => msg.id,
+            Message::VersionsMsg(msg) => msg.id,
+            Message::ChunksMsg(msg) => msg.id,
+        }
+    }
+
+    pub fn type_name(self: &Message) -> &'static str {
+        match self {
+            Message::AuthMsg(_) => "AuthMsg",
+            Message::TreeMsg(_) => "TreeMsg",
+            Message::CopyMsg(_) => "CopyMsg",
+            Message::StreamMsg(_) => "StreamMsg",
+            Message::CreditMsg(_) => "CreditMsg",
+            Message::CancelMsg(_) => "CancelMsg",
+            Message::VersionsMsg(_) => "VersionsMsg",
+            Message::ChunksMsg(_) => "ChunksMsg",
+        }
+    }
 }
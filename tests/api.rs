@@ -1,12 +1,28 @@
+use std::sync::mpsc::channel;
 use std::sync::{Mutex, Once};
+use std::thread::spawn;
 
-use actix_web::{web::Data, test, App};
+use actix_web::{http::StatusCode, web::{self, Data}, test, App};
 use lazy_static::lazy_static;
 
-use test_utils::{current_dir_path, setting_up_test_file_tree};
-use cs::api::api::{Client, client_api_endpoint, create_client_endpoint, delete_client_endpoint};
+use test_utils::{current_dir_path, gen_msg_id, setting_up_test_file_tree};
+use cs::api::api::{
+    Client, client_api_endpoint, client_keys_endpoint, create_client_endpoint, create_client_key_endpoint,
+    delete_client_endpoint, revoke_client_key_endpoint,
+};
+use cs::api::audit::{audit_api_endpoint, audit_csv_endpoint};
+use cs::api::health::{healthz_endpoint, readyz_endpoint, HealthReport, STATUS_BUSY, STATUS_FAIL, STATUS_OK};
+use cs::api::ignore_rules::{client_ignore_endpoint, remove_client_ignore_endpoint, set_client_ignore_endpoint};
+use cs::api::metrics::metrics_endpoint;
+use cs::api::throttle::{
+    remove_client_throttle_endpoint, set_client_throttle_endpoint, set_global_throttle_endpoint, throttle_api_endpoint,
+};
+use cs::data::audit::{AuditEntry, AUTH_FAILURE, FILE_TRANSFER};
 use cs::data::DataService;
 use cs::file::FileService;
+use cs::metrics::AUTH_ATTEMPTS;
+use cs::throttle::{Throttle, ThrottleSettings};
+use cs::ws::WsServerState;
 
 static BEFORE_ALL: Once = Once::new();
 
@@ -33,6 +49,8 @@ fn create_mock_clients(clients: Vec<String>) {
             id: None,
             key: None,
             name: Some(name),
+            max_connections: None,
+            limit_policy: None,
         });
     }
 }
@@ -63,7 +81,7 @@ async fn list_of_clients_request_test() {
         }
     }
 
-    assert!(clients_resp.len() > 0);
+    assert!(!clients_resp.is_empty());
     assert!(found);
 }
 
@@ -82,6 +100,8 @@ async fn create_new_client_test() {
         id: None,
         key: None,
         name: Some(new_cli_name.clone()),
+        max_connections: None,
+        limit_policy: None,
     };
 
     let req = test::TestRequest::post()
@@ -125,3 +145,274 @@ async fn delete_client_request_test() {
     let cli = DATA_INS.lock().unwrap().get_client_by_name(to_remove_cli_name);
     assert!(cli.is_none());
 }
+
+#[test]
+async fn audit_filter_and_export_test() {
+    before_all();
+
+    let client_name = format!("audit_filter_test_{}", gen_msg_id());
+    {
+        let data_service = DATA_INS.lock().unwrap();
+        data_service.add_audit_entry(AuditEntry::new(
+            AUTH_FAILURE, Some(client_name.clone()), Some("127.0.0.1:1000".to_string()),
+        ));
+        for _ in 0..3 {
+            let mut entry = AuditEntry::new(FILE_TRANSFER, Some(client_name.clone()), None);
+            entry.file_hash = Some("some,hash".to_string());
+            entry.bytes = Some(10);
+            data_service.add_audit_entry(entry);
+        }
+    }
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::clone(&DATA_INS))
+            .service(audit_api_endpoint)
+            .service(audit_csv_endpoint)
+    ).await;
+
+    let req = test::TestRequest::get()
+        .uri(format!("/api/audit?client={}&event={}&limit=2", client_name, FILE_TRANSFER).as_str())
+        .to_request();
+    let entries: Vec<AuditEntry> = test::call_and_read_body_json(&app, req).await;
+
+    assert_eq!(entries.len(), 2);
+    for entry in entries {
+        assert_eq!(entry.event, FILE_TRANSFER);
+        assert_eq!(entry.client.unwrap(), client_name);
+    }
+
+    let req = test::TestRequest::get()
+        .uri(format!("/api/audit/csv?client={}", client_name).as_str())
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let csv = String::from_utf8(body.to_vec()).unwrap();
+
+    assert!(csv.starts_with("id,time,event,client,peer,file_hash,bytes,duration_ms,detail\n"));
+    assert_eq!(csv.lines().count(), 5);
+    assert!(csv.contains("\"some,hash\""));
+}
+
+#[test]
+async fn metrics_endpoint_test() {
+    AUTH_ATTEMPTS.with_label_values(&["denied"]).inc();
+
+    let app = test::init_service(
+        App::new().service(metrics_endpoint)
+    ).await;
+
+    let req = test::TestRequest::get().uri("/metrics").to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+
+    assert!(metrics.contains("copy_service_auth_attempts_total{result=\"denied\"}"));
+    // the other tests of the binary can have connections open, only the gauge is checked
+    assert!(metrics.lines().any(|line| line.starts_with("copy_service_ws_active_connections ")));
+    assert!(metrics.contains("copy_service_tree_scan_seconds_count"));
+}
+
+#[test]
+async fn health_and_readiness_test() {
+    before_all();
+
+    let ws_state = Data::new(WsServerState::new());
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::clone(&DATA_INS))
+            .app_data(Data::clone(&FILE_INS))
+            .app_data(Data::clone(&ws_state))
+            .service(healthz_endpoint)
+            .service(web::resource("/readyz").route(web::get().to(readyz_endpoint::<FileService>)))
+    ).await;
+
+    let req = test::TestRequest::get().uri("/healthz").to_request();
+    let report: HealthReport = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(report.status, STATUS_OK);
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let report: HealthReport = test::read_body_json(res).await;
+    assert_eq!(report.status, STATUS_OK);
+    assert_eq!(report.checks.len(), 3);
+
+    // a scan holding the file service doesn't make the probe wait
+    let (locked_tx, locked_rx) = channel();
+    let (release_tx, release_rx) = channel::<()>();
+    let file_ins = Data::clone(&FILE_INS);
+    let scan = spawn(move || {
+        let _file_service = file_ins.lock().unwrap();
+        locked_tx.send(()).unwrap();
+        release_rx.recv().unwrap();
+    });
+    locked_rx.recv().unwrap();
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let res = test::call_service(&app, req).await;
+    release_tx.send(()).unwrap();
+    scan.join().unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let report: HealthReport = test::read_body_json(res).await;
+    assert_eq!(report.checks["data_root"].status, STATUS_BUSY);
+
+    // the listener thread stopped
+    ws_state.accepting.store(false, std::sync::atomic::Ordering::SeqCst);
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report: HealthReport = test::read_body_json(res).await;
+    assert_eq!(report.status, STATUS_FAIL);
+    assert_eq!(report.checks["websocket"].status, STATUS_FAIL);
+    assert_eq!(report.checks["database"].status, STATUS_OK);
+    assert_eq!(report.checks["data_root"].status, STATUS_OK);
+}
+
+#[test]
+async fn throttle_api_test() {
+    // own database, the limits must not slow down the other tests
+    let data_ins = Data::new(Mutex::new(DataService::open(":memory:".to_string(), 0).unwrap()));
+    let throttle_ins = Data::new(Mutex::new(Throttle::default()));
+    let client = data_ins.lock().unwrap().new_client(Client {
+        id: None,
+        key: None,
+        name: Some("throttle_api_test".to_string()),
+        max_connections: None,
+        limit_policy: None,
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::clone(&data_ins))
+            .app_data(Data::clone(&throttle_ins))
+            .service(throttle_api_endpoint)
+            .service(set_global_throttle_endpoint)
+            .service(set_client_throttle_endpoint)
+            .service(remove_client_throttle_endpoint)
+    ).await;
+
+    let req = test::TestRequest::put()
+        .uri("/api/throttle")
+        .set_json(serde_json::json!({"rate": 1000000, "windows": [{"from": "22:00", "to": "06:00"}]}))
+        .to_request();
+    let settings: ThrottleSettings = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(settings.global.rate, Some(1000000));
+    assert_eq!(settings.global.windows[0].rate, None);
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/api/clients/{}/throttle", client.id.unwrap()))
+        .set_json(serde_json::json!({"rate": 5000}))
+        .to_request();
+    test::call_service(&app, req).await;
+
+    // the running throttle picks up the changes
+    let settings = throttle_ins.lock().unwrap().settings();
+    assert_eq!(settings.clients[&client.id.unwrap()].rate, Some(5000));
+    let req = test::TestRequest::get().uri("/api/throttle").to_request();
+    let listed: ThrottleSettings = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(listed, settings);
+
+    let req = test::TestRequest::put()
+        .uri("/api/throttle")
+        .set_json(serde_json::json!({"rate": 0}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    let req = test::TestRequest::put()
+        .uri("/api/clients/999999/throttle")
+        .set_json(serde_json::json!({"rate": 5000}))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/clients/{}/throttle", client.id.unwrap()))
+        .to_request();
+    let settings: ThrottleSettings = test::call_and_read_body_json(&app, req).await;
+    assert!(settings.clients.is_empty());
+    assert!(throttle_ins.lock().unwrap().settings().clients.is_empty());
+}
+
+#[actix_web::test]
+async fn client_ignore_api_test() {
+    let data_ins = Data::new(Mutex::new(DataService::open(":memory:".to_string(), 0).unwrap()));
+    let client = data_ins.lock().unwrap().new_client(Client {
+        id: None,
+        key: None,
+        name: Some("ignore_api_test".to_string()),
+        max_connections: None,
+        limit_policy: None,
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::clone(&data_ins))
+            .service(client_ignore_endpoint)
+            .service(set_client_ignore_endpoint)
+            .service(remove_client_ignore_endpoint)
+    ).await;
+    let uri = format!("/api/clients/{}/ignore", client.id.unwrap());
+
+    let req = test::TestRequest::put().uri(&uri).set_json(serde_json::json!(["logs/", "*.tmp"])).to_request();
+    let patterns: Vec<String> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(patterns, vec!["logs/", "*.tmp"]);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let patterns: Vec<String> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(patterns, vec!["logs/", "*.tmp"]);
+    // the connections of the client pick up the patterns with their next request
+    let scope = data_ins.lock().unwrap().get_client_scope("ignore_api_test").unwrap();
+    assert_eq!(scope.ignore.patterns(), ["logs/", "*.tmp"]);
+
+    let req = test::TestRequest::put().uri(&uri).set_json(serde_json::json!(["a["])).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    let req = test::TestRequest::put()
+        .uri("/api/clients/999999/ignore")
+        .set_json(serde_json::json!(["logs/"]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::delete().uri(&uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let scope = data_ins.lock().unwrap().get_client_scope("ignore_api_test").unwrap();
+    assert!(scope.ignore.patterns().is_empty());
+}
+
+#[actix_web::test]
+async fn client_keys_api_test() {
+    let data_ins = Data::new(Mutex::new(DataService::open(":memory:".to_string(), 0).unwrap()));
+    let client = data_ins.lock().unwrap().new_client(Client {
+        id: None,
+        key: None,
+        name: Some("keys_api_test".to_string()),
+        max_connections: None,
+        limit_policy: None,
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::clone(&data_ins))
+            .service(client_keys_endpoint)
+            .service(create_client_key_endpoint)
+            .service(revoke_client_key_endpoint)
+    ).await;
+    let uri = format!("/api/clients/{}/keys", client.id.unwrap());
+
+    // the key is shown once, when it is created
+    let req = test::TestRequest::post().uri(&uri).set_form([("label", "backup")]).to_request();
+    let key: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let key_value = key["key"].as_str().unwrap().to_string();
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let keys: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(keys.len(), 2);
+    for listed in &keys {
+        assert!(listed.get("key").is_none());
+        assert_eq!(listed["prefix"].as_str().unwrap().len(), 4);
+    }
+    assert!(keys.iter().any(|listed| key_value.starts_with(listed["prefix"].as_str().unwrap())));
+
+    let req = test::TestRequest::delete().uri(&format!("{}/{}", uri, key["id"])).to_request();
+    let revoked: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert!(revoked.get("key").is_none());
+    assert!(revoked["revoked_at"].is_i64());
+
+    let req = test::TestRequest::post().uri(&uri).set_form([("expires_in", "0")]).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    let req = test::TestRequest::post().uri("/api/clients/999999/keys").set_form([("label", "x")]).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    let req = test::TestRequest::get().uri("/api/clients/999999/keys").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
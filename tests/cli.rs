@@ -0,0 +1,154 @@
+use std::env::temp_dir;
+use std::fs::{create_dir_all, write};
+
+use cs::api::api::Client;
+use cs::cli::{run_command, ClientCommand, Command, DbCommand, IndexCommand, ShareCommand};
+use cs::config::Config;
+use cs::data::shares::Share;
+use cs::data::DataService;
+use cs::file::ignore::ClientScope;
+use cs::file::{FileService, ProvideFile};
+use test_utils::gen_msg_id;
+
+fn temp_config() -> Config {
+    let dir = temp_dir().join(format!("copy_service_cli_{}", gen_msg_id()));
+    create_dir_all(dir.join("data")).unwrap();
+    create_dir_all(dir.join("extra")).unwrap();
+    write(dir.join("data").join("A.txt"), "some data").unwrap();
+    write(dir.join("extra").join("B.txt"), "more data").unwrap();
+    Config {
+        config_path: dir.display().to_string(),
+        data_path: dir.join("data").display().to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn client_commands_test() {
+    let config = temp_config();
+
+    let output = run_command(Command::Db(DbCommand::Migrate), &config, false).unwrap();
+    assert!(output.contains("Migrated"));
+
+    let output = run_command(Command::Client(ClientCommand::Add {
+        name: "cli_client".to_string(),
+        max_connections: Some(2),
+        limit_policy: Some("evict_oldest".to_string()),
+    }), &config, true).unwrap();
+    let client: Client = serde_json::from_str(&output).unwrap();
+    assert_eq!(client.max_connections, Some(2));
+    let key = client.key.clone().unwrap();
+
+    // a second client with the same name is refused
+    assert!(run_command(Command::Client(ClientCommand::Add {
+        name: "cli_client".to_string(),
+        max_connections: None,
+        limit_policy: None,
+    }), &config, false).is_err());
+
+    let output = run_command(Command::Client(ClientCommand::RotateKey {
+        name: "cli_client".to_string(),
+    }), &config, true).unwrap();
+    let rotated: Client = serde_json::from_str(&output).unwrap();
+    assert_ne!(rotated.key.unwrap(), key);
+
+    let output = run_command(Command::Client(ClientCommand::List), &config, true).unwrap();
+    let clients: Vec<Client> = serde_json::from_str(&output).unwrap();
+    assert_eq!(clients.len(), 1);
+
+    let output = run_command(Command::Client(ClientCommand::Ignore {
+        name: "cli_client".to_string(),
+        patterns: vec!["*.tmp".to_string(), "!keep.tmp".to_string()],
+    }), &config, false).unwrap();
+    assert_eq!(output, "cli_client\tignore=*.tmp,!keep.tmp");
+    assert!(run_command(Command::Client(ClientCommand::Ignore {
+        name: "cli_client".to_string(),
+        patterns: vec!["a[".to_string()],
+    }), &config, false).is_err());
+
+    run_command(Command::Client(ClientCommand::Remove { name: "cli_client".to_string() }), &config, false).unwrap();
+    let output = run_command(Command::Client(ClientCommand::List), &config, true).unwrap();
+    let clients: Vec<Client> = serde_json::from_str(&output).unwrap();
+    assert!(clients.is_empty());
+}
+
+#[test]
+fn share_and_index_commands_test() {
+    let config = temp_config();
+    let extra_path = format!("{}/extra", config.config_path);
+
+    run_command(Command::Share(ShareCommand::Add {
+        name: "extra".to_string(),
+        path: extra_path.clone(),
+    }), &config, false).unwrap();
+    assert!(run_command(Command::Share(ShareCommand::Add {
+        name: "missing".to_string(),
+        path: format!("{}/missing", config.config_path),
+    }), &config, false).is_err());
+
+    let output = run_command(Command::Share(ShareCommand::List), &config, true).unwrap();
+    let shares: Vec<Share> = serde_json::from_str(&output).unwrap();
+    assert_eq!(shares.len(), 1);
+    assert_eq!(shares[0].name, "extra");
+
+    let output = run_command(Command::Index(IndexCommand::Rebuild), &config, false).unwrap();
+    assert_eq!(output, "Indexed 2 files");
+
+    // the server serves the share and reuses the persisted hashes
+    let data_service = DataService::open(config.db_path(), 0).unwrap();
+    let index = data_service.get_file_index();
+    assert_eq!(index.len(), 2);
+
+    let mut file_service = FileService::new(config.data_path.clone());
+    file_service.set_shares(data_service.get_shares());
+    file_service.load_index(index.clone());
+    let tree = file_service.get_tree(&ClientScope::default()).unwrap();
+    let share_dir = tree.dirs.unwrap().into_iter().find(|dir| dir.name == "extra").unwrap();
+    let share_file = &share_dir.files.unwrap()[0];
+    assert_eq!(share_file.name, "B.txt");
+    assert!(index.iter().any(|entry| entry.hash == share_file.hash));
+    assert!(file_service.get_file_data(0, 4, share_file.hash.clone(), &ClientScope::default()).is_ok());
+
+    // the rules of the share apply from the next start
+    let output = run_command(Command::Share(ShareCommand::Ignore {
+        name: "extra".to_string(),
+        patterns: vec!["*.txt".to_string()],
+    }), &config, false).unwrap();
+    assert!(output.ends_with("\tignore=*.txt"));
+    let output = run_command(Command::Share(ShareCommand::Hash {
+        name: "extra".to_string(),
+        algorithm: Some("blake3".to_string()),
+    }), &config, false).unwrap();
+    assert!(output.contains("\thash=blake3\t"));
+    assert!(run_command(Command::Share(ShareCommand::Hash {
+        name: "extra".to_string(),
+        algorithm: Some("md5".to_string()),
+    }), &config, false).is_err());
+    file_service.set_shares(DataService::open(config.db_path(), 0).unwrap().get_shares());
+    let tree = file_service.get_tree(&ClientScope::default()).unwrap();
+    let share_dir = tree.dirs.unwrap().into_iter().find(|dir| dir.name == "extra").unwrap();
+    assert!(share_dir.files.unwrap().is_empty());
+    assert!(file_service.get_file_data(0, 4, share_file.hash.clone(), &ClientScope::default()).is_err());
+}
+
+#[test]
+fn commands_create_the_directories_test() {
+    let dir = temp_dir().join(format!("copy_service_cli_new_{}", gen_msg_id()));
+    let config = Config {
+        config_path: dir.join("config").display().to_string(),
+        data_path: dir.join("data").display().to_string(),
+        ..Default::default()
+    };
+
+    run_command(Command::Client(ClientCommand::Add {
+        name: "first_client".to_string(),
+        max_connections: None,
+        limit_policy: None,
+    }), &config, false).unwrap();
+    assert!(dir.join("config").is_dir());
+    assert!(!dir.join("data").exists());
+
+    let output = run_command(Command::Index(IndexCommand::Rebuild), &config, false).unwrap();
+    assert_eq!(output, "Indexed 0 files");
+    assert!(dir.join("data").is_dir());
+}
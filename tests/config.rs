@@ -0,0 +1,101 @@
+use std::env::{set_var, temp_dir};
+use std::fs::{create_dir_all, write};
+use std::path::PathBuf;
+
+use cs::config::{Config, ConfigLayer, DEFAULT_CONFIG_FILE_NAME};
+use cs::data::DataService;
+use cs::logging::LogFormat;
+use test_utils::gen_msg_id;
+
+fn config_dir(content: &str) -> PathBuf {
+    let dir = temp_dir().join(format!("copy_service_config_{}", gen_msg_id()));
+    create_dir_all(&dir).unwrap();
+    write(dir.join(DEFAULT_CONFIG_FILE_NAME), content).unwrap();
+    dir
+}
+
+#[test]
+fn config_precedence_test() {
+    let dir = config_dir(
+        "web_port = 5000\n\
+         ws_port = 5001\n\
+         max_chunk_size = 1024\n\
+         log_format = \"json\"\n",
+    );
+    let dir_path = dir.display().to_string();
+
+    // the file of the config path is used, the flags win over it
+    let config = Config::load(None, ConfigLayer {
+        config_path: Some(dir_path.clone()),
+        data_path: Some(dir_path.clone()),
+        ws_port: Some(6001),
+        ..Default::default()
+    }).unwrap();
+
+    assert_eq!(config.web_port, 5000);
+    assert_eq!(config.ws_port, 6001);
+    assert_eq!(config.max_chunk_size, 1024);
+    assert_eq!(config.log_format, LogFormat::Json);
+    assert_eq!(config.web_bind, Config::default().web_bind);
+    assert_eq!(config.db_path(), format!("{}/data.db", dir_path));
+}
+
+#[test]
+fn config_validation_test() {
+    let dir = config_dir("unknown_setting = 1\n");
+    let err = Config::load(Some(&dir.join(DEFAULT_CONFIG_FILE_NAME)), ConfigLayer::default()).unwrap_err();
+    assert!(err.contains("unknown_setting"));
+
+    let err = Config::load(Some(&dir.join("missing.toml")), ConfigLayer::default()).unwrap_err();
+    assert!(err.contains("missing.toml"));
+
+    // every problem is reported
+    let dir = config_dir("");
+    let err = Config::load(None, ConfigLayer {
+        config_path: Some(dir.display().to_string()),
+        web_bind: Some("localhost:80".to_string()),
+        web_port: Some(4001),
+        data_path: Some("/not/a/directory".to_string()),
+        max_chunk_size: Some(0),
+        ..Default::default()
+    }).unwrap_err();
+    assert!(err.contains("web_bind"));
+    assert!(err.contains("ws_port"));
+    assert!(err.contains("data_path"));
+    assert!(err.contains("max_chunk_size"));
+
+    // the commands create the directories they use
+    let missing = ConfigLayer {
+        config_path: Some(dir.join("missing").display().to_string()),
+        data_path: Some("/not/a/directory".to_string()),
+        ..Default::default()
+    };
+    assert!(Config::load_for_command(None, missing.clone()).is_ok());
+    assert!(Config::load_for_command(None, ConfigLayer { max_chunk_size: Some(0), ..missing }).is_err());
+
+    let err = Config::load(None, ConfigLayer {
+        config_path: Some(dir.display().to_string()),
+        log_format: Some("xml".to_string()),
+        ..Default::default()
+    }).unwrap_err();
+    assert!(err.contains("xml"));
+
+    let err = Config::load(None, ConfigLayer {
+        config_path: Some(dir.display().to_string()),
+        symlinks: Some("always".to_string()),
+        ..Default::default()
+    }).unwrap_err();
+    assert!(err.contains("always"));
+}
+
+#[test]
+fn data_service_from_env_test() {
+    let dir = config_dir("");
+    // the only test of the file reading the environment
+    set_var("CONFIG_PATH", dir.display().to_string());
+    set_var("DB_FILE_NAME", "from_env.db");
+
+    assert_eq!(Config::from_env().unwrap().db_path(), format!("{}/from_env.db", dir.display()));
+    DataService::new();
+    assert!(dir.join("from_env.db").exists());
+}
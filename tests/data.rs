@@ -0,0 +1,175 @@
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use rusqlite::Connection;
+
+use cs::api::api::Client;
+use cs::data::keys::NewClientKey;
+use cs::data::migrations::{current_version, latest_version, SCHEMA_VERSION_KEY};
+use cs::data::DataService;
+
+fn memory_data_service(db_connection: Connection, key_rotation_grace: i64) -> DataService {
+    DataService::initialize_db(&db_connection).unwrap();
+    DataService {
+        db_connection: Data::new(Mutex::new(db_connection)),
+        key_rotation_grace,
+    }
+}
+
+fn new_client(data_service: &DataService, name: &str) -> Client {
+    data_service.new_client(Client {
+        id: None,
+        key: None,
+        name: Some(name.to_string()),
+        max_connections: None,
+        limit_policy: None,
+    })
+}
+
+#[test]
+fn migrate_new_database_test() {
+    let db_connection = Connection::open_in_memory().unwrap();
+
+    let version = DataService::initialize_db(&db_connection).unwrap();
+
+    assert_eq!(version, latest_version());
+    assert_eq!(current_version(&db_connection).unwrap(), latest_version());
+
+    // running the migrations again is a no-op
+    let version = DataService::initialize_db(&db_connection).unwrap();
+    assert_eq!(version, latest_version());
+}
+
+#[test]
+fn migrate_legacy_database_test() {
+    let db_connection = Connection::open_in_memory().unwrap();
+
+    // database created before the migrations existed
+    db_connection.execute_batch(
+        "CREATE TABLE configuration ( key TEXT PRIMARY KEY, value TEXT );
+         CREATE TABLE client ( id INTEGER PRIMARY KEY, key TEXT NOT NULL, name TEXT );
+         INSERT INTO client (name, key) VALUES ('legacy_client', 'legacy-key');",
+    ).unwrap();
+    assert_eq!(current_version(&db_connection).unwrap(), 0);
+
+    DataService::initialize_db(&db_connection).unwrap();
+
+    assert_eq!(current_version(&db_connection).unwrap(), latest_version());
+    let name: String = db_connection
+        .query_row("SELECT name FROM client WHERE name = 'legacy_client';", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(name, "legacy_client");
+
+    // the key of the client keeps working
+    let data_service = memory_data_service(db_connection, 0);
+    assert!(data_service.validate_user_auth("legacy_client".to_string(), "legacy-key".to_string()));
+    assert_eq!(data_service.get_client_key("legacy_client".to_string()).unwrap(), "legacy-key");
+}
+
+#[test]
+fn refuse_newer_schema_test() {
+    let db_connection = Connection::open_in_memory().unwrap();
+    DataService::initialize_db(&db_connection).unwrap();
+
+    db_connection.execute(
+        "UPDATE configuration SET value = ?1 WHERE key = ?2;",
+        [(latest_version() + 1).to_string(), SCHEMA_VERSION_KEY.to_string()],
+    ).unwrap();
+
+    assert!(DataService::initialize_db(&db_connection).is_err());
+}
+
+#[test]
+fn key_rotation_grace_period_test() {
+    let data_service = memory_data_service(Connection::open_in_memory().unwrap(), 3600);
+    let client = new_client(&data_service, "rotation_client");
+    let old_key = client.key.clone().unwrap();
+
+    let rotated = data_service.gen_key(client.id.unwrap());
+    let new_key = rotated.key.unwrap();
+    assert_ne!(old_key, new_key);
+
+    // both keys work during the grace period
+    assert!(data_service.validate_user_auth("rotation_client".to_string(), old_key.clone()));
+    assert!(data_service.validate_user_auth("rotation_client".to_string(), new_key.clone()));
+
+    let keys = data_service.get_client_keys(client.id.unwrap());
+    assert_eq!(keys.len(), 2);
+    assert!(keys[0].expires_at.is_some());
+    assert!(keys[0].last_used_at.is_some());
+    assert!(keys[1].expires_at.is_none());
+}
+
+#[test]
+fn key_rotation_without_grace_period_test() {
+    let data_service = memory_data_service(Connection::open_in_memory().unwrap(), 0);
+    let client = new_client(&data_service, "rotation_no_grace_client");
+    let old_key = client.key.clone().unwrap();
+
+    let new_key = data_service.gen_key(client.id.unwrap()).key.unwrap();
+
+    assert!(!data_service.validate_user_auth("rotation_no_grace_client".to_string(), old_key));
+    assert!(data_service.validate_user_auth("rotation_no_grace_client".to_string(), new_key));
+}
+
+#[test]
+fn key_revoke_and_expiry_test() {
+    let data_service = memory_data_service(Connection::open_in_memory().unwrap(), 3600);
+    let client = new_client(&data_service, "revoke_client");
+    let client_id = client.id.unwrap();
+
+    let backup = data_service.add_client_key(client_id, NewClientKey {
+        label: Some("backup".to_string()),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(backup.label.clone().unwrap(), "backup");
+    assert!(data_service.validate_user_auth("revoke_client".to_string(), backup.key.clone()));
+
+    let revoked = data_service.revoke_client_key(client_id, backup.id).unwrap();
+    assert!(revoked.revoked_at.is_some());
+    assert!(!data_service.validate_user_auth("revoke_client".to_string(), backup.key));
+
+    // a key can't be born expired, nor belong to a missing client
+    for expires_in in [-1, 0] {
+        assert!(data_service.add_client_key(client_id, NewClientKey {
+            expires_in: Some(expires_in),
+            ..Default::default()
+        }).is_err());
+    }
+    assert!(data_service.add_client_key(client_id + 1000, NewClientKey::default()).is_err());
+    assert_eq!(data_service.get_client_keys(client_id + 1000).len(), 0);
+
+    let expiring = data_service.add_client_key(client_id, NewClientKey {
+        expires_in: Some(1),
+        ..Default::default()
+    }).unwrap();
+    assert!(data_service.validate_user_auth("revoke_client".to_string(), expiring.key.clone()));
+    sleep(Duration::from_millis(2100));
+    assert!(!data_service.validate_user_auth("revoke_client".to_string(), expiring.key));
+
+    // the original key is not affected
+    assert!(data_service.validate_user_auth("revoke_client".to_string(), client.key.unwrap()));
+}
+
+#[test]
+fn corrupt_ignore_patterns_test() {
+    let data_service = memory_data_service(Connection::open_in_memory().unwrap(), 0);
+    let client = new_client(&data_service, "corrupt_ignore_client");
+    data_service.add_share("docs".to_string(), ".".to_string()).unwrap();
+    {
+        let db_connection = data_service.db_connection.lock().unwrap();
+        db_connection.execute("UPDATE client SET ignore = 'not json' WHERE id = ?1;", [client.id.unwrap()]).unwrap();
+        db_connection.execute("UPDATE share SET ignore = '[\"cache/' WHERE name = 'docs';", []).unwrap();
+    }
+
+    assert!(data_service.get_client_scope("corrupt_ignore_client").is_err());
+    assert!(data_service.get_client_ignore(client.id.unwrap()).is_err());
+    let share = data_service.get_shares().into_iter().find(|share| share.name == "docs").unwrap();
+    assert!(share.ignore_error.is_some());
+
+    // saving new patterns replaces the unreadable ones
+    data_service.set_client_ignore(client.id.unwrap(), Some(&["*.tmp".to_string()])).unwrap();
+    assert_eq!(data_service.get_client_scope("corrupt_ignore_client").unwrap().ignore.patterns(), ["*.tmp"]);
+}
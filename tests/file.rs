@@ -0,0 +1,530 @@
+#![cfg(target_family = "unix")]
+
+use std::env::temp_dir;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+use std::fs::{create_dir_all, set_permissions, write, File, Permissions};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cs::data::shares::Share;
+use base64::{engine::general_purpose, Engine as _};
+use cs::data::now_secs;
+use cs::file::chunks::ChunkFileService;
+use cs::file::hash::{negotiate, HashAlgorithm};
+use cs::file::ignore::ClientScope;
+use cs::file::versions::{Retention, VersionStore};
+use cs::file::walk::SymlinkPolicy;
+use cs::file::{read_file_data, FileError, FileService, ProvideFile};
+use cs::ws::ws_message::Directory;
+use test_utils::gen_msg_id;
+
+#[test]
+fn tree_metadata_test() {
+    let root = temp_dir().join(format!("copy_service_metadata_{}", gen_msg_id()));
+    create_dir_all(root.join("empty")).unwrap();
+    write(root.join("script.sh"), "echo hi").unwrap();
+    set_permissions(root.join("script.sh"), Permissions::from_mode(0o750)).unwrap();
+    let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789);
+    File::options().write(true).open(root.join("script.sh")).unwrap().set_modified(mtime).unwrap();
+    symlink("script.sh", root.join("run")).unwrap();
+    symlink("/etc", root.join("etc")).unwrap();
+
+    let mut file_service = FileService::new(root.display().to_string());
+    let tree = file_service.get_tree(&ClientScope::default()).unwrap();
+
+    let file = &tree.files.as_ref().unwrap()[0];
+    assert_eq!(file.name, "script.sh");
+    assert_eq!(file.attributes.mode, Some(0o750));
+    assert_eq!(file.attributes.mtime_ns, 1_600_000_000_123_456_789);
+    assert_eq!((file.attributes.uid, file.attributes.gid), (None, None));
+
+    // the links are not followed, not even the ones to directories
+    let mut links: Vec<(String, String)> = tree.links.as_ref().unwrap()
+        .iter()
+        .map(|link| (link.name.clone(), link.target.clone()))
+        .collect();
+    links.sort();
+    assert_eq!(links, vec![
+        ("etc".to_string(), "/etc".to_string()),
+        ("run".to_string(), "script.sh".to_string()),
+    ]);
+    let dirs = tree.dirs.as_ref().unwrap();
+    assert_eq!(dirs.len(), 1);
+    assert_eq!(dirs[0].name, "empty");
+    assert!(dirs[0].files.as_ref().unwrap().is_empty());
+
+    let json = serde_json::to_value(&tree).unwrap();
+    assert_eq!(json["files"][0]["mode"], 0o750);
+    assert_eq!(json["files"][0]["mtime_ns"], 1_600_000_000_123_456_789_i64);
+    assert!(json["links"][0]["target"].is_string());
+
+    // the owner only when asked for
+    file_service.set_include_owner(true);
+    let tree = file_service.get_tree(&ClientScope::default()).unwrap();
+    let owner = root.join("script.sh").metadata().unwrap().uid();
+    assert_eq!(tree.files.unwrap()[0].attributes.uid, Some(owner));
+}
+
+fn names(dir: &Directory) -> Vec<String> {
+    let mut names: Vec<String> = dir.files.as_ref().unwrap().iter().map(|file| file.name.clone()).collect();
+    names.extend(dir.dirs.as_ref().unwrap().iter().map(|dir| format!("{}/", dir.name)));
+    names.sort();
+    names
+}
+
+#[test]
+fn ignore_rules_test() {
+    let base = temp_dir().join(format!("copy_service_ignore_{}", gen_msg_id()));
+    let root = base.join("data");
+    let extra = base.join("extra");
+    create_dir_all(root.join(".git")).unwrap();
+    create_dir_all(root.join("logs")).unwrap();
+    create_dir_all(extra.join("cache")).unwrap();
+    write(root.join(".git").join("config"), "git").unwrap();
+    write(root.join("a.txt"), "a").unwrap();
+    write(root.join("b.tmp"), "b").unwrap();
+    write(root.join("keep.tmp"), "keep").unwrap();
+    write(root.join("logs").join("x.log"), "log").unwrap();
+    write(extra.join("s.txt"), "s").unwrap();
+    write(extra.join("cache").join("c.bin"), "c").unwrap();
+    let settled = SystemTime::now() - Duration::from_secs(120);
+    for path in [root.join("a.txt"), root.join("keep.tmp"), root.join("logs").join("x.log"), extra.join("s.txt")] {
+        File::options().write(true).open(path).unwrap().set_modified(settled).unwrap();
+    }
+
+    let mut file_service = FileService::new(root.display().to_string());
+    file_service.set_shares(vec![Share {
+        id: 1,
+        name: "extra".to_string(),
+        path: extra.display().to_string(),
+        created_at: 0,
+        ignore: vec!["cache/".to_string()],
+        ignore_error: None,
+        hash_algorithm: None,
+    }]);
+    file_service.set_ignore(&[".git/".to_string(), "*.tmp".to_string(), "!keep.tmp".to_string()]).unwrap();
+    assert!(file_service.set_ignore(&["a[".to_string()]).is_err());
+
+    let everything = ClientScope::default();
+    let tree = file_service.get_tree(&everything).unwrap();
+    assert_eq!(names(&tree), vec!["a.txt", "extra/", "keep.tmp", "logs/"]);
+    let share = tree.dirs.as_ref().unwrap().iter().find(|dir| dir.name == "extra").unwrap();
+    assert_eq!(names(share), vec!["s.txt"]);
+    let log_hash = tree.dirs.as_ref().unwrap().iter().find(|dir| dir.name == "logs").unwrap()
+        .files.as_ref().unwrap()[0].hash.clone();
+    // the lookup caches the path of the file for the next requests
+    assert!(file_service.get_file_data(0, 3, log_hash.clone(), &everything).is_ok());
+
+    // the rules of the client apply on top, also to the files already looked up
+    let scope = ClientScope::new(Some("ignore_client".to_string()), &["logs/".to_string(), "/extra".to_string()]).unwrap();
+    let tree = file_service.get_tree(&scope).unwrap();
+    assert_eq!(names(&tree), vec!["a.txt", "keep.tmp"]);
+    assert_eq!(
+        file_service.get_file_data(0, 3, log_hash.clone(), &scope).unwrap_err(),
+        FileError::NotFound(log_hash),
+    );
+
+    // the files written recently show up once they have settled
+    file_service.set_settle_time(Duration::from_secs(60));
+    write(root.join("new.txt"), "new").unwrap();
+    assert_eq!(names(&file_service.get_tree(&scope).unwrap()), vec!["a.txt", "keep.tmp"]);
+    File::options().write(true).open(root.join("new.txt")).unwrap().set_modified(settled).unwrap();
+    assert_eq!(names(&file_service.get_tree(&scope).unwrap()), vec!["a.txt", "keep.tmp", "new.txt"]);
+}
+
+fn error_codes(dir: &Directory) -> Vec<(String, String)> {
+    let mut codes: Vec<(String, String)> = dir.errors.as_ref().unwrap()
+        .iter()
+        .map(|error| (error.name.clone(), error.code.clone()))
+        .collect();
+    codes.sort();
+    codes
+}
+
+#[test]
+fn tree_walker_safety_test() {
+    let root = temp_dir().join(format!("copy_service_walker_{}", gen_msg_id()));
+    create_dir_all(root.join("docs")).unwrap();
+    write(root.join("a.txt"), "a").unwrap();
+    write(root.join(OsStr::from_bytes(b"bad\xff")), "bad").unwrap();
+    symlink("a.txt", root.join("alias")).unwrap();
+    symlink("docs", root.join("docs_link")).unwrap();
+    symlink("..", root.join("docs").join("up")).unwrap();
+    symlink("/etc", root.join("etc")).unwrap();
+    symlink("missing", root.join("dangling")).unwrap();
+    let fifo = Command::new("mkfifo").arg(root.join("fifo")).status().unwrap();
+    assert!(fifo.success());
+
+    // the fifo is left out, the tree is still served
+    let mut file_service = FileService::new(root.display().to_string());
+    let everything = ClientScope::default();
+    let tree = file_service.get_tree(&everything).unwrap();
+    assert_eq!(names(&tree), vec!["a.txt", "docs/"]);
+    assert_eq!(tree.links.as_ref().unwrap().len(), 4);
+    assert_eq!(error_codes(&tree), vec![("bad\u{fffd}".to_string(), "invalid_name".to_string())]);
+
+    file_service.set_symlink_policy(SymlinkPolicy::Skip);
+    let tree = file_service.get_tree(&everything).unwrap();
+    assert!(tree.links.as_ref().unwrap().is_empty());
+    assert_eq!(names(&tree), vec!["a.txt", "docs/"]);
+
+    // the links are followed inside the data path only, without going round in circles
+    file_service.set_symlink_policy(SymlinkPolicy::Follow);
+    let tree = file_service.get_tree(&everything).unwrap();
+    assert_eq!(names(&tree), vec!["a.txt", "alias", "docs/", "docs_link/"]);
+    assert_eq!(error_codes(&tree), vec![
+        ("bad\u{fffd}".to_string(), "invalid_name".to_string()),
+        ("dangling".to_string(), "io_error".to_string()),
+        ("etc".to_string(), "outside_root".to_string()),
+    ]);
+    let docs = tree.dirs.as_ref().unwrap().iter().find(|dir| dir.name == "docs").unwrap();
+    assert_eq!(error_codes(docs), vec![("up".to_string(), "symlink_loop".to_string())]);
+    let files = tree.files.as_ref().unwrap();
+    assert_eq!(files[0].hash, files[1].hash);
+    let data = file_service.get_file_data(0, 1, files[0].hash.clone(), &everything).unwrap();
+    assert_eq!(data.end, 1);
+
+    let json = serde_json::to_value(docs).unwrap();
+    assert_eq!(json["errors"][0]["code"], "symlink_loop");
+}
+
+#[test]
+fn hash_algorithm_test() {
+    let base = temp_dir().join(format!("copy_service_hash_{}", gen_msg_id()));
+    create_dir_all(base.join("data")).unwrap();
+    create_dir_all(base.join("fast")).unwrap();
+    write(base.join("data").join("a.txt"), "abc").unwrap();
+    write(base.join("fast").join("b.txt"), "abc").unwrap();
+
+    let mut file_service = FileService::new(base.join("data").display().to_string());
+    file_service.set_shares(vec![Share {
+        id: 1,
+        name: "fast".to_string(),
+        path: base.join("fast").display().to_string(),
+        created_at: 0,
+        ignore: Vec::new(),
+        ignore_error: None,
+        hash_algorithm: Some(HashAlgorithm::Blake3),
+    }]);
+
+    // the sha256 hashes are not tagged
+    let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let blake3 = "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85";
+    let tree = file_service.get_tree(&ClientScope::default()).unwrap();
+    assert_eq!(tree.files.as_ref().unwrap()[0].hash, sha256);
+    let share = &tree.dirs.as_ref().unwrap()[0];
+    assert_eq!(share.files.as_ref().unwrap()[0].hash, blake3);
+    assert_eq!(HashAlgorithm::of_hash(blake3), HashAlgorithm::Blake3);
+    assert_eq!(HashAlgorithm::of_hash(sha256), HashAlgorithm::Sha256);
+
+    // a client that doesn't know the algorithm of a share gets the hashes it knows
+    let scope = ClientScope {
+        hash_algorithms: Some(negotiate(None)),
+        ..Default::default()
+    };
+    let tree = file_service.get_tree(&scope).unwrap();
+    assert_eq!(tree.dirs.as_ref().unwrap()[0].files.as_ref().unwrap()[0].hash, sha256);
+    // the files can still be copied by any of their hashes
+    let data = file_service.get_file_data(0, 3, blake3.to_string(), &scope).unwrap();
+    assert_eq!(data.end, 3);
+    let data_sha256 = FileService::new(base.join("data").display().to_string())
+        .get_file_data(0, 3, sha256.to_string(), &ClientScope::default())
+        .unwrap();
+    assert_eq!(data_sha256.data, data.data);
+
+    // the hashes of every algorithm stay in the index, the copy found the file of the tree without a scan
+    let entries = file_service.index_entries();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries.iter().filter(|entry| entry.path.ends_with("b.txt")).count(), 2);
+    assert_eq!(negotiate(Some(&["blake3".to_string(), "sha256".to_string()])), vec![
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Sha256,
+    ]);
+}
+
+#[test]
+fn parallel_scan_test() {
+    let base = temp_dir().join(format!("copy_service_scan_{}", gen_msg_id()));
+    for dir in 0..4 {
+        create_dir_all(base.join(format!("dir_{}", dir))).unwrap();
+        for file in 0..10 {
+            write(base.join(format!("dir_{}", dir)).join(format!("{}.txt", file)), format!("{} {}", dir, file)).unwrap();
+        }
+    }
+    let mut file_service = FileService::new(base.display().to_string());
+    file_service.set_hash_workers(4);
+    let progress = file_service.scan_progress().unwrap();
+
+    let tree = file_service.get_tree(&ClientScope::default()).unwrap();
+    assert_eq!(progress.current(), None);
+    let dirs = tree.dirs.as_ref().unwrap();
+    assert_eq!(dirs.len(), 4);
+    for dir in dirs {
+        let files = dir.files.as_ref().unwrap();
+        assert_eq!(files.len(), 10);
+        for file in files {
+            let content = std::fs::read_to_string(file.path.as_ref().unwrap()).unwrap();
+            assert_eq!(file.hash, sha256::digest(content));
+        }
+    }
+    assert_eq!(file_service.index_entries().len(), 40);
+
+    // the next scan takes the hashes from the index
+    write(base.join("dir_0").join("0.txt"), "changed").unwrap();
+    let tree = file_service.get_tree(&ClientScope::default()).unwrap();
+    let dir_0 = tree.dirs.as_ref().unwrap().iter().find(|dir| dir.name == "dir_0").unwrap();
+    let changed = dir_0.files.as_ref().unwrap().iter().find(|file| file.name == "0.txt").unwrap();
+    assert_eq!(changed.hash, sha256::digest("changed"));
+    assert_eq!(file_service.index_entries().len(), 40);
+}
+
+#[test]
+fn file_change_test() {
+    let base = temp_dir().join(format!("copy_service_change_{}", gen_msg_id()));
+    create_dir_all(base.join("data")).unwrap();
+    create_dir_all(base.join("snapshots")).unwrap();
+    let path = base.join("data").join("a.txt");
+    write(&path, "hello world").unwrap();
+    let scope = ClientScope::default();
+
+    let mut file_service = FileService::new(base.join("data").display().to_string());
+    let hash = file_service.get_tree(&scope).unwrap().files.unwrap()[0].hash.clone();
+    assert!(file_service.get_file_data(0, 5, hash.clone(), &scope).is_ok());
+    write(&path, "hello world, again").unwrap();
+    let err = file_service.get_file_data(5, 11, hash.clone(), &scope).unwrap_err();
+    assert_eq!(err, FileError::Changed(hash.clone()));
+    assert_eq!(err.code(), "file_changed");
+
+    // a change keeping the size and the modification time is caught by the hash
+    write(&path, "hello world").unwrap();
+    let hash = file_service.get_tree(&scope).unwrap().files.unwrap()[0].hash.clone();
+    let modified = path.metadata().unwrap().modified().unwrap();
+    write(&path, "HELLO WORLD").unwrap();
+    File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+    assert!(file_service.get_file_data(0, 11, hash.clone(), &scope).unwrap().last_data);
+    file_service.set_verify_hash(true);
+    let err = file_service.get_file_data(0, 11, hash.clone(), &scope).unwrap_err();
+    assert_eq!(err, FileError::Changed(hash));
+
+    // with snapshots the transfer goes on with the content of the tree
+    write(&path, "hello world").unwrap();
+    file_service.set_snapshot_dir(Some(base.join("snapshots").display().to_string())).unwrap();
+    let hash = file_service.get_tree(&scope).unwrap().files.unwrap()[0].hash.clone();
+    assert_eq!(file_service.get_file_data(0, 5, hash.clone(), &scope).unwrap().data, "aGVsbG8=");
+    write(&path, "changed!").unwrap();
+    let data = file_service.get_file_data(5, 11, hash.clone(), &scope).unwrap();
+    assert_eq!(data.data, "IHdvcmxk");
+    assert!(data.last_data);
+    assert_eq!(std::fs::read_dir(base.join("snapshots")).unwrap().count(), 1);
+}
+
+#[test]
+fn prepared_read_test() {
+    let base = temp_dir().join(format!("copy_service_prepared_{}", gen_msg_id()));
+    create_dir_all(base.join("data")).unwrap();
+    create_dir_all(base.join("snapshots")).unwrap();
+    let path = base.join("data").join("a.txt");
+    write(&path, "hello world").unwrap();
+    let scope = ClientScope::default();
+
+    let mut file_service = FileService::new(base.join("data").display().to_string());
+    file_service.set_verify_hash(true);
+    let hash = file_service.get_tree(&scope).unwrap().files.unwrap()[0].hash.clone();
+    // only the reads of the last chunk hash the file again
+    assert!(file_service.prepare_file_data(0, 5, &hash, &scope).is_none());
+    let file_service = Mutex::new(file_service);
+
+    // the file is hashed again while the provider serves the other reads
+    let preparation = file_service.lock().unwrap().prepare_file_data(0, 11, &hash, &scope).unwrap();
+    {
+        let _provider = file_service.lock().unwrap();
+        preparation().unwrap();
+    }
+    assert!(read_file_data(&file_service, 0, 11, hash.clone(), &scope).unwrap().last_data);
+
+    let modified = path.metadata().unwrap().modified().unwrap();
+    write(&path, "HELLO WORLD").unwrap();
+    File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+    let err = read_file_data(&file_service, 0, 11, hash.clone(), &scope).unwrap_err();
+    assert_eq!(err, FileError::Changed(hash));
+
+    // the snapshot is taken without holding the provider too, and then published
+    write(&path, "hello world").unwrap();
+    file_service.lock().unwrap().set_snapshot_dir(Some(base.join("snapshots").display().to_string())).unwrap();
+    let hash = file_service.lock().unwrap().get_tree(&scope).unwrap().files.unwrap()[0].hash.clone();
+    let preparation = file_service.lock().unwrap().prepare_file_data(0, 5, &hash, &scope).unwrap();
+    {
+        let _provider = file_service.lock().unwrap();
+        preparation().unwrap();
+    }
+    assert_eq!(std::fs::read_dir(base.join("snapshots")).unwrap().count(), 1);
+    assert!(file_service.lock().unwrap().prepare_file_data(0, 5, &hash, &scope).is_none());
+    write(&path, "changed!").unwrap();
+    assert_eq!(read_file_data(&file_service, 0, 5, hash.clone(), &scope).unwrap().data, "aGVsbG8=");
+    assert_eq!(read_file_data(&file_service, 5, 11, hash, &scope).unwrap().data, "IHdvcmxk");
+}
+
+#[test]
+fn versions_test() {
+    let base = temp_dir().join(format!("copy_service_versions_{}", gen_msg_id()));
+    create_dir_all(base.join("data")).unwrap();
+    let path = base.join("data").join("a.txt");
+    let path_str = path.display().to_string();
+    let tree_path = "a.txt";
+    let versions_dir = base.join("versions").display().to_string();
+    let retention = Retention {
+        max_versions: Some(2),
+        max_age: None,
+    };
+    let scope = ClientScope::default();
+
+    let mut file_service = FileService::new(base.join("data").display().to_string());
+    file_service.set_versions(Some(versions_dir.clone()), retention).unwrap();
+    let mut hashes = Vec::new();
+    for content in ["one", "two!", "three"] {
+        write(&path, content).unwrap();
+        hashes.push(file_service.get_tree(&scope).unwrap().files.unwrap()[0].hash.clone());
+    }
+
+    // the oldest version is out of the retention
+    let versions = file_service.get_versions(tree_path, &scope).unwrap();
+    let stored: Vec<String> = versions.iter().map(|version| version.hash.clone()).collect();
+    assert_eq!(stored, hashes[1..].to_vec());
+    assert_eq!(versions[0].size, 4);
+    let data = file_service.get_file_data(0, 10, hashes[1].clone(), &scope).unwrap();
+    assert_eq!(data.data, "dHdvIQ==");
+    assert!(data.last_data);
+    let err = file_service.get_file_data(0, 10, hashes[0].clone(), &scope).unwrap_err();
+    assert_eq!(err, FileError::Changed(hashes[0].clone()));
+
+    // the versions are kept across restarts, and hidden from the clients that can't see the file
+    let mut file_service = FileService::new(base.join("data").display().to_string());
+    file_service.set_versions(Some(versions_dir.clone()), retention).unwrap();
+    assert_eq!(file_service.get_versions(tree_path, &scope).unwrap().len(), 2);
+    let hidden = ClientScope::new(Some("client".to_string()), &["a.txt".to_string()]).unwrap();
+    assert!(file_service.get_versions(tree_path, &hidden).unwrap().is_empty());
+    assert!(file_service.get_versions("../data/a.txt", &scope).unwrap().is_empty());
+    let err = file_service.get_file_data(0, 10, hashes[1].clone(), &hidden).unwrap_err();
+    assert_eq!(err.code(), "file_not_found");
+
+    // a version replaced long enough ago is removed, with its content
+    let mut store = VersionStore::open(&base.join("versions"), Retention {
+        max_versions: None,
+        max_age: Some(Duration::from_secs(60)),
+    }).unwrap();
+    assert!(store.prune(now_secs() + 120));
+    assert_eq!(store.versions(&path_str).len(), 1);
+    assert!(store.find(&hashes[1]).is_none());
+    assert_eq!(std::fs::read_dir(base.join("versions").join("objects")).unwrap().count(), 1);
+}
+
+/** Bytes that don't repeat, so the content defined chunks have their usual size */
+fn noise(size: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..size)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn chunk_store_test() {
+    let base = temp_dir().join(format!("copy_service_chunks_{}", gen_msg_id()));
+    create_dir_all(base.join("data")).unwrap();
+    create_dir_all(base.join("share")).unwrap();
+    let content = noise(600 * 1024, 7);
+    let mut edited = content.clone();
+    edited[300 * 1024..300 * 1024 + 10].copy_from_slice(b"0123456789");
+    write(base.join("data").join("a.bin"), &content).unwrap();
+    write(base.join("share").join("b.bin"), &content).unwrap();
+    write(base.join("share").join("c.bin"), &edited).unwrap();
+
+    let mut files = FileService::new(base.join("data").display().to_string());
+    files.set_shares(vec![Share {
+        id: 1,
+        name: "share".to_string(),
+        path: base.join("share").display().to_string(),
+        created_at: 0,
+        ignore: Vec::new(),
+        ignore_error: None,
+        hash_algorithm: None,
+    }]);
+    let service = ChunkFileService::new(files, &base.join("chunks")).unwrap();
+    let scope = ClientScope::default();
+    let tree = service.get_tree(&scope).unwrap();
+    let hash = tree.files.as_ref().unwrap()[0].hash.clone();
+    let share = &tree.dirs.as_ref().unwrap()[0];
+    let edited_hash = share.files.as_ref().unwrap().iter().find(|file| file.name == "c.bin").unwrap().hash.clone();
+
+    // the chunks cover the file, the same content is stored once
+    let chunks = service.get_chunks(&hash, &scope).unwrap();
+    assert!(chunks.len() > 2);
+    assert_eq!(chunks.iter().map(|chunk| chunk.size).sum::<u64>(), content.len() as u64);
+    assert!(chunks.windows(2).all(|pair| pair[0].offset + pair[0].size == pair[1].offset));
+    let edited_chunks = service.get_chunks(&edited_hash, &scope).unwrap();
+    let shared = edited_chunks.iter().filter(|chunk| chunks.contains(chunk)).count();
+    assert!(shared >= chunks.len() - 2);
+    let stored = std::fs::read_dir(base.join("chunks").join("chunks")).unwrap().count();
+    assert_eq!(stored, chunks.len() + edited_chunks.len() - shared);
+
+    // the files are read from their chunks, and a chunk can be copied by its hash
+    let mut copied = Vec::new();
+    let mut start = 0;
+    loop {
+        let data = service.get_file_data(start, start + 100 * 1000, hash.clone(), &scope).unwrap();
+        copied.extend(general_purpose::STANDARD.decode(data.data).unwrap());
+        start = data.end;
+        if data.last_data {
+            break;
+        }
+    }
+    assert_eq!(copied, content);
+    let chunk = &chunks[1];
+    let data = service.get_file_data(0, chunk.size, chunk.hash.clone(), &scope).unwrap();
+    let chunk_range = chunk.offset as usize..(chunk.offset + chunk.size) as usize;
+    assert_eq!(general_purpose::STANDARD.decode(data.data).unwrap(), content[chunk_range]);
+
+    // the stored content outlives the changes of the file, the rules still apply
+    write(base.join("data").join("a.bin"), "changed").unwrap();
+    let data = service.get_file_data(0, 10, hash.clone(), &scope).unwrap();
+    assert_eq!(general_purpose::STANDARD.decode(data.data).unwrap(), content[..10]);
+    let hidden = ClientScope::new(Some("client".to_string()), &["*.bin".to_string()]).unwrap();
+    assert_eq!(service.get_chunks(&edited_hash, &hidden).unwrap_err().code(), "file_not_found");
+
+    // a chunk is only served to the clients that can see a file it is part of
+    assert_eq!(service.get_file_data(0, 10, chunk.hash.clone(), &hidden).unwrap_err().code(), "file_not_found");
+    let edited_only = edited_chunks.iter().find(|chunk| !chunks.contains(chunk)).unwrap();
+    let without_c = ClientScope::new(Some("client".to_string()), &["c.bin".to_string()]).unwrap();
+    assert_eq!(
+        service.get_file_data(0, 10, edited_only.hash.clone(), &without_c).unwrap_err().code(),
+        "file_not_found"
+    );
+    assert!(service.get_file_data(0, 10, edited_only.hash.clone(), &scope).is_ok());
+    assert!(service.get_file_data(0, 10, chunk.hash.clone(), &without_c).is_ok());
+}
+
+#[test]
+fn chunk_key_traversal_test() {
+    let base = temp_dir().join(format!("copy_service_chunk_keys_{}", gen_msg_id()));
+    create_dir_all(base.join("data")).unwrap();
+    write(base.join("data").join("a.bin"), noise(100 * 1024, 3)).unwrap();
+    write(base.join("secret"), "secret").unwrap();
+
+    let files = FileService::new(base.join("data").display().to_string());
+    let service = ChunkFileService::new(files, &base.join("chunks")).unwrap();
+    let scope = ClientScope::default();
+    service.get_tree(&scope).unwrap();
+
+    // only the chunk hashes reach the store, the rest are not found
+    let upper = format!("chunk:{}", "A".repeat(64));
+    let short = format!("chunk:{}", "a".repeat(63));
+    for key in ["chunk:/etc/passwd", "chunk:../../secret", "chunk:../secret", upper.as_str(), short.as_str()] {
+        let err = service.get_file_data(0, 10, key.to_string(), &scope).unwrap_err();
+        assert_eq!(err.code(), "file_not_found", "{}", key);
+    }
+}
@@ -0,0 +1,99 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::{connect, Message};
+
+use cs::file::memory::MemoryFileService;
+use cs::ws::test_server::{read_close, TestServer};
+use cs::ws::ws_message::{ErrRes, TreeRes};
+use cs::ws::WsOptions;
+
+const CLIENT: &str = "keepalive_client";
+
+fn start_server() -> (TestServer<MemoryFileService>, String) {
+    let server = TestServer::start_with(MemoryFileService::new().with_file("A.txt", "x".repeat(1000)), WsOptions {
+        ping_interval: Duration::from_millis(300),
+        idle_timeout: Duration::from_secs(2),
+        auth_timeout: Duration::from_millis(500),
+        ..Default::default()
+    }).unwrap();
+    let key = server.add_client(CLIENT);
+    (server, key)
+}
+
+fn wait_sessions(server: &TestServer<MemoryFileService>, count: usize, timeout: Duration) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if server.handle.sessions.lock().unwrap().list().len() == count {
+            return true;
+        }
+        sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn keepalive_and_timeouts_test() {
+    let (server, key) = start_server();
+
+    // a connection that doesn't authenticate is closed
+    let (mut unauthenticated, _) = connect(server.url()).unwrap();
+    assert_eq!(read_close(&mut unauthenticated), Ok(CloseCode::Policy));
+
+    // invalid messages are answered with an error and the connection goes on
+    let mut socket = server.connect_accepted(CLIENT, &key).unwrap();
+    socket.send(Message::Text("{\"id\": 7, \"type\": \"NoSuchMsg\"}".to_string())).unwrap();
+    let err_res: ErrRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!((err_res.id, err_res.code.as_str()), (7, "invalid_message"));
+    socket.send(Message::Text("not json".to_string())).unwrap();
+    let err_res: ErrRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!((err_res.id, err_res.code.as_str()), (0, "invalid_message"));
+
+    // a quiet client is pinged, answering keeps it alive until the idle timeout
+    let started = Instant::now();
+    let mut pings = 0;
+    let code = loop {
+        match socket.read().unwrap() {
+            Message::Ping(_) => pings += 1,
+            Message::Close(Some(frame)) => break frame.code,
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    };
+    assert_eq!(code, CloseCode::Normal);
+    assert!(pings >= 2);
+    assert!(started.elapsed() >= Duration::from_millis(1500));
+    assert!(socket.read().is_err());
+    assert!(wait_sessions(&server, 0, Duration::from_secs(1)));
+
+    // a peer that doesn't answer the pings is dropped before the idle timeout
+    let _silent = server.connect_accepted(CLIENT, &key).unwrap();
+    assert!(wait_sessions(&server, 1, Duration::from_secs(1)));
+    assert!(wait_sessions(&server, 0, Duration::from_millis(1500)));
+}
+
+#[test]
+fn idle_stream_without_credit_test() {
+    let (server, key) = start_server();
+
+    // the stream waits for credit that never comes, the connection is idle
+    let mut socket = server.connect_accepted(CLIENT, &key).unwrap();
+    socket.send(Message::Text("{\"id\": 2, \"type\": \"TreeMsg\"}".to_string())).unwrap();
+    let tree_res: TreeRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    let hash = tree_res.root.files.unwrap()[0].hash.clone();
+    socket.send(Message::Text(format!(
+        "{{\"id\": 3, \"type\": \"StreamMsg\", \"file_hash\": \"{}\", \"credit\": 0}}", hash
+    ))).unwrap();
+    let started = Instant::now();
+    let code = loop {
+        assert!(started.elapsed() < Duration::from_secs(3), "the idle stream was not closed");
+        match socket.read().unwrap() {
+            Message::Ping(_) => {}
+            Message::Close(Some(frame)) => break frame.code,
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    };
+    assert_eq!(code, CloseCode::Normal);
+    assert!(socket.read().is_err());
+    assert!(wait_sessions(&server, 0, Duration::from_secs(1)));
+}
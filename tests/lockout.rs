@@ -0,0 +1,131 @@
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use actix_web::{test, web::Data, App};
+
+use cs::api::lockouts::{lift_lockout_endpoint, lockouts_api_endpoint};
+use cs::data::audit::{AuditFilter, AUTH_FAILURE, AUTH_LOCKOUT};
+use cs::data::lockouts::Lockout;
+use cs::data::DataService;
+use cs::file::memory::MemoryFileService;
+use cs::ws::auth_guard::{AuthGuard, AuthPolicy, LOCKOUT_CLIENT, LOCKOUT_PEER, MAX_TRACKED_SUBJECTS, UNKNOWN_CLIENT};
+use cs::ws::test_server::TestServer;
+use cs::ws::ws_message::AuthRes;
+use cs::ws::WsOptions;
+
+fn auth(server: &TestServer<MemoryFileService>, name: &str, key: &str) -> AuthRes {
+    server.connect(name, key).unwrap().1
+}
+
+#[actix_web::test]
+async fn auth_backoff_and_lockout_test() {
+    let policy = AuthPolicy {
+        max_failures: 3,
+        backoff: Duration::from_millis(500),
+        lockout: Duration::from_secs(60),
+    };
+    let server = TestServer::start_with(
+        MemoryFileService::new(), WsOptions { auth_policy: policy, ..Default::default() },
+    ).unwrap();
+    let data_ins = Data::clone(&server.data);
+    let key = server.add_client("lockout_client");
+    let client = data_ins.lock().unwrap().get_client_by_name("lockout_client".to_string()).unwrap();
+
+    // accepting a connection can take a poll interval, the backoff is well above it
+    assert_eq!(auth(&server, "lockout_client", "wrong").status, "denied");
+    // even the right key waits for the backoff
+    let res = auth(&server, "lockout_client", &key);
+    assert_eq!((res.status.as_str(), res.retry_after), ("retry_later", Some(1)));
+
+    sleep(Duration::from_millis(600));
+    assert_eq!(auth(&server, "lockout_client", "wrong").status, "denied");
+    // the backoff doubles
+    sleep(Duration::from_millis(600));
+    assert_eq!(auth(&server, "lockout_client", "wrong").status, "retry_later");
+    sleep(Duration::from_millis(500));
+    assert_eq!(auth(&server, "lockout_client", "wrong").status, "denied");
+
+    // the third failure locks out the client name and the peer address
+    let res = auth(&server, "lockout_client", &key);
+    assert_eq!(res.status, "locked_out");
+    assert!(res.retry_after.unwrap() > 50);
+    assert_eq!(auth(&server, "other_client", "wrong").status, "locked_out");
+    let audit = data_ins.lock().unwrap().get_audit_entries(&AuditFilter {
+        event: Some(AUTH_LOCKOUT.to_string()),
+        ..Default::default()
+    });
+    assert_eq!(audit.len(), 2);
+    // the attempts turned away only write a row now and then
+    for _ in 0..10 {
+        assert_eq!(auth(&server, "lockout_client", "wrong").status, "locked_out");
+    }
+    let failures = data_ins.lock().unwrap().get_audit_entries(&AuditFilter {
+        event: Some(AUTH_FAILURE.to_string()),
+        ..Default::default()
+    });
+    assert_eq!(failures.iter().filter(|entry| entry.detail.is_none()).count(), 3);
+    assert_eq!(failures.len(), 4);
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::clone(&data_ins))
+            .app_data(Data::clone(&server.handle.auth_guard))
+            .service(lockouts_api_endpoint)
+            .service(lift_lockout_endpoint)
+    ).await;
+    let req = test::TestRequest::get().uri("/api/lockouts?active=true").to_request();
+    let lockouts: Vec<Lockout> = test::call_and_read_body_json(&app, req).await;
+    let mut subjects: Vec<(String, String)> = lockouts
+        .iter()
+        .map(|lockout| (lockout.kind.clone(), lockout.subject.clone()))
+        .collect();
+    subjects.sort();
+    assert_eq!(subjects, vec![
+        (LOCKOUT_CLIENT.to_string(), client.id.unwrap().to_string()),
+        (LOCKOUT_PEER.to_string(), "127.0.0.1".to_string()),
+    ]);
+    assert!(lockouts.iter().all(|lockout| lockout.failures == 3));
+
+    for lockout in &lockouts {
+        let req = test::TestRequest::delete().uri(&format!("/api/lockouts/{}", lockout.id)).to_request();
+        let lifted: Lockout = test::call_and_read_body_json(&app, req).await;
+        assert!(lifted.lifted_at.is_some());
+    }
+    let req = test::TestRequest::delete().uri(&format!("/api/lockouts/{}", lockouts[0].id)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), 404);
+
+    // the lifted lockouts stay in the history
+    let req = test::TestRequest::get().uri("/api/lockouts").to_request();
+    let history: Vec<Lockout> = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(history.len(), 2);
+    assert_eq!(auth(&server, "lockout_client", &key).status, "accepted");
+}
+
+#[actix_web::test]
+async fn auth_guard_subjects_test() {
+    let mut guard = AuthGuard::new(AuthPolicy::default());
+    let now = Instant::now();
+    // the peers of a flood only keep the latest ones
+    for i in 0..MAX_TRACKED_SUBJECTS + 100 {
+        let subjects = [(LOCKOUT_PEER, format!("10.0.{}.{}", i / 256, i % 256)), (LOCKOUT_CLIENT, UNKNOWN_CLIENT.to_string())];
+        guard.record_failure(&subjects, now + Duration::from_millis(i as u64));
+        guard.turn_away(&subjects, now + Duration::from_millis(i as u64));
+    }
+    assert!(guard.tracked() <= MAX_TRACKED_SUBJECTS);
+    let latest = (LOCKOUT_PEER, format!("10.0.{}.{}", (MAX_TRACKED_SUBJECTS + 99) / 256, (MAX_TRACKED_SUBJECTS + 99) % 256));
+    assert!(guard.backoff_remaining(&[latest], now + Duration::from_millis(MAX_TRACKED_SUBJECTS as u64 + 100)).is_some());
+    assert!(guard.backoff_remaining(&[(LOCKOUT_PEER, "10.0.0.0".to_string())], now).is_none());
+}
+
+#[actix_web::test]
+async fn lift_every_lockout_of_the_subject_test() {
+    let data_service = DataService::open(":memory:".to_string(), 0).unwrap();
+    let first = data_service.add_lockout(LOCKOUT_PEER, "10.0.0.1", 3, 60);
+    let second = data_service.add_lockout(LOCKOUT_PEER, "10.0.0.1", 3, 120);
+    let other = data_service.add_lockout(LOCKOUT_PEER, "10.0.0.2", 3, 60);
+
+    assert!(data_service.lift_lockout(first.id).unwrap().lifted_at.is_some());
+    assert!(data_service.active_lockout(LOCKOUT_PEER, "10.0.0.1").is_none());
+    assert!(data_service.get_lockout(second.id).unwrap().lifted_at.is_some());
+    assert!(data_service.get_lockout(other.id).unwrap().lifted_at.is_none());
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use tungstenite::Message;
+
+use cs::data::audit::{AuditFilter, TREE_REQUEST};
+use cs::file::ignore::ClientScope;
+use cs::file::scan::ScanProgress;
+use cs::file::{FileError, ProvideFile, ReadedData};
+use cs::ws::ws_message::{Directory, NoticeRes, TreeRes, NOTICE_SCAN_PROGRESS};
+use cs::ws::test_server::TestServer;
+use cs::ws::WsOptions;
+
+/** Provider whose scans hash a file every 100 milliseconds */
+struct SlowScan {
+    progress: Arc<ScanProgress>,
+}
+
+impl ProvideFile for SlowScan {
+    fn get_tree(&self, _scope: &ClientScope) -> Result<Directory, String> {
+        let _scan = self.progress.start();
+        for _ in 0..5 {
+            self.progress.found(false);
+        }
+        for _ in 0..5 {
+            sleep(Duration::from_millis(100));
+            self.progress.hashed();
+        }
+        Ok(Directory {
+            name: "root".to_string(),
+            path: None,
+            files: Some(Vec::new()),
+            dirs: Some(Vec::new()),
+            links: Some(Vec::new()),
+            errors: Some(Vec::new()),
+            attributes: Default::default(),
+        })
+    }
+
+    fn get_file_data(
+        &self,
+        _start: u64,
+        _end: u64,
+        file_key: String,
+        _scope: &ClientScope,
+    ) -> Result<ReadedData, FileError> {
+        Err(FileError::NotFound(file_key))
+    }
+
+    fn scan_progress(&self) -> Option<Arc<ScanProgress>> {
+        Some(self.progress.clone())
+    }
+
+    fn served_paths(&self) -> Vec<String> {
+        vec!["/slow".to_string(), "photos=/srv/photos".to_string()]
+    }
+}
+
+#[test]
+fn scan_progress_notice_test() {
+    let server = TestServer::start_with(
+        SlowScan {
+            progress: Arc::new(ScanProgress::new()),
+        },
+        WsOptions {
+            scan_notice_interval: Duration::from_millis(150),
+            ..Default::default()
+        },
+    ).unwrap();
+    let key = server.add_client("scan_client");
+    let (mut socket, auth_res) = server.connect("scan_client", &key).unwrap();
+    assert_eq!(auth_res.status, "accepted");
+
+    socket.send(Message::Text("{\"id\": 7, \"type\": \"TreeMsg\"}".to_string())).unwrap();
+    let mut notices: Vec<NoticeRes> = Vec::new();
+    let tree = loop {
+        let text = socket.read().unwrap().to_string();
+        if text.contains(NOTICE_SCAN_PROGRESS) {
+            notices.push(serde_json::from_str(&text).unwrap());
+            continue;
+        }
+        break serde_json::from_str::<TreeRes>(&text).unwrap();
+    };
+    assert_eq!(tree.id, 7);
+    assert!(!notices.is_empty());
+    for notice in &notices {
+        assert_eq!(notice.id, 7);
+        assert_eq!(notice.total, Some(5));
+        assert_eq!(notice.detail, format!("scan in progress: {} of 5 files", notice.done.unwrap()));
+    }
+    // the notices follow the scan
+    assert!(notices.windows(2).all(|pair| pair[0].done <= pair[1].done));
+
+    let audit = server.data.lock().unwrap().get_audit_entries(&AuditFilter {
+        event: Some(TREE_REQUEST.to_string()),
+        ..Default::default()
+    });
+    assert_eq!(audit.len(), 1);
+    assert_eq!(audit[0].detail.as_deref(), Some("/slow, photos=/srv/photos"));
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::Ordering;
+use std::thread::spawn;
+use std::time::Duration;
+
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message;
+
+use cs::file::memory::MemoryFileService;
+use cs::ws::test_server::{read_close, TestServer, TestSocket};
+use cs::ws::ws_message::{CopyRes, NoticeRes, TreeRes, NOTICE_GOING_AWAY};
+use test_utils::gen_msg_id;
+
+const CLIENT: &str = "shutdown_client";
+
+fn copy(socket: &mut TestSocket, hash: &str, start: u64, end: u64) -> CopyRes {
+    socket.send(Message::Text(format!(
+        "{{\"id\": {}, \"start\": {}, \"end\": {}, \"file_hash\": \"{}\", \"type\": \"CopyMsg\"}}",
+        gen_msg_id(), start, end, hash
+    ))).unwrap();
+    serde_json::from_str(&socket.read().unwrap().to_string()).unwrap()
+}
+
+fn read_going_away(socket: &mut TestSocket) {
+    let notice: NoticeRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!(notice.notice, NOTICE_GOING_AWAY);
+}
+
+#[test]
+fn shutdown_drains_transfers_test() {
+    let server = TestServer::start(MemoryFileService::new().with_file("A.txt", "x".repeat(500000))).unwrap();
+    let key = server.add_client(CLIENT);
+
+    let mut idle = server.connect_accepted(CLIENT, &key).unwrap();
+    let mut copying = server.connect_accepted(CLIENT, &key).unwrap();
+    copying.send(Message::Text(format!("{{\"id\": {}, \"type\": \"TreeMsg\"}}", gen_msg_id()))).unwrap();
+    let tree_res: TreeRes = serde_json::from_str(&copying.read().unwrap().to_string()).unwrap();
+    let hash = tree_res.root.files.unwrap()[0].hash.clone();
+    assert!(!copy(&mut copying, &hash, 0, 300000).last_data);
+
+    let shutdown_server = server.handle.clone();
+    let shutdown = spawn(move || shutdown_server.shutdown(Duration::from_secs(10)));
+
+    // the idle connection is closed right away
+    read_going_away(&mut idle);
+    assert_eq!(read_close(&mut idle), Ok(CloseCode::Away));
+
+    // the transfer in flight can be finished before closing
+    read_going_away(&mut copying);
+    assert!(copy(&mut copying, &hash, 300000, 500000).last_data);
+    assert_eq!(read_close(&mut copying), Ok(CloseCode::Away));
+
+    assert_eq!(shutdown.join().unwrap(), 0);
+    assert!(server.handle.state.shutting_down.load(Ordering::SeqCst));
+    assert!(server.handle.sessions.lock().unwrap().list().is_empty());
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use cs::api::api::Client;
+use cs::data::DataService;
+use cs::throttle::{parse_utc_offset, RateSchedule, RateWindow, Throttle, ThrottleSettings};
+
+fn night_schedule() -> RateSchedule {
+    RateSchedule {
+        rate: Some(1000),
+        windows: vec![RateWindow {
+            from: "22:00".to_string(),
+            to: "06:30".to_string(),
+            rate: None,
+        }],
+    }
+}
+
+#[test]
+fn rate_schedule_test() {
+    let schedule = night_schedule();
+    assert!(schedule.validate().is_ok());
+    assert_eq!(schedule.rate_at(12 * 60), Some(1000));
+    assert_eq!(schedule.rate_at(21 * 60 + 59), Some(1000));
+    assert_eq!(schedule.rate_at(22 * 60), None);
+    assert_eq!(schedule.rate_at(3 * 60), None);
+    assert_eq!(schedule.rate_at(6 * 60 + 30), Some(1000));
+
+    let mut invalid = night_schedule();
+    invalid.windows[0].to = "24:00".to_string();
+    assert!(invalid.validate().is_err());
+    let zero = RateSchedule { rate: Some(0), windows: vec![] };
+    assert!(zero.validate().is_err());
+}
+
+#[test]
+fn utc_offset_test() {
+    assert_eq!(parse_utc_offset("+00:00"), Ok(0));
+    assert_eq!(parse_utc_offset("+02:00"), Ok(120));
+    assert_eq!(parse_utc_offset("-05:30"), Ok(-330));
+    assert!(parse_utc_offset("02:00").is_err());
+    assert!(parse_utc_offset("+24:00").is_err());
+}
+
+#[test]
+fn throttle_take_test() {
+    let mut clients = BTreeMap::new();
+    clients.insert(1, RateSchedule { rate: Some(1000), windows: vec![] });
+    let mut throttle = Throttle::new(ThrottleSettings {
+        global: RateSchedule { rate: Some(10000), windows: vec![] },
+        clients,
+    });
+    let now = Instant::now();
+    let noon = 12 * 60;
+
+    // a full bucket lets one second of data out right away, then the debt is paid waiting
+    assert_eq!(throttle.take_at(Some(1), 1000, now, noon), Duration::ZERO);
+    assert_eq!(throttle.take_at(Some(1), 500, now, noon), Duration::from_millis(500));
+    assert_eq!(throttle.take_at(Some(1), 500, now + Duration::from_millis(500), noon), Duration::from_millis(500));
+
+    // other clients only have the global limit
+    let later = now + Duration::from_millis(500);
+    assert_eq!(throttle.take_at(Some(2), 9500, later, noon), Duration::ZERO);
+    assert_eq!(throttle.take_at(None, 1000, later, noon), Duration::from_millis(100));
+
+    // the pieces sent at once are no bigger than the smallest bucket
+    assert_eq!(throttle.max_piece_at(Some(1), noon), Some(1000));
+    assert_eq!(throttle.max_piece_at(Some(2), noon), Some(10000));
+
+    // removing the limits applies to the next chunk
+    throttle.set_settings(ThrottleSettings::default());
+    assert_eq!(throttle.take_at(Some(1), 1_000_000, now, noon), Duration::ZERO);
+    assert_eq!(throttle.max_piece_at(Some(1), noon), None);
+}
+
+#[test]
+fn rate_limits_persistence_test() {
+    let data_service = DataService::open(":memory:".to_string(), 0).unwrap();
+    let client = data_service.new_client(Client {
+        id: None,
+        key: None,
+        name: Some("rate_limited".to_string()),
+        max_connections: None,
+        limit_policy: None,
+    });
+
+    assert_eq!(data_service.get_throttle_settings().unwrap(), ThrottleSettings::default());
+
+    data_service.set_global_rate_limit(&night_schedule()).unwrap();
+    data_service.set_client_rate_limit(client.id.unwrap(), Some(&RateSchedule { rate: Some(5), windows: vec![] })).unwrap();
+    let settings = data_service.get_throttle_settings().unwrap();
+    assert_eq!(settings.global, night_schedule());
+    assert_eq!(settings.clients[&client.id.unwrap()].rate, Some(5));
+
+    data_service.set_client_rate_limit(client.id.unwrap(), None).unwrap();
+    assert!(data_service.get_throttle_settings().unwrap().clients.is_empty());
+    assert!(data_service.set_client_rate_limit(-1, None).is_err());
+    assert!(data_service.set_global_rate_limit(&RateSchedule { rate: Some(0), windows: vec![] }).is_err());
+}
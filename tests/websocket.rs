@@ -1,129 +1,101 @@
+#![allow(clippy::needless_return)]
+
 extern crate test_utils;
 
 use std::borrow::Cow;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::net::TcpStream;
 use std::string::ToString;
-use std::sync::{Mutex, Once};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
 
 use actix_web::web::Data;
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::App;
 use base64::{engine::general_purpose, Engine as _};
-use lazy_static::lazy_static;
+use tungstenite::protocol::frame::coding::CloseCode;
 use tungstenite::protocol::CloseFrame;
 use tungstenite::stream::MaybeTlsStream;
-use tungstenite::{connect, Message, WebSocket};
-
-use cs::api::api::Client;
-use cs::data::DataService;
-use cs::ws::start_websocket_server;
-use cs::ws::ws_message::{AuthRes, CopyRes, TreeRes};
-use test_utils::{current_dir_path, gen_msg_id, setting_up_test_file_tree};
-
-static PORT: i32 = 9004;
-
-
-static BEFORE_ALL: Once = Once::new();
-
-lazy_static! {
-    static ref DATA_INS: Data<Mutex<DataService>> = Data::new(Mutex::new(DataService::new()));
-
-    // test with FileService
-    static ref FILE_INS: Data<Mutex<cs::file::FileService>> = Data::new(Mutex::new(cs::file::FileService::new(
-        format!("{}/{}", current_dir_path(), "data-test")
-    )));
+use tungstenite::{connect, Message};
+
+use cs::api::api::{Client, LimitPolicy};
+use cs::data::audit::{AuditFilter, FILE_TRANSFER};
+use cs::throttle::RateSchedule;
+use cs::api::sessions::{close_session_endpoint, sessions_api_endpoint};
+use cs::file::ignore::ClientScope;
+use cs::file::memory::{Fault, MemoryFileService};
+use cs::file::ProvideFile;
+use cs::ws::session::Session;
+use cs::ws::test_server::{TestServer, TestSocket};
+use cs::ws::WsOptions;
+use cs::ws::ws_message::{AuthMsg, AuthRes, CancelRes, CopyRes, ErrRes, File, TreeRes, VersionsRes};
+use test_utils::gen_msg_id;
+
+static MAX_CHUNK_SIZE: u64 = 300000;
+static FILE_SIZE: usize = 500000;
+
+/** Content of the test file, without repeating itself every few bytes */
+fn file_content() -> Vec<u8> {
+    (0..FILE_SIZE).map(|i| ((i * 7 + i / 1000) % 256) as u8).collect()
 }
 
-fn before_all() {
-    let data_ins = DATA_INS.clone();
-    let file_ins = FILE_INS.clone();
-    BEFORE_ALL.call_once(|| {
-        // start websocket connection
-        start_websocket_server(Data::clone(&data_ins), Data::clone(&file_ins), PORT);
-
-        // testing files
-        setting_up_test_file_tree("data-test".to_string())
-    });
+fn test_files() -> MemoryFileService {
+    MemoryFileService::new()
+        .with_file("A.txt", file_content())
+        .with_file("dir1/A/B/C.txt", "nested file")
 }
 
-fn create_mock_clients(clients: Vec<String>) {
-    for name in clients {
-        let data_service = DATA_INS.clone();
-        data_service.lock().unwrap().new_client(Client {
-            id: None,
-            key: None,
-            name: Some(name),
-        });
-    }
+fn start_server(files: MemoryFileService) -> TestServer<MemoryFileService> {
+    TestServer::start_with(files, WsOptions {
+        max_chunk_size: MAX_CHUNK_SIZE,
+        ..Default::default()
+    }).unwrap()
 }
 
 fn start_socket_with_auth(
+    server: &TestServer<MemoryFileService>,
     client_name: String,
     key: String,
     expected_connection_status: bool,
-) -> Option<WebSocket<MaybeTlsStream<TcpStream>>> {
-    // connect mock client to the websocket server
-    let mut socket = loop {
-        match connect(format!("ws://localhost:{}/websocket", PORT)) {
-            Ok((socket, _)) => break socket,
-            Err(_) => continue,
-        };
-    };
-
-    let id: i32 = gen_msg_id();
-
-    // create authentication message
-    let auth_msg = String::from(format!(
-        "{{\"id\": {}, \"name\": \"{}\", \"key\":\"{}\", \"type\":\"AuthMsg\"}}",
-        id, client_name, key
-    ));
-
-    // send auth message to the server
-    socket.send(Message::Text(auth_msg)).unwrap();
+) -> Option<TestSocket> {
+    start_socket_with_auth_status(
+        server,
+        client_name,
+        key,
+        if expected_connection_status { "accepted" } else { "denied" },
+    )
+}
 
-    // get response from server
-    let msg_res = socket.read().expect("Error reading message");
-    let auth_res: AuthRes = serde_json::from_str(&*msg_res.to_string()).unwrap();
+fn start_socket_with_auth_status(
+    server: &TestServer<MemoryFileService>,
+    client_name: String,
+    key: String,
+    expected_status: &str,
+) -> Option<TestSocket> {
+    let (socket, auth_res) = server.connect(&client_name, &key).unwrap();
 
     // validate response
-    assert_eq!(auth_res.id, id);
-    assert_eq!(
-        auth_res.status,
-        if expected_connection_status {
-            "accepted"
-        } else {
-            "denied"
-        }
-    );
+    assert_eq!(auth_res.id, 1);
+    assert_eq!(auth_res.status, expected_status);
 
     return Some(socket);
 }
 
-fn get_client_key(client_name: String) -> String {
-    let data_service = DATA_INS.clone();
-    return data_service
-        .lock()
-        .unwrap()
-        .get_client_key(client_name.clone())
-        .unwrap();
+/** Server with the test files and an authenticated connection of the client */
+fn connected_client(client_name: &str) -> (TestServer<MemoryFileService>, TestSocket) {
+    let server = start_server(test_files());
+    let key = server.add_client(client_name);
+    let socket = start_socket_with_auth(&server, client_name.to_string(), key, true).unwrap();
+    return (server, socket);
 }
 
 #[test]
 fn ws_auth_test() {
-    before_all();
-    let data_service = DATA_INS.clone();
-
-    // create dummy data for test
+    let server = start_server(test_files());
     let client_name = "client_auth_test".to_string();
-
-    // create mock clients
-    create_mock_clients(vec![client_name.clone()]);
-
-    // get client key
-    let key = get_client_key(client_name.clone());
+    let key = server.add_client(&client_name);
 
     // start websocket server and get connected with authorization
-    let mut socket = start_socket_with_auth(client_name.clone(), key.clone(), true).unwrap();
+    let mut socket = start_socket_with_auth(&server, client_name.clone(), key.clone(), true).unwrap();
 
     // close websocket connection
     socket
@@ -134,139 +106,572 @@ fn ws_auth_test() {
         .unwrap();
     // wait until the server update the client connection status
     loop {
-        if !data_service
-            .lock()
-            .unwrap()
-            .connection_status
-            .contains(&client_name)
-        {
+        if server.handle.sessions.lock().unwrap().client_sessions(&client_name).is_empty() {
             break;
         }
     }
-    assert!(!data_service
-        .lock()
-        .unwrap()
-        .connection_status
-        .contains(&client_name));
+    assert!(server.handle.sessions.lock().unwrap().client_sessions(&client_name).is_empty());
 }
 
 #[test]
 fn ws_auth_with_wrong_password_test() {
-    before_all();
-
-    // create dummy data for test
+    let server = start_server(test_files());
     let client_name = "client2".to_string();
+    server.add_client(&client_name);
 
-    create_mock_clients(vec![client_name.clone()]);
-
-    // start websocket server and get connected with authorization
-    start_socket_with_auth(client_name.clone(), "not_valid_key".to_string(), false).unwrap();
+    start_socket_with_auth(&server, client_name.clone(), "not_valid_key".to_string(), false).unwrap();
 }
 
-fn get_tree(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, id: i32) -> TreeRes {
-    let get_tree_msg = String::from(format!("{{\"id\": {},\"type\":\"TreeMsg\"}}", id));
+fn get_tree(socket: &mut TestSocket, id: i32) -> TreeRes {
+    let get_tree_msg = format!("{{\"id\": {},\"type\":\"TreeMsg\"}}", id);
 
     // send auth message to the server
     socket.send(Message::Text(get_tree_msg)).unwrap();
 
     // get response from server
     let msg_res = socket.read().expect("Error reading message");
-    let tree_res: TreeRes = serde_json::from_str(&*msg_res.to_string()).unwrap();
+    let tree_res: TreeRes = serde_json::from_str(&msg_res.to_string()).unwrap();
 
     return tree_res;
 }
 
+fn first_file(socket: &mut TestSocket) -> File {
+    return get_tree(socket, gen_msg_id()).root.files.unwrap().first().unwrap().clone();
+}
+
 #[test]
 fn ws_get_files_tree_test() {
-    before_all();
-
-    // create dummy data for test
-    let client_name = "client_get_file_tree".to_string();
-
-    create_mock_clients(vec![client_name.clone()]);
-
-    let key = get_client_key(client_name.clone());
-
-    // start websocket server and get connected with authorization
-    let mut socket = start_socket_with_auth(client_name.clone(), key, true).unwrap();
+    let (_server, mut socket) = connected_client("client_get_file_tree");
 
     let id: i32 = gen_msg_id();
     let tree_res = get_tree(&mut socket, id);
-    // print!("{msg_res}");
 
     assert_eq!(tree_res.id, id);
     assert_eq!(tree_res.root.name.clone(), "root".to_string());
+    let dir1 = tree_res.root.dirs.unwrap().first().unwrap().clone();
+    assert_eq!(dir1.name, "dir1");
+    assert_eq!(dir1.dirs.unwrap()[0].dirs.as_ref().unwrap()[0].files.as_ref().unwrap()[0].name, "C.txt");
 }
 
-// #[ignore = "temporaly"]
 #[test]
 fn ws_copy_file_test() {
-    before_all();
-
-    // create dummy data for test
-    let client_name = "client_copy_test".to_string();
+    let (server, mut socket) = connected_client("client_copy_test");
 
-    create_mock_clients(vec![client_name.clone()]);
-
-    let key = get_client_key(client_name.clone());
-
-    // start websocket server and get connected with authorization
-    let mut socket = start_socket_with_auth(client_name.clone(), key, true).unwrap();
-
-    // request tree from server
-    let id: i32 = gen_msg_id();
-    let tree_res = get_tree(&mut socket, id);
-
-    let file_name = tree_res.root.files.unwrap().get(0).unwrap().clone();
-    let file_path = format!(
-        "{}/{}/{}",
-        current_dir_path(),
-        "data-test",
-        file_name.name.clone()
-    );
-    let file = File::open(file_path.clone()).unwrap();
+    let file = first_file(&mut socket);
+    let content = file_content();
     let read_size = 300000;
-    let mut reader = BufReader::with_capacity(read_size, file);
-
     let mut start = 0;
 
-    loop {
-        let buffer = reader.fill_buf().unwrap();
-        let readed_size = buffer.len();
-        if readed_size == 0 {
-            break;
-        }
-
+    while start < content.len() {
         // send the copy request to the server
         let id: i32 = gen_msg_id();
         let end = start + read_size;
-        let file_hash = file_name.hash.clone();
+        let file_hash = file.hash.clone();
 
-        let copy_msg = String::from(format!("{{\"type\":\"CopyMsg\", \"id\": {id}, \"start\": {start}, \"end\": {end}, \"file_hash\": \"{file_hash}\"}}", ));
-        println!("{copy_msg}");
-
-        // send auth message to the server
+        let copy_msg = format!("{{\"type\":\"CopyMsg\", \"id\": {id}, \"start\": {start}, \"end\": {end}, \"file_hash\": \"{file_hash}\"}}");
         socket.send(Message::Text(copy_msg)).unwrap();
 
         // get response from server
         let msg_res = socket.read().expect("Error reading message");
-        let copy_res: CopyRes = serde_json::from_str(&*msg_res.to_string()).unwrap();
-
-        println!("{:?}", copy_res);
+        let copy_res: CopyRes = serde_json::from_str(&msg_res.to_string()).unwrap();
 
         // validate CopyRes information
+        let readed_size = (content.len() - start).min(read_size);
         assert_eq!(copy_res.id, id);
         assert_eq!(copy_res.start, u64::try_from(start).unwrap());
         assert_eq!(copy_res.end, u64::try_from(start + readed_size).unwrap());
 
         // validate data
         let data_bytes = general_purpose::STANDARD.decode(copy_res.data).unwrap();
-        for i in [0..data_bytes.len()] {
-            assert_eq!(data_bytes[i.clone()], buffer[i]);
-        }
+        assert_eq!(data_bytes[..], content[start..start + readed_size]);
 
-        // free buffer reader
         start += readed_size;
-        reader.consume(readed_size);
     }
+
+    // the chunks are one transfer, audited once the last one is sent
+    let filter = AuditFilter {
+        event: Some(FILE_TRANSFER.to_string()),
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let mut audit = server.data.lock().unwrap().get_audit_entries(&filter);
+    while audit.is_empty() && started.elapsed() < Duration::from_secs(2) {
+        sleep(Duration::from_millis(10));
+        audit = server.data.lock().unwrap().get_audit_entries(&filter);
+    }
+    assert_eq!(audit.len(), 1);
+    assert_eq!(audit[0].file_hash.as_deref(), Some(file.hash.as_str()));
+    assert_eq!(audit[0].bytes, Some(FILE_SIZE as i64));
+    assert_eq!(audit[0].detail, None);
+}
+
+fn send_copy(socket: &mut TestSocket, id: i32, range: &str, file_hash: &str) {
+    let copy_msg = format!("{{\"type\":\"CopyMsg\", \"id\": {id}, {range} \"file_hash\": \"{file_hash}\"}}");
+    socket.send(Message::Text(copy_msg)).unwrap();
+}
+
+fn read_err(socket: &mut TestSocket, id: i32) -> ErrRes {
+    let err_res: ErrRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!(err_res.id, id);
+    return err_res;
+}
+
+#[test]
+fn ws_copy_range_validation_test() {
+    let (_server, mut socket) = connected_client("client_copy_range");
+
+    let file = first_file(&mut socket);
+
+    // a huge end is limited to the maximum chunk size
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, &format!("\"start\": 0, \"end\": {},", u64::MAX), &file.hash);
+    let copy_res: CopyRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!((copy_res.start, copy_res.end), (0, MAX_CHUNK_SIZE));
+    assert!(!copy_res.last_data);
+
+    // the errors don't close the connection
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, &format!("\"start\": {}, \"end\": {},", file.size + 1, file.size + 10), &file.hash);
+    assert_eq!(read_err(&mut socket, id).code, "range_not_satisfiable");
+
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 10, \"end\": 5,", &file.hash);
+    assert_eq!(read_err(&mut socket, id).code, "invalid_range");
+
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 5,", "not_a_hash");
+    assert_eq!(read_err(&mut socket, id).code, "file_not_found");
+
+    // without an end the rest of the file comes in chunks
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 100,", &file.hash);
+    let mut chunks = Vec::new();
+    loop {
+        let copy_res: CopyRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+        assert_eq!(copy_res.id, id);
+        assert!(copy_res.end - copy_res.start <= MAX_CHUNK_SIZE);
+        chunks.push((copy_res.start, copy_res.end));
+        if copy_res.last_data {
+            break;
+        }
+    }
+    assert_eq!(chunks, vec![(100, 100 + MAX_CHUNK_SIZE), (100 + MAX_CHUNK_SIZE, file.size)]);
+}
+
+fn read_copy(socket: &mut TestSocket, id: i32) -> CopyRes {
+    let copy_res: CopyRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!(copy_res.id, id);
+    return copy_res;
+}
+
+#[test]
+fn ws_stream_with_credit_test() {
+    let (_server, mut socket) = connected_client("client_stream_credit");
+
+    let file = first_file(&mut socket);
+    let stream_id = gen_msg_id();
+    let stream_msg = format!(
+        "{{\"type\":\"StreamMsg\", \"id\": {stream_id}, \"file_hash\": \"{}\", \"credit\": 1, \"chunk_size\": 100000}}",
+        file.hash
+    );
+    socket.send(Message::Text(stream_msg.clone())).unwrap();
+    assert_eq!(read_copy(&mut socket, stream_id).end, 100000);
+
+    // nothing else comes until the client grants more credit
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    }
+    assert!(socket.read().is_err());
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        stream.set_read_timeout(None).unwrap();
+    }
+
+    socket.send(Message::Text(format!("{{\"type\":\"CreditMsg\", \"id\": {stream_id}, \"credit\": 2}}"))).unwrap();
+    assert_eq!(read_copy(&mut socket, stream_id).end, 200000);
+    assert_eq!(read_copy(&mut socket, stream_id).end, 300000);
+
+    // one stream at a time
+    socket.send(Message::Text(stream_msg)).unwrap();
+    assert_eq!(read_err(&mut socket, stream_id).code, "stream_in_progress");
+
+    socket.send(Message::Text(format!("{{\"type\":\"CancelMsg\", \"id\": {stream_id}}}"))).unwrap();
+    let cancel_res: CancelRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!((cancel_res.id, cancel_res.end), (stream_id, 300000));
+
+    socket.send(Message::Text(format!("{{\"type\":\"CreditMsg\", \"id\": {stream_id}, \"credit\": 1}}"))).unwrap();
+    assert_eq!(read_err(&mut socket, stream_id).code, "unknown_stream");
+
+    // resume from where it stopped with enough credit for the rest
+    let stream_id = gen_msg_id();
+    socket.send(Message::Text(format!(
+        "{{\"type\":\"StreamMsg\", \"id\": {stream_id}, \"file_hash\": \"{}\", \"start\": 300000, \"credit\": 10, \"chunk_size\": 100000}}",
+        file.hash
+    ))).unwrap();
+    assert!(!read_copy(&mut socket, stream_id).last_data);
+    let last = read_copy(&mut socket, stream_id);
+    assert_eq!(last.end, file.size);
+    assert!(last.last_data);
+}
+
+#[test]
+fn ws_throttled_copy_test() {
+    let client_name = "client_throttled_copy".to_string();
+    let (server, mut socket) = connected_client(&client_name);
+    let file = first_file(&mut socket);
+    let client_id = server.data.lock().unwrap().get_client_by_name(client_name.clone()).unwrap().id.unwrap();
+
+    let set_rate = |rate: Option<u64>| {
+        let mut settings = server.handle.throttle.lock().unwrap().settings();
+        settings.clients.insert(client_id, RateSchedule { rate, windows: vec![] });
+        server.handle.throttle.lock().unwrap().set_settings(settings);
+    };
+    let copy_chunks = |socket: &mut TestSocket| {
+        let started = Instant::now();
+        for start in [0, 100000, 200000, 0] {
+            let id = gen_msg_id();
+            send_copy(socket, id, &format!("\"start\": {start}, \"end\": {},", start + 100000), &file.hash);
+            read_copy(socket, id);
+        }
+        return started.elapsed();
+    };
+
+    // one second of data goes out right away, the rest waits for the bucket
+    set_rate(Some(200000));
+    assert!(copy_chunks(&mut socket) >= Duration::from_millis(400));
+
+    // the limit is lifted without reconnecting
+    set_rate(None);
+    sleep(Duration::from_millis(600));
+    assert!(copy_chunks(&mut socket) < Duration::from_millis(400));
+
+    // a chunk is no bigger than one second of data
+    set_rate(Some(50000));
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 100000,", &file.hash);
+    assert_eq!(read_copy(&mut socket, id).end, 50000);
+
+    // the requests waiting for the bucket don't hold the connection, a close request gets through
+    for start in [50000, 100000] {
+        send_copy(&mut socket, gen_msg_id(), &format!("\"start\": {start}, \"end\": {},", start + 50000), &file.hash);
+    }
+    // the server reads them before the close is requested, a closing session no longer takes requests
+    sleep(Duration::from_millis(100));
+    let session_id = server.handle.sessions.lock().unwrap().client_sessions(&client_name)[0].id;
+    let requested = Instant::now();
+    server.handle.sessions.lock().unwrap().request_close(session_id, "Closed by the test".to_string());
+    loop {
+        match socket.read().unwrap() {
+            Message::Close(_) => break,
+            msg => assert!(msg.is_text()),
+        }
+    }
+    assert!(requested.elapsed() < Duration::from_millis(700));
+}
+
+#[actix_web::test]
+async fn ws_list_and_close_session_test() {
+    let client_name = "client_session_test".to_string();
+    let (server, mut socket) = connected_client(&client_name);
+
+    let app = init_service(
+        App::new()
+            .app_data(Data::clone(&server.handle.sessions))
+            .service(sessions_api_endpoint)
+            .service(close_session_endpoint)
+    ).await;
+
+    // the authenticated connection is listed
+    let req = TestRequest::get().uri("/api/sessions").to_request();
+    let sessions: Vec<Session> = call_and_read_body_json(&app, req).await;
+    let session = sessions
+        .iter()
+        .find(|session| session.client == Some(client_name.clone()))
+        .unwrap()
+        .clone();
+    assert!(session.peer.is_some());
+
+    // closing the session sends a close frame to the client
+    let req = TestRequest::delete()
+        .uri(format!("/api/sessions/{}", session.id).as_str())
+        .to_request();
+    let closed: Session = call_and_read_body_json(&app, req).await;
+    assert_eq!(closed.id, session.id);
+
+    let msg = socket.read().expect("Error reading message");
+    match msg {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Policy),
+        _ => panic!("expected a close frame, got: {:?}", msg),
+    }
+    // reading again flushes the close acknowledgement
+    assert!(socket.read().is_err());
+
+    // wait until the server removes the session
+    while server.handle.sessions.lock().unwrap().get(session.id).is_some() {}
+
+    let req = TestRequest::delete()
+        .uri(format!("/api/sessions/{}", session.id).as_str())
+        .to_request();
+    let res = call_service(&app, req).await;
+    assert_eq!(res.status(), 404);
+}
+
+fn set_connection_limit(server: &TestServer<MemoryFileService>, client_name: String, max_connections: i64, policy: LimitPolicy) {
+    let data_service = server.data.lock().unwrap();
+    let client = data_service.get_client_by_name(client_name).unwrap();
+    data_service.update_client(Client {
+        id: client.id,
+        name: client.name,
+        key: None,
+        max_connections: Some(max_connections),
+        limit_policy: Some(policy),
+    }, false);
+}
+
+#[test]
+fn ws_multiple_connections_per_client_test() {
+    let server = start_server(test_files());
+    let client_name = "client_multiple_connections".to_string();
+    let key = server.add_client(&client_name);
+
+    let mut first = start_socket_with_auth(&server, client_name.clone(), key.clone(), true).unwrap();
+    let mut second = start_socket_with_auth(&server, client_name.clone(), key, true).unwrap();
+
+    // closing one connection keeps the other one authenticated
+    first
+        .close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: Cow::from("Goodbye"),
+        }))
+        .unwrap();
+    while server.handle.sessions.lock().unwrap().client_sessions(&client_name).len() > 1 {}
+
+    let id: i32 = gen_msg_id();
+    let tree_res = get_tree(&mut second, id);
+    assert_eq!(tree_res.id, id);
+}
+
+#[test]
+fn ws_connection_limit_reject_test() {
+    let server = start_server(test_files());
+    let client_name = "client_limit_reject".to_string();
+    let key = server.add_client(&client_name);
+    set_connection_limit(&server, client_name.clone(), 1, LimitPolicy::Reject);
+
+    let mut first = start_socket_with_auth(&server, client_name.clone(), key.clone(), true).unwrap();
+    start_socket_with_auth_status(&server, client_name.clone(), key, "limit_exceeded").unwrap();
+
+    // the existing connection is not affected
+    let id: i32 = gen_msg_id();
+    assert_eq!(get_tree(&mut first, id).id, id);
+}
+
+#[test]
+fn ws_connection_limit_evict_oldest_test() {
+    let server = start_server(test_files());
+    let client_name = "client_limit_evict".to_string();
+    let key = server.add_client(&client_name);
+    set_connection_limit(&server, client_name.clone(), 1, LimitPolicy::EvictOldest);
+
+    let mut first = start_socket_with_auth(&server, client_name.clone(), key.clone(), true).unwrap();
+    let mut second = start_socket_with_auth(&server, client_name.clone(), key, true).unwrap();
+
+    // the oldest connection gets closed
+    match first.read().expect("Error reading message") {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Policy),
+        msg => panic!("expected a close frame, got: {:?}", msg),
+    }
+
+    let id: i32 = gen_msg_id();
+    assert_eq!(get_tree(&mut second, id).id, id);
+}
+
+#[test]
+fn auth_msg_debug_redacts_key_test() {
+    let msg = AuthMsg {
+        id: 1,
+        name: "client_debug".to_string(),
+        key: "secret-key".to_string(),
+        hash_algorithms: None,
+    };
+
+    let debug = format!("{:?}", msg);
+    assert!(debug.contains("client_debug"));
+    assert!(!debug.contains("secret-key"));
+}
+
+#[test]
+fn ws_hash_algorithm_negotiation_test() {
+    let server = start_server(test_files());
+    let client_name = "client_hash_algorithms".to_string();
+    let key = server.add_client(&client_name);
+    let (mut socket, _) = connect(server.url()).unwrap();
+
+    // the unknown algorithms are left out, the data path falls back to the one the client knows
+    let auth_msg = serde_json::json!({
+        "type": "AuthMsg", "id": 1, "name": client_name, "key": key, "hash_algorithms": ["md5", "blake3"],
+    });
+    socket.send(Message::Text(auth_msg.to_string())).unwrap();
+    let auth_res: AuthRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!(auth_res.status, "accepted");
+    assert_eq!(auth_res.hash_algorithms, Some(vec!["blake3".to_string()]));
+
+    let file = first_file(&mut socket);
+    assert!(file.hash.starts_with("blake3:"));
+    let id = gen_msg_id();
+    let copy_msg = format!("{{\"type\":\"CopyMsg\", \"id\": {id}, \"file_hash\": \"{}\", \"start\": 0, \"end\": 10}}", file.hash);
+    socket.send(Message::Text(copy_msg)).unwrap();
+    assert_eq!(read_copy(&mut socket, id).end, 10);
+
+    // the clients that don't offer any algorithm get sha256
+    let (mut socket, auth_res) = server.connect(&client_name, &key).unwrap();
+    assert_eq!(auth_res.hash_algorithms, Some(vec!["sha256".to_string()]));
+    let file = first_file(&mut socket);
+    assert_eq!(file.hash.len(), 64);
+}
+
+#[test]
+fn ws_versions_without_store_test() {
+    let (_server, mut socket) = connected_client("client_versions");
+    let file = first_file(&mut socket);
+
+    let id = gen_msg_id();
+    let versions_msg = serde_json::json!({"type": "VersionsMsg", "id": id, "path": file.name});
+    socket.send(Message::Text(versions_msg.to_string())).unwrap();
+    let versions_res: VersionsRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!(versions_res.id, id);
+    assert_eq!(versions_res.path, file.name);
+    assert!(versions_res.versions.is_empty());
+}
+
+#[test]
+fn ws_copy_io_error_test() {
+    let server = start_server(test_files().with_fault("A.txt", Fault::Io("disk failure".to_string())));
+    let key = server.add_client("client_io_error");
+    let mut socket = start_socket_with_auth(&server, "client_io_error".to_string(), key, true).unwrap();
+    let file = first_file(&mut socket);
+
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 10,", &file.hash);
+    let err_res = read_err(&mut socket, id);
+    assert_eq!(err_res.code, "io_error");
+
+    // the connection goes on
+    let id = gen_msg_id();
+    assert_eq!(get_tree(&mut socket, id).id, id);
+}
+
+#[test]
+fn ws_file_changed_mid_transfer_test() {
+    let server = start_server(test_files().with_fault("A.txt", Fault::ChangeAfter(1)));
+    let key = server.add_client("client_changed");
+    let mut socket = start_socket_with_auth(&server, "client_changed".to_string(), key, true).unwrap();
+    let file = first_file(&mut socket);
+
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 100000,", &file.hash);
+    assert_eq!(read_copy(&mut socket, id).end, 100000);
+
+    // the rest of the transfer is refused, the new content has another hash
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 100000, \"end\": 200000,", &file.hash);
+    assert_eq!(read_err(&mut socket, id).code, "file_changed");
+    let changed = first_file(&mut socket);
+    assert_ne!(changed.hash, file.hash);
+    assert_eq!(changed.size, file.size + 1);
+
+    // a file written between the tree and the copy changed too
+    server.files.lock().unwrap().write_file("A.txt", "rewritten");
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 10,", &changed.hash);
+    assert_eq!(read_err(&mut socket, id).code, "file_changed");
+}
+
+#[test]
+fn ws_slow_copy_test() {
+    let server = start_server(test_files().with_fault("A.txt", Fault::Slow(Duration::from_millis(300))));
+    let key = server.add_client("client_slow");
+    let mut socket = start_socket_with_auth(&server, "client_slow".to_string(), key, true).unwrap();
+    let file = first_file(&mut socket);
+
+    let started = Instant::now();
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 10,", &file.hash);
+    assert_eq!(read_copy(&mut socket, id).end, 10);
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn memory_slow_read_test() {
+    let files = Arc::new(test_files().with_fault("A.txt", Fault::Slow(Duration::from_millis(300))));
+    let scope = ClientScope::default();
+    let hash = files.get_tree(&scope).unwrap().files.unwrap()[0].hash.clone();
+
+    let reader = {
+        let files = Arc::clone(&files);
+        spawn(move || files.get_file_data(0, 10, hash, &ClientScope::default()))
+    };
+    sleep(Duration::from_millis(50));
+    // the files can be changed while the slow read waits
+    let started = Instant::now();
+    files.write_file("dir1/A/B/C.txt", "changed");
+    assert!(started.elapsed() < Duration::from_millis(200));
+    assert_eq!(reader.join().unwrap().unwrap().end, 10);
+}
+
+#[test]
+fn ws_client_ignore_test() {
+    let server = start_server(test_files());
+    let key = server.add_client("client_ignore");
+    let client = server.data.lock().unwrap().get_client_by_name("client_ignore".to_string()).unwrap();
+    server.data.lock().unwrap().set_client_ignore(client.id.unwrap(), Some(&["dir1/".to_string()])).unwrap();
+
+    // the ignored directory is left out of the tree, its files can't be copied
+    let other_key = server.add_client("client_all_files");
+    let mut allowed = start_socket_with_auth(&server, "client_all_files".to_string(), other_key, true).unwrap();
+    let nested = get_tree(&mut allowed, gen_msg_id()).root.dirs.unwrap()[0].clone();
+    let nested_hash = nested.dirs.unwrap()[0].dirs.as_ref().unwrap()[0].files.as_ref().unwrap()[0].hash.clone();
+
+    let mut socket = start_socket_with_auth(&server, "client_ignore".to_string(), key, true).unwrap();
+    let tree = get_tree(&mut socket, gen_msg_id());
+    assert!(tree.root.dirs.unwrap().is_empty());
+    assert_eq!(tree.root.files.unwrap().len(), 1);
+
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 10,", &nested_hash);
+    assert_eq!(read_err(&mut socket, id).code, "file_not_found");
+
+    // patterns that can't be read hide everything rather than nothing
+    server.data.lock().unwrap().db_connection.lock().unwrap().execute(
+        "UPDATE client SET ignore = '[\"dir1/' WHERE id = ?1;",
+        [client.id.unwrap()],
+    ).unwrap();
+    let id = gen_msg_id();
+    socket.send(Message::Text(format!("{{\"id\": {}, \"type\": \"TreeMsg\"}}", id))).unwrap();
+    assert!(read_err(&mut socket, id).err.contains("Invalid ignore patterns"));
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0, \"end\": 10,", &nested_hash);
+    read_err(&mut socket, id);
+}
+
+#[test]
+fn ws_cancel_open_ended_copy_test() {
+    let server = start_server(test_files().with_fault("A.txt", Fault::Slow(Duration::from_millis(200))));
+    let key = server.add_client("client_open_copy");
+    let mut socket = start_socket_with_auth(&server, "client_open_copy".to_string(), key, true).unwrap();
+    let file = first_file(&mut socket);
+
+    // a copy without an end is a stream, the connection still reads the other messages
+    let id = gen_msg_id();
+    send_copy(&mut socket, id, "\"start\": 0,", &file.hash);
+    socket.send(Message::Text(format!("{{\"type\":\"CancelMsg\", \"id\": {id}}}"))).unwrap();
+
+    let copy_res = read_copy(&mut socket, id);
+    assert_eq!(copy_res.end, MAX_CHUNK_SIZE);
+    assert!(!copy_res.last_data);
+    let cancel_res: CancelRes = serde_json::from_str(&socket.read().unwrap().to_string()).unwrap();
+    assert_eq!((cancel_res.id, cancel_res.end), (id, MAX_CHUNK_SIZE));
+
+    // nothing else of the file comes
+    let id = gen_msg_id();
+    assert_eq!(get_tree(&mut socket, id).id, id);
 }